@@ -0,0 +1,474 @@
+//! `\EFI\rustos\boot.cfg`, the RustOS bootloader's configuration file.
+//!
+//! Parsing lives in its own crate so the host tests can feed it arbitrary
+//! text; the bootloader only reads the file and hands over the bytes.
+//!
+//! The file is UTF-8 text with one `key = value` pair per line. Blank lines
+//! and lines starting with `#` are ignored, and every key is optional.
+//! Global settings come first; each `[name]` header then starts a boot menu
+//! entry with its own kernel, command line and modules:
+//!
+//! ```text
+//! resolution = max 1920x1080
+//! log_level = debug
+//! stack_size = 128K
+//! timeout = 5
+//! default = Release kernel
+//!
+//! [Debug kernel]
+//! kernel = \EFI\rustos\kernel-debug.elf
+//! cmdline = console=ttyS0 init=/bin/sh
+//! module = \EFI\rustos\initrd.img
+//!
+//! [Release kernel]
+//! kernel = \EFI\rustos\kernel.elf
+//! module = \EFI\rustos\initrd.img
+//! ```
+//!
+//! `kernel` is either a RustOS ELF kernel, a Multiboot2 kernel or an EFI
+//! application such as the UEFI shell or a Linux kernel with its EFI stub.
+//! Multiboot2 kernels get `cmdline` and the modules through the Multiboot2
+//! boot information, each module with its path as its string. EFI
+//! applications are started with `cmdline` as their load options and don't
+//! get modules.
+//!
+//! `module` may be given any number of times; each file is handed to the
+//! kernel as a boot module named after its file name. `kernel`, `cmdline` and
+//! `module` may also appear before the first header, which describes a single
+//! unnamed entry. That entry is listed too if it sets any of them or if there
+//! are no `[name]` entries at all.
+//!
+//! `resolution` is one of `largest` (the default), `WxH` for an exact mode,
+//! `max WxH` for the largest mode that fits, `aspect W:H` for the mode
+//! closest to that aspect ratio, or `current` to keep the firmware's mode.
+//!
+//! `log_level` is a level (`off`, `error`, `warn`, `info`, `debug`, `trace`)
+//! optionally followed by comma-separated `module=level` overrides, e.g.
+//! `info, rustos_bootloader::paging=trace`. An override applies to the module
+//! and everything below it; the most specific one wins.
+//!
+//! `stack_size` is the kernel stack size in bytes, optionally with a `K` or
+//! `M` suffix or in hex with `0x`, rounded up to whole pages and at most
+//! 16M. It defaults to 64K.
+//!
+//! `diagnostics = on` draws a progress bar at the bottom of the screen while
+//! the bootloader hands over to the kernel and pauses briefly at each step,
+//! for debugging boots that hang without any output.
+//!
+//! `kaslr = off` loads a position independent kernel at its link-time
+//! address instead of a random one, so addresses are the same every boot;
+//! see `kaslr.rs` in the bootloader. It is on by default.
+//!
+//! `sha256 = <digest> <path>`, in `sha256sum` order, pins the contents of a
+//! kernel or module; it may be given any number of times, anywhere.
+//! `ed25519_key = <public key>` makes every kernel and module need a valid
+//! signature. Both are hex; see [`verify`] for the details.
+
+#![no_std]
+
+extern crate alloc;
+
+mod mode;
+pub mod verify;
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use log::LevelFilter;
+
+pub use mode::{Mode, ModeFormat};
+
+pub const CONFIG_PATH: &str = "\\EFI\\rustos\\boot.cfg";
+
+const DEFAULT_KERNEL_PATH: &str = "\\kernel.elf";
+const DEFAULT_ENTRY_NAME: &str = "RustOS";
+const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+/// Largest `stack_size`, far more than any kernel needs, so the stack's end
+/// address can't overflow.
+pub const MAX_STACK_SIZE: u64 = 16 * 1024 * 1024;
+/// Menu timeout when there is more than one entry and none was configured.
+const DEFAULT_MENU_TIMEOUT: u64 = 5;
+const PAGE_SIZE: u64 = 0x1000;
+
+/// One bootable kernel in the menu.
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    pub name: String,
+    /// Path of the kernel ELF, Multiboot2 kernel or EFI application on the
+    /// boot volume.
+    pub kernel_path: String,
+    /// Passed to the kernel verbatim through `BootInfo`.
+    pub cmdline: String,
+    /// Paths of boot modules, in the order the kernel sees them.
+    pub modules: Vec<String>,
+}
+
+impl BootEntry {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kernel_path: DEFAULT_KERNEL_PATH.to_string(),
+            cmdline: String::new(),
+            modules: Vec::new(),
+        }
+    }
+}
+
+/// Expected SHA-256 of the file at `path`, from a `sha256` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDigest {
+    pub path: String,
+    pub sha256: [u8; 32],
+}
+
+/// How the bootloader picks a GOP mode, see [`ResolutionPolicy::choose_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResolutionPolicy {
+    /// The mode with the most pixels.
+    #[default]
+    Largest,
+    /// Exactly this `(width, height)`, or `Largest` if the firmware lacks it.
+    Exact(usize, usize),
+    /// The largest mode no wider and no taller than this, or the smallest
+    /// mode if none fits.
+    Max(usize, usize),
+    /// The largest of the modes closest to this `width:height` ratio.
+    Aspect(usize, usize),
+    /// Leave the firmware's current mode alone.
+    Current,
+}
+
+/// Log levels per module, from the `log_level` key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    /// Level for modules without an override.
+    pub default: LevelFilter,
+    /// `(module path, level)` overrides.
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    fn parse(value: &str) -> Option<Self> {
+        let mut filter = LogFilter::default();
+        for (index, directive) in value.split(',').map(str::trim).enumerate() {
+            match directive.split_once('=') {
+                Some((module, level)) if !module.trim().is_empty() => {
+                    filter.modules.push((module.trim().to_string(), level.trim().parse().ok()?));
+                }
+                Some(_) => return None,
+                // The global level may only come first
+                None if index == 0 => filter.default = directive.parse().ok()?,
+                None => return None,
+            }
+        }
+        Some(filter)
+    }
+
+    /// Level that applies to log records from `target` (a module path).
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// The most verbose level any module is allowed.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.default, Ord::max)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    /// Always has at least one entry.
+    pub entries: Vec<BootEntry>,
+    /// Index into `entries` of the entry booted when the menu times out.
+    pub default_entry: usize,
+    /// Seconds the menu waits before booting the default entry; 0 boots it
+    /// right away without showing the menu.
+    pub timeout: u64,
+    pub resolution: ResolutionPolicy,
+    pub log_filter: LogFilter,
+    /// Kernel stack size in bytes, a multiple of the page size and at most
+    /// [`MAX_STACK_SIZE`].
+    pub stack_size: u64,
+    /// Show the boot progress bar, see `diagnostics.rs` in the bootloader.
+    pub diagnostics: bool,
+    /// Randomise where position independent kernels are loaded.
+    pub kaslr: bool,
+    pub digests: Vec<FileDigest>,
+    /// Ed25519 public key kernels and modules must be signed with.
+    pub ed25519_key: Option<[u8; 32]>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            entries: vec![BootEntry::new(DEFAULT_ENTRY_NAME)],
+            default_entry: 0,
+            timeout: 0,
+            resolution: ResolutionPolicy::default(),
+            log_filter: LogFilter::default(),
+            stack_size: DEFAULT_STACK_SIZE,
+            diagnostics: false,
+            kaslr: true,
+            digests: Vec::new(),
+            ed25519_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    NotUtf8,
+    MissingEquals { line: usize },
+    BadSectionHeader { line: usize },
+    UnknownKey { line: usize, key: String },
+    GlobalKeyInEntry { line: usize, key: String },
+    UnknownDefault(String),
+    InvalidValue { line: usize, key: &'static str, value: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotUtf8 => write!(f, "{} is not valid UTF-8", CONFIG_PATH),
+            ConfigError::MissingEquals { line } => write!(f, "line {}: expected `key = value`", line),
+            ConfigError::BadSectionHeader { line } => write!(f, "line {}: expected `[entry name]`", line),
+            ConfigError::UnknownKey { line, key } => write!(f, "line {}: unknown key `{}`", line, key),
+            ConfigError::GlobalKeyInEntry { line, key } => {
+                write!(f, "line {}: `{}` must come before the first [entry]", line, key)
+            }
+            ConfigError::UnknownDefault(name) => write!(f, "default entry `{}` does not exist", name),
+            ConfigError::InvalidValue { line, key, value } => {
+                write!(f, "line {}: invalid {} `{}`", line, key, value)
+            }
+        }
+    }
+}
+
+impl BootConfig {
+    pub fn parse(data: &[u8]) -> Result<Self, ConfigError> {
+        let text = core::str::from_utf8(data).map_err(|_| ConfigError::NotUtf8)?;
+        let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+
+        let mut config = BootConfig::default();
+        let mut top_level = BootEntry::new(DEFAULT_ENTRY_NAME);
+        let mut top_level_used = false;
+        let mut entries: Vec<BootEntry> = Vec::new();
+        let mut timeout = None;
+        let mut default = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let name = header
+                    .strip_suffix(']')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .ok_or(ConfigError::BadSectionHeader { line: line_number })?;
+                entries.push(BootEntry::new(name));
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(ConfigError::MissingEquals { line: line_number })?;
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = |key: &'static str| ConfigError::InvalidValue {
+                line: line_number,
+                key,
+                value: value.to_string(),
+            };
+
+            let entry = match entries.last_mut() {
+                Some(entry) => entry,
+                None => &mut top_level,
+            };
+            match key {
+                "kernel" => {
+                    if value.is_empty() {
+                        return Err(invalid("kernel"));
+                    }
+                    entry.kernel_path = value.to_string();
+                }
+                "cmdline" => entry.cmdline = value.to_string(),
+                "module" => {
+                    if value.is_empty() {
+                        return Err(invalid("module"));
+                    }
+                    entry.modules.push(value.to_string());
+                }
+                // Names its file, so it doesn't matter where it appears
+                "sha256" => {
+                    let (digest, path) = value.split_once(char::is_whitespace).ok_or_else(|| invalid("sha256"))?;
+                    let path = path.trim();
+                    config.digests.push(FileDigest {
+                        // sha256sum marks binary mode with a `*` before the name
+                        path: path.strip_prefix('*').unwrap_or(path).to_string(),
+                        sha256: parse_hex(digest).ok_or_else(|| invalid("sha256"))?,
+                    });
+                }
+                "resolution" | "log_level" | "stack_size" | "diagnostics" | "kaslr" | "ed25519_key" | "timeout"
+                | "default"
+                    if !entries.is_empty() =>
+                {
+                    return Err(ConfigError::GlobalKeyInEntry {
+                        line: line_number,
+                        key: key.to_string(),
+                    })
+                }
+                "resolution" => config.resolution = parse_resolution_policy(value).ok_or_else(|| invalid("resolution"))?,
+                "log_level" => config.log_filter = LogFilter::parse(value).ok_or_else(|| invalid("log_level"))?,
+                "stack_size" => {
+                    config.stack_size = parse_size(value)
+                        .filter(|&size| size <= MAX_STACK_SIZE)
+                        .ok_or_else(|| invalid("stack_size"))?
+                }
+                "diagnostics" => config.diagnostics = parse_bool(value).ok_or_else(|| invalid("diagnostics"))?,
+                "kaslr" => config.kaslr = parse_bool(value).ok_or_else(|| invalid("kaslr"))?,
+                "ed25519_key" => config.ed25519_key = Some(parse_hex(value).ok_or_else(|| invalid("ed25519_key"))?),
+                "timeout" => timeout = Some(value.parse().map_err(|_| invalid("timeout"))?),
+                "default" => default = Some(value.to_string()),
+                _ => {
+                    return Err(ConfigError::UnknownKey {
+                        line: line_number,
+                        key: key.to_string(),
+                    })
+                }
+            }
+            if entries.is_empty() && matches!(key, "kernel" | "cmdline" | "module") {
+                top_level_used = true;
+            }
+        }
+
+        if top_level_used || entries.is_empty() {
+            entries.insert(0, top_level);
+        }
+        config.entries = entries;
+
+        // `default` is an entry name or an index
+        if let Some(default) = default {
+            config.default_entry = config
+                .entries
+                .iter()
+                .position(|entry| entry.name == default)
+                .or_else(|| default.parse().ok().filter(|&index| index < config.entries.len()))
+                .ok_or(ConfigError::UnknownDefault(default))?;
+        }
+        config.timeout = timeout.unwrap_or(if config.entries.len() > 1 { DEFAULT_MENU_TIMEOUT } else { 0 });
+        Ok(config)
+    }
+}
+
+/// Name a module is known by: the last component of its path.
+pub fn module_name(path: &str) -> &str {
+    path.rsplit(['\\', '/']).next().unwrap_or(path)
+}
+
+fn parse_resolution_policy(value: &str) -> Option<ResolutionPolicy> {
+    let policy = match value.split_once(char::is_whitespace) {
+        Some(("max", size)) => {
+            let (width, height) = parse_pair(size.trim(), &['x', 'X'])?;
+            ResolutionPolicy::Max(width, height)
+        }
+        Some(("aspect", ratio)) => {
+            let (width, height) = parse_pair(ratio.trim(), &[':'])?;
+            ResolutionPolicy::Aspect(width, height)
+        }
+        Some(_) => return None,
+        None => match value {
+            "largest" => ResolutionPolicy::Largest,
+            "current" => ResolutionPolicy::Current,
+            _ => {
+                let (width, height) = parse_pair(value, &['x', 'X'])?;
+                ResolutionPolicy::Exact(width, height)
+            }
+        },
+    };
+    Some(policy)
+}
+
+/// Two non-zero numbers split by one of `separators`, e.g. `1024x768` or `16:9`.
+fn parse_pair(value: &str, separators: &[char]) -> Option<(usize, usize)> {
+    let (width, height) = value.split_once(separators)?;
+    let width = width.trim().parse().ok()?;
+    let height = height.trim().parse().ok()?;
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "on" | "yes" | "true" | "1" => Some(true),
+        "off" | "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// A byte count with an optional `K`/`M` suffix or `0x` prefix, rounded up
+/// to whole pages.
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, multiplier) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 1024),
+        b'M' | b'm' => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    let digits = digits.trim();
+    let number = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    let size = number.checked_mul(multiplier)?;
+    if size == 0 {
+        return None;
+    }
+    size.checked_add(PAGE_SIZE - 1).map(|size| size & !(PAGE_SIZE - 1))
+}
+
+/// Exactly `2 * N` hex digits. Usable in constants, for keys built into the
+/// bootloader.
+pub const fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    const fn digit(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let hex = hex.as_bytes();
+    if hex.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0; N];
+    let mut i = 0;
+    while i < N {
+        match (digit(hex[2 * i]), digit(hex[2 * i + 1])) {
+            (Some(high), Some(low)) => bytes[i] = high << 4 | low,
+            _ => return None,
+        }
+        i += 1;
+    }
+    Some(bytes)
+}
@@ -0,0 +1,70 @@
+//! Picking a graphics mode for a [`ResolutionPolicy`].
+//!
+//! The bootloader lists the firmware's GOP modes as plain
+//! `(width, height, format)` tuples, so the choice can be tested on the host.
+
+use crate::ResolutionPolicy;
+
+/// Pixel format of a graphics mode, like UEFI's `EFI_GRAPHICS_PIXEL_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeFormat {
+    Rgb,
+    Bgr,
+    Bitmask,
+    /// No linear framebuffer, only the firmware's `Blt` can draw.
+    BltOnly,
+}
+
+/// `(width, height, format)` of one mode.
+pub type Mode = (usize, usize, ModeFormat);
+
+fn pixels(mode: &Mode) -> usize {
+    mode.0 * mode.1
+}
+
+impl ResolutionPolicy {
+    /// Index into `modes` of the mode to switch to, or `None` to keep the
+    /// current one. Only modes with a linear framebuffer the kernel can draw
+    /// to are considered.
+    pub fn choose_mode(self, modes: &[Mode]) -> Option<usize> {
+        let candidates = || modes.iter().enumerate().filter(|(_, mode)| mode.2 != ModeFormat::BltOnly);
+        let largest = |candidates: &mut dyn Iterator<Item = (usize, &Mode)>| {
+            candidates.max_by_key(|(_, mode)| pixels(mode)).map(|(index, _)| index)
+        };
+
+        match self {
+            ResolutionPolicy::Current => None,
+            ResolutionPolicy::Largest => largest(&mut candidates()),
+            ResolutionPolicy::Exact(width, height) => {
+                let exact = candidates().find(|(_, mode)| (mode.0, mode.1) == (width, height));
+                match exact {
+                    Some((index, _)) => Some(index),
+                    None => {
+                        log::warn!("No {}x{} mode, using the largest one", width, height);
+                        largest(&mut candidates())
+                    }
+                }
+            }
+            ResolutionPolicy::Max(width, height) => {
+                largest(&mut candidates().filter(|(_, mode)| mode.0 <= width && mode.1 <= height))
+                    // Nothing fits; the smallest mode is the closest we can get
+                    .or_else(|| candidates().min_by_key(|(_, mode)| pixels(mode)).map(|(index, _)| index))
+            }
+            ResolutionPolicy::Aspect(aspect_width, aspect_height) => {
+                // |w/h - aw/ah| = |w*ah - h*aw| / (h*ah); the common ah factor
+                // doesn't change the order, so compare |w*ah - h*aw| / h
+                let error = |mode: &Mode| {
+                    let difference = (mode.0 as u128 * aspect_height as u128).abs_diff(mode.1 as u128 * aspect_width as u128);
+                    (difference, mode.1 as u128)
+                };
+                candidates()
+                    .max_by(|(_, a), (_, b)| {
+                        let ((error_a, height_a), (error_b, height_b)) = (error(a), error(b));
+                        // Smaller error first, then more pixels
+                        (error_b * height_a).cmp(&(error_a * height_b)).then(pixels(a).cmp(&pixels(b)))
+                    })
+                    .map(|(index, _)| index)
+            }
+        }
+    }
+}
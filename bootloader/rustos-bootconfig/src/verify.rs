@@ -0,0 +1,137 @@
+//! Integrity checks for kernels and modules before anything is booted.
+//!
+//! A file with a `sha256` line in the config must match that digest. Once
+//! there is an Ed25519 public key, every kernel and module must also come
+//! with a detached signature over its contents: 64 raw bytes in a file next
+//! to it named `<path>.sig`.
+//!
+//! The key can be built into the bootloader by setting `RUSTOS_ED25519_KEY`
+//! to its 64 hex digits at build time; the bootloader passes it to
+//! [`Verifier::new`]. A built-in key takes precedence over `ed25519_key`, so
+//! editing the config can't swap it out.
+//!
+//! Paths are compared ignoring ASCII case, like the FAT boot volume does.
+
+use alloc::string::{String, ToString};
+use core::fmt;
+use ed25519_compact::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+use crate::{BootConfig, FileDigest};
+
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// Formats bytes as lowercase hex.
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    DigestMismatch { path: String, expected: [u8; 32], actual: [u8; 32] },
+    MissingSignature { path: String },
+    BadSignature { path: String },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::DigestMismatch { path, expected, actual } => write!(
+                f,
+                "{} is corrupt or stale: SHA-256 is {}, expected {}",
+                path,
+                Hex(actual),
+                Hex(expected)
+            ),
+            VerifyError::MissingSignature { path } => {
+                write!(f, "{} is not signed, {}{} is missing", path, path, SIGNATURE_SUFFIX)
+            }
+            VerifyError::BadSignature { path } => {
+                write!(f, "{} does not match its signature {}{}", path, path, SIGNATURE_SUFFIX)
+            }
+        }
+    }
+}
+
+/// What [`Verifier::verify`] checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verified {
+    pub digest: bool,
+    pub signature: bool,
+}
+
+impl fmt::Display for Verified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.digest, self.signature) {
+            (true, true) => write!(f, "SHA-256 and Ed25519 signature match"),
+            (true, false) => write!(f, "SHA-256 matches"),
+            (false, true) => write!(f, "Ed25519 signature matches"),
+            (false, false) => write!(f, "nothing to check"),
+        }
+    }
+}
+
+pub struct Verifier<'a> {
+    digests: &'a [FileDigest],
+    key: Option<PublicKey>,
+}
+
+impl<'a> Verifier<'a> {
+    /// Checks files against `config`, with `builtin_key` in place of its
+    /// `ed25519_key` if there is one.
+    pub fn new(config: &'a BootConfig, builtin_key: Option<[u8; 32]>) -> Self {
+        let key = match (builtin_key, config.ed25519_key) {
+            (Some(builtin), Some(configured)) if builtin != configured => {
+                log::warn!("Ignoring ed25519_key, the bootloader has a different one built in");
+                Some(builtin)
+            }
+            (builtin, configured) => builtin.or(configured),
+        };
+        Self {
+            digests: &config.digests,
+            key: key.map(PublicKey::new),
+        }
+    }
+
+    /// Whether files need a signature, so the caller should look for one.
+    pub fn wants_signature(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Checks `data`, read from `path`, against its digest if it has one,
+    /// and against `signature` if there is a key.
+    pub fn verify(&self, path: &str, data: &[u8], signature: Option<&[u8]>) -> Result<Verified, VerifyError> {
+        let expected = self
+            .digests
+            .iter()
+            .find(|digest| digest.path.eq_ignore_ascii_case(path))
+            .map(|digest| digest.sha256);
+        if let Some(expected) = expected {
+            let actual: [u8; 32] = Sha256::digest(data).into();
+            if actual != expected {
+                return Err(VerifyError::DigestMismatch {
+                    path: path.to_string(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        if let Some(key) = &self.key {
+            let signature = signature.ok_or_else(|| VerifyError::MissingSignature { path: path.to_string() })?;
+            let valid = Signature::from_slice(signature).is_ok_and(|signature| key.verify(data, &signature).is_ok());
+            if !valid {
+                return Err(VerifyError::BadSignature { path: path.to_string() });
+            }
+        }
+
+        Ok(Verified {
+            digest: expected.is_some(),
+            signature: self.key.is_some(),
+        })
+    }
+}
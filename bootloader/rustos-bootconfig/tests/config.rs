@@ -0,0 +1,280 @@
+use log::LevelFilter;
+use rustos_bootconfig::{module_name, parse_hex, BootConfig, ConfigError, ResolutionPolicy, MAX_STACK_SIZE};
+
+fn parse(text: &str) -> Result<BootConfig, ConfigError> {
+    BootConfig::parse(text.as_bytes())
+}
+
+#[test]
+fn empty_config_has_one_default_entry() {
+    let config = parse("").unwrap();
+    assert_eq!(config.entries.len(), 1);
+    assert_eq!(config.entries[0].name, "RustOS");
+    assert_eq!(config.entries[0].kernel_path, "\\kernel.elf");
+    assert_eq!(config.default_entry, 0);
+    assert_eq!(config.timeout, 0);
+    assert_eq!(config.stack_size, 64 * 1024);
+    assert_eq!(config.resolution, ResolutionPolicy::Largest);
+    assert!(!config.diagnostics);
+    assert!(config.kaslr);
+    assert!(config.digests.is_empty());
+    assert_eq!(config.ed25519_key, None);
+}
+
+#[test]
+fn parses_stack_sizes() {
+    let cases: &[(&str, Option<u64>)] = &[
+        ("4096", Some(0x1000)),
+        ("1", Some(0x1000)),
+        ("4097", Some(0x2000)),
+        ("128K", Some(128 * 1024)),
+        ("128k", Some(128 * 1024)),
+        ("2M", Some(2 * 1024 * 1024)),
+        ("0x10000", Some(0x10000)),
+        ("16M", Some(MAX_STACK_SIZE)),
+        ("16385K", None),
+        ("0xffffffffffffffff", None),
+        ("18446744073709551615M", None),
+        ("0", None),
+        ("", None),
+        ("K", None),
+        ("12G", None),
+        ("-4K", None),
+        ("0x", None),
+    ];
+    for &(value, expected) in cases {
+        let result = parse(&format!("stack_size = {}\n", value));
+        match expected {
+            Some(size) => assert_eq!(result.unwrap().stack_size, size, "stack_size = {}", value),
+            None => assert!(
+                matches!(result, Err(ConfigError::InvalidValue { key: "stack_size", .. })),
+                "stack_size = {} was accepted",
+                value
+            ),
+        }
+    }
+}
+
+#[test]
+fn parses_resolution_policies() {
+    let cases: &[(&str, Option<ResolutionPolicy>)] = &[
+        ("largest", Some(ResolutionPolicy::Largest)),
+        ("current", Some(ResolutionPolicy::Current)),
+        ("1024x768", Some(ResolutionPolicy::Exact(1024, 768))),
+        ("800X600", Some(ResolutionPolicy::Exact(800, 600))),
+        ("max 1920x1080", Some(ResolutionPolicy::Max(1920, 1080))),
+        ("aspect 16:9", Some(ResolutionPolicy::Aspect(16, 9))),
+        ("0x768", None),
+        ("1024", None),
+        ("min 800x600", None),
+        ("aspect 16x9", None),
+    ];
+    for &(value, expected) in cases {
+        let result = parse(&format!("resolution = {}\n", value)).ok().map(|config| config.resolution);
+        assert_eq!(result, expected, "resolution = {}", value);
+    }
+}
+
+#[test]
+fn parses_booleans() {
+    for (value, expected) in [("on", true), ("yes", true), ("true", true), ("1", true), ("off", false), ("0", false)] {
+        assert_eq!(parse(&format!("diagnostics = {}\n", value)).unwrap().diagnostics, expected);
+        assert_eq!(parse(&format!("kaslr = {}\n", value)).unwrap().kaslr, expected);
+    }
+    assert!(parse("diagnostics = maybe\n").is_err());
+    assert!(parse("kaslr = sometimes\n").is_err());
+}
+
+#[test]
+fn parses_entries_and_modules() {
+    let config = parse(
+        "timeout = 3\n\
+         default = Release\n\
+         \n\
+         # A comment\n\
+         [Debug]\n\
+         kernel = \\EFI\\rustos\\debug.elf\n\
+         cmdline = console=ttyS0 init=/bin/sh\n\
+         module = \\EFI\\rustos\\initrd.img\n\
+         module = \\EFI\\rustos\\fonts.bin\n\
+         \n\
+         [ Release ]\n\
+         kernel = \\EFI\\rustos\\kernel.elf\n",
+    )
+    .unwrap();
+    assert_eq!(config.timeout, 3);
+    assert_eq!(config.entries.len(), 2);
+    assert_eq!(config.default_entry, 1);
+
+    let debug = &config.entries[0];
+    assert_eq!(debug.name, "Debug");
+    assert_eq!(debug.kernel_path, "\\EFI\\rustos\\debug.elf");
+    assert_eq!(debug.cmdline, "console=ttyS0 init=/bin/sh");
+    assert_eq!(debug.modules, ["\\EFI\\rustos\\initrd.img", "\\EFI\\rustos\\fonts.bin"]);
+    assert_eq!(module_name(&debug.modules[0]), "initrd.img");
+
+    let release = &config.entries[1];
+    assert_eq!(release.name, "Release");
+    assert!(release.cmdline.is_empty());
+    assert!(release.modules.is_empty());
+}
+
+#[test]
+fn top_level_entry_is_listed_when_used() {
+    let config = parse("kernel = \\a.elf\n[B]\nkernel = \\b.elf\n").unwrap();
+    let names: Vec<_> = config.entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["RustOS", "B"]);
+    assert_eq!(config.timeout, 5);
+
+    let config = parse("timeout = 2\n[B]\n").unwrap();
+    assert_eq!(config.entries.len(), 1);
+    assert_eq!(config.entries[0].name, "B");
+}
+
+#[test]
+fn default_entry_by_name_or_index() {
+    let text = "[A]\n[B]\n[C]\n";
+    assert_eq!(parse(&format!("default = B\n{}", text)).unwrap().default_entry, 1);
+    assert_eq!(parse(&format!("default = 2\n{}", text)).unwrap().default_entry, 2);
+    assert_eq!(
+        parse(&format!("default = 3\n{}", text)).unwrap_err(),
+        ConfigError::UnknownDefault("3".into())
+    );
+}
+
+#[test]
+fn parses_digests_and_keys() {
+    let digest = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    let key = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    let config = parse(&format!(
+        "ed25519_key = {key}\n\
+         sha256 = {digest}  \\kernel.elf\n\
+         [A]\n\
+         sha256 = {digest} *\\initrd.img\n"
+    ))
+    .unwrap();
+    assert_eq!(config.ed25519_key, parse_hex(key));
+    assert_eq!(config.digests.len(), 2);
+    assert_eq!(config.digests[0].path, "\\kernel.elf");
+    assert_eq!(config.digests[1].path, "\\initrd.img");
+    assert_eq!(Some(config.digests[0].sha256), parse_hex(digest));
+    assert_eq!(config.digests[0].sha256[..4], [0xe3, 0xb0, 0xc4, 0x42]);
+
+    for bad in [
+        "sha256 = e3b0 \\kernel.elf",
+        &format!("sha256 = {}", digest),
+        &format!("sha256 = {}zz \\kernel.elf", &digest[..62]),
+        "ed25519_key = 00",
+    ] {
+        assert!(matches!(parse(bad), Err(ConfigError::InvalidValue { .. })), "{} was accepted", bad);
+    }
+}
+
+#[test]
+fn parses_hex() {
+    assert_eq!(parse_hex::<2>("00fF"), Some([0x00, 0xff]));
+    assert_eq!(parse_hex::<2>("00f"), None);
+    assert_eq!(parse_hex::<2>("00fff"), None);
+    assert_eq!(parse_hex::<1>("g0"), None);
+    assert_eq!(parse_hex::<0>(""), Some([]));
+}
+
+#[test]
+fn parses_log_filters() {
+    let filter = parse("log_level = warn, a::b=trace, a=debug\n").unwrap().log_filter;
+    assert_eq!(filter.default, LevelFilter::Warn);
+    assert_eq!(filter.level_for("a::b::c"), LevelFilter::Trace);
+    assert_eq!(filter.level_for("a::bc"), LevelFilter::Debug);
+    assert_eq!(filter.level_for("a"), LevelFilter::Debug);
+    assert_eq!(filter.level_for("x"), LevelFilter::Warn);
+    assert_eq!(filter.max_level(), LevelFilter::Trace);
+    assert_eq!(parse("log_level = a=error\n").unwrap().log_filter.default, LevelFilter::Info);
+}
+
+#[test]
+fn rejects_bad_input() {
+    let cases: &[(&[u8], ConfigError)] = &[
+        (b"\xff\xfe", ConfigError::NotUtf8),
+        (b"resolution\n", ConfigError::MissingEquals { line: 1 }),
+        (b"# ok\n[\n", ConfigError::BadSectionHeader { line: 2 }),
+        (b"[]\n", ConfigError::BadSectionHeader { line: 1 }),
+        (b"[A\n", ConfigError::BadSectionHeader { line: 1 }),
+        (
+            b"colour = blue\n",
+            ConfigError::UnknownKey {
+                line: 1,
+                key: "colour".into(),
+            },
+        ),
+        (
+            b"[A]\nstack_size = 8K\n",
+            ConfigError::GlobalKeyInEntry {
+                line: 2,
+                key: "stack_size".into(),
+            },
+        ),
+        (
+            b"[A]\nkaslr = off\n",
+            ConfigError::GlobalKeyInEntry {
+                line: 2,
+                key: "kaslr".into(),
+            },
+        ),
+        (b"default = nope\n", ConfigError::UnknownDefault("nope".into())),
+        (
+            b"kernel =\n",
+            ConfigError::InvalidValue {
+                line: 1,
+                key: "kernel",
+                value: "".into(),
+            },
+        ),
+        (
+            b"[A]\nmodule = \n",
+            ConfigError::InvalidValue {
+                line: 2,
+                key: "module",
+                value: "".into(),
+            },
+        ),
+        (
+            b"timeout = soon\n",
+            ConfigError::InvalidValue {
+                line: 1,
+                key: "timeout",
+                value: "soon".into(),
+            },
+        ),
+        (
+            b"log_level = a=loud\n",
+            ConfigError::InvalidValue {
+                line: 1,
+                key: "log_level",
+                value: "a=loud".into(),
+            },
+        ),
+        (
+            b"log_level = a=info, warn\n",
+            ConfigError::InvalidValue {
+                line: 1,
+                key: "log_level",
+                value: "a=info, warn".into(),
+            },
+        ),
+    ];
+    for (text, expected) in cases {
+        assert_eq!(
+            BootConfig::parse(text).unwrap_err(),
+            *expected,
+            "{:?}",
+            String::from_utf8_lossy(text)
+        );
+    }
+}
+
+#[test]
+fn accepts_byte_order_mark_and_crlf() {
+    let config = BootConfig::parse(b"\xef\xbb\xbfstack_size = 8K\r\n[A]\r\ncmdline = quiet\r\n").unwrap();
+    assert_eq!(config.stack_size, 8 * 1024);
+    assert_eq!(config.entries[0].cmdline, "quiet");
+}
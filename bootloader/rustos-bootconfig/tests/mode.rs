@@ -0,0 +1,59 @@
+use rustos_bootconfig::{Mode, ModeFormat, ResolutionPolicy};
+
+use ModeFormat::{Bgr, BltOnly, Rgb};
+
+/// A typical firmware list: not sorted, with a Blt-only mode that would
+/// otherwise win on size.
+const MODES: &[Mode] = &[
+    (800, 600, Bgr),
+    (1024, 768, Bgr),
+    (640, 480, Rgb),
+    (1280, 720, Bgr),
+    (1920, 1080, Bgr),
+    (2560, 1600, BltOnly),
+    (1280, 1024, Bgr),
+];
+
+#[test]
+fn chooses_modes_by_policy() {
+    let cases = [
+        (ResolutionPolicy::Current, None),
+        (ResolutionPolicy::Largest, Some(4)),
+        // Exact match
+        (ResolutionPolicy::Exact(1024, 768), Some(1)),
+        (ResolutionPolicy::Exact(640, 480), Some(2)),
+        // No such mode: the largest one
+        (ResolutionPolicy::Exact(1366, 768), Some(4)),
+        // A Blt-only mode never matches
+        (ResolutionPolicy::Exact(2560, 1600), Some(4)),
+        // The largest that fits, not the first smaller one
+        (ResolutionPolicy::Max(1300, 1050), Some(6)),
+        (ResolutionPolicy::Max(1280, 720), Some(3)),
+        (ResolutionPolicy::Max(1000, 1000), Some(0)),
+        (ResolutionPolicy::Max(4096, 4096), Some(4)),
+        // Nothing fits: the smallest mode
+        (ResolutionPolicy::Max(320, 200), Some(2)),
+        (ResolutionPolicy::Aspect(16, 9), Some(4)),
+        (ResolutionPolicy::Aspect(4, 3), Some(1)),
+        (ResolutionPolicy::Aspect(5, 4), Some(6)),
+        // 16:10 only exists Blt-only; 16:9 is the closest
+        (ResolutionPolicy::Aspect(16, 10), Some(4)),
+    ];
+    for (policy, expected) in cases {
+        assert_eq!(policy.choose_mode(MODES), expected, "{:?}", policy);
+    }
+}
+
+#[test]
+fn nothing_to_choose_from() {
+    let blt_only: &[Mode] = &[(1024, 768, BltOnly)];
+    for policy in [
+        ResolutionPolicy::Largest,
+        ResolutionPolicy::Exact(1024, 768),
+        ResolutionPolicy::Max(1024, 768),
+        ResolutionPolicy::Aspect(4, 3),
+    ] {
+        assert_eq!(policy.choose_mode(&[]), None, "{:?}", policy);
+        assert_eq!(policy.choose_mode(blt_only), None, "{:?}", policy);
+    }
+}
@@ -0,0 +1,157 @@
+use rustos_bootconfig::verify::{Verified, Verifier, VerifyError};
+use rustos_bootconfig::{parse_hex, BootConfig};
+
+// RFC 8032 section 7.1, tests 1 and 2
+const KEY_1: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+const MESSAGE_1: &[u8] = b"";
+const SIGNATURE_1: &str = "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065\
+                           224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b";
+const KEY_2: &str = "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c";
+const MESSAGE_2: &[u8] = b"\x72";
+const SIGNATURE_2: &str = "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223\
+                           ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00";
+
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn config(text: &str) -> BootConfig {
+    BootConfig::parse(text.as_bytes()).unwrap()
+}
+
+fn key(hex: &str) -> [u8; 32] {
+    parse_hex(hex).unwrap()
+}
+
+fn signature(hex: &str) -> [u8; 64] {
+    parse_hex(hex).unwrap()
+}
+
+#[test]
+fn checks_nothing_without_digests_or_key() {
+    let config = config("");
+    let verifier = Verifier::new(&config, None);
+    assert!(!verifier.wants_signature());
+    assert_eq!(
+        verifier.verify("\\kernel.elf", b"anything", None),
+        Ok(Verified {
+            digest: false,
+            signature: false,
+        })
+    );
+}
+
+#[test]
+fn checks_digests() {
+    let config = config(&format!("sha256 = {} \\EFI\\rustos\\kernel.elf\n", EMPTY_SHA256));
+    let verifier = Verifier::new(&config, None);
+    assert!(!verifier.wants_signature());
+
+    // Paths match like on FAT, ignoring ASCII case
+    for path in ["\\EFI\\rustos\\kernel.elf", "\\efi\\RUSTOS\\Kernel.ELF"] {
+        assert_eq!(
+            verifier.verify(path, b"", None),
+            Ok(Verified {
+                digest: true,
+                signature: false,
+            }),
+            "{}",
+            path
+        );
+    }
+
+    let error = verifier.verify("\\efi\\rustos\\kernel.elf", b"abc", None).unwrap_err();
+    assert_eq!(
+        error,
+        VerifyError::DigestMismatch {
+            path: "\\efi\\rustos\\kernel.elf".into(),
+            expected: parse_hex(EMPTY_SHA256).unwrap(),
+            actual: parse_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad").unwrap(),
+        }
+    );
+    assert!(error.to_string().contains("SHA-256 is ba7816bf"));
+
+    // Files without a digest aren't checked
+    assert!(verifier.verify("\\EFI\\rustos\\initrd.img", b"abc", None).is_ok());
+}
+
+#[test]
+fn checks_signatures_once_there_is_a_key() {
+    let config = config(&format!("ed25519_key = {}\n", KEY_1));
+    let verifier = Verifier::new(&config, None);
+    assert!(verifier.wants_signature());
+
+    assert_eq!(
+        verifier.verify("\\kernel.elf", MESSAGE_1, Some(&signature(SIGNATURE_1))),
+        Ok(Verified {
+            digest: false,
+            signature: true,
+        })
+    );
+
+    assert_eq!(
+        verifier.verify("\\kernel.elf", MESSAGE_1, None),
+        Err(VerifyError::MissingSignature {
+            path: "\\kernel.elf".into()
+        })
+    );
+
+    let mut flipped = signature(SIGNATURE_1);
+    flipped[0] ^= 1;
+    let bad_signatures: [&[u8]; 4] = [&flipped, &signature(SIGNATURE_2), &signature(SIGNATURE_1)[..63], &[]];
+    for bad in bad_signatures {
+        assert_eq!(
+            verifier.verify("\\kernel.elf", MESSAGE_1, Some(bad)),
+            Err(VerifyError::BadSignature {
+                path: "\\kernel.elf".into()
+            })
+        );
+    }
+
+    // A valid signature over something else
+    assert_eq!(
+        verifier.verify("\\kernel.elf", MESSAGE_2, Some(&signature(SIGNATURE_1))),
+        Err(VerifyError::BadSignature {
+            path: "\\kernel.elf".into()
+        })
+    );
+}
+
+#[test]
+fn digest_is_checked_before_signature() {
+    let config = config(&format!("ed25519_key = {}\nsha256 = {} \\kernel.elf\n", KEY_1, EMPTY_SHA256));
+    let verifier = Verifier::new(&config, None);
+    assert_eq!(
+        verifier.verify("\\KERNEL.ELF", MESSAGE_1, Some(&signature(SIGNATURE_1))),
+        Ok(Verified {
+            digest: true,
+            signature: true,
+        })
+    );
+    assert!(matches!(
+        verifier.verify("\\kernel.elf", b"abc", Some(&signature(SIGNATURE_1))),
+        Err(VerifyError::DigestMismatch { .. })
+    ));
+}
+
+#[test]
+fn builtin_key_takes_priority() {
+    // The config names key 1, the bootloader was built with key 2
+    let config = config(&format!("ed25519_key = {}\n", KEY_1));
+    let verifier = Verifier::new(&config, Some(key(KEY_2)));
+    assert!(verifier.verify("\\kernel.elf", MESSAGE_2, Some(&signature(SIGNATURE_2))).is_ok());
+    assert_eq!(
+        verifier.verify("\\kernel.elf", MESSAGE_1, Some(&signature(SIGNATURE_1))),
+        Err(VerifyError::BadSignature {
+            path: "\\kernel.elf".into()
+        })
+    );
+
+    // A built-in key applies even if the config has none
+    let config = self::config("");
+    let verifier = Verifier::new(&config, Some(key(KEY_1)));
+    assert!(verifier.wants_signature());
+    assert!(matches!(
+        verifier.verify("\\kernel.elf", MESSAGE_1, None),
+        Err(VerifyError::MissingSignature { .. })
+    ));
+    assert!(verifier.verify("\\kernel.elf", MESSAGE_1, Some(&signature(SIGNATURE_1))).is_ok());
+}
@@ -0,0 +1,132 @@
+//! Bounds-checked drawing on the framebuffer described by [`FramebufferInfo`].
+
+use core::ptr;
+
+use crate::FramebufferInfo;
+
+/// Linear framebuffer addressed by `pitch`, clipped to its real size.
+///
+/// Colours are given as `0xRRGGBB` and packed according to the channel masks
+/// with [`Framebuffer::color`].
+#[derive(Debug)]
+pub struct Framebuffer {
+    base: *mut u8,
+    width: usize,
+    height: usize,
+    pitch: usize,
+    bytes_per_pixel: usize,
+    red_mask: u32,
+    green_mask: u32,
+    blue_mask: u32,
+}
+
+// The framebuffer is plain memory that nothing else writes to
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Wraps the framebuffer at its physical address, for code that runs
+    /// identity mapped. Returns `None` if there is no usable framebuffer.
+    ///
+    /// # Safety
+    ///
+    /// `info` must describe memory that is mapped at `info.addr` and not
+    /// otherwise aliased while the returned value is in use.
+    pub unsafe fn new(info: &FramebufferInfo) -> Option<Self> {
+        Self::with_base(info, info.addr as *mut u8)
+    }
+
+    /// Like [`Framebuffer::new`], but with the framebuffer mapped at `base`.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to at least `info.pitch * info.height` writable bytes.
+    pub unsafe fn with_base(info: &FramebufferInfo, base: *mut u8) -> Option<Self> {
+        let bytes_per_pixel = info.bpp as usize / 8;
+        if !info.is_available()
+            || base.is_null()
+            || !info.bpp.is_multiple_of(8)
+            || !(1..=4).contains(&bytes_per_pixel)
+            || (info.pitch as usize) < info.width as usize * bytes_per_pixel
+        {
+            return None;
+        }
+        Some(Self {
+            base,
+            width: info.width as usize,
+            height: info.height as usize,
+            pitch: info.pitch as usize,
+            bytes_per_pixel,
+            red_mask: info.red_mask,
+            green_mask: info.green_mask,
+            blue_mask: info.blue_mask,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Packs `0xRRGGBB` into this framebuffer's pixel format.
+    pub fn color(&self, rgb: u32) -> u32 {
+        pack_channel((rgb >> 16) as u8, self.red_mask)
+            | pack_channel((rgb >> 8) as u8, self.green_mask)
+            | pack_channel(rgb as u8, self.blue_mask)
+    }
+
+    /// Writes a packed `color` at `(x, y)`; pixels outside the screen are ignored.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        unsafe { self.write_pixel(y * self.pitch + x * self.bytes_per_pixel, color) };
+    }
+
+    /// Fills a rectangle with a packed `color`, clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        for row in y.min(y_end)..y_end {
+            let line = row * self.pitch;
+            for column in x.min(x_end)..x_end {
+                unsafe { self.write_pixel(line + column * self.bytes_per_pixel, color) };
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: u32) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// # Safety
+    ///
+    /// `offset` must be the start of a pixel inside the framebuffer.
+    unsafe fn write_pixel(&mut self, offset: usize, color: u32) {
+        let pixel = self.base.add(offset);
+        if self.bytes_per_pixel == 4 && (pixel as usize).is_multiple_of(4) {
+            ptr::write_volatile(pixel as *mut u32, color);
+        } else {
+            for (i, byte) in color.to_le_bytes().iter().take(self.bytes_per_pixel).enumerate() {
+                ptr::write_volatile(pixel.add(i), *byte);
+            }
+        }
+    }
+}
+
+/// Scales an 8-bit channel value to the width of `mask` and shifts it into place.
+fn pack_channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let bits = (mask >> shift).count_ones();
+    let scaled = if bits >= 8 {
+        (value as u32) << (bits - 8)
+    } else {
+        (value as u32) >> (8 - bits)
+    };
+    (scaled << shift) & mask
+}
@@ -0,0 +1,507 @@
+//! Boot handoff ABI shared by the RustOS UEFI bootloader and the kernels it loads.
+//!
+//! The bootloader fills in a [`BootInfo`] in `LOADER_DATA` memory and passes its
+//! address to the kernel entry point. Every type in here is `#[repr(C)]` and only
+//! uses fixed-width fields so both sides agree on the layout. Any change to the
+//! layout must bump [`BOOT_INFO_VERSION`].
+//!
+//! # Kernel entry
+//!
+//! The entry point is called like an
+//! `extern "C" fn(boot_info: *const BootInfo, magic: u64) -> !`:
+//!
+//! - `rdi` holds the address of the [`BootInfo`] and `rsi`
+//!   [`KERNEL_ENTRY_MAGIC`], so a kernel can tell it was started by this
+//!   bootloader rather than something else.
+//! - `rsp` is [`KernelStackInfo::top`] minus 8 and points at a null return
+//!   address, the alignment a SysV callee expects right after a `call`.
+//!   Returning from the entry point faults instead of running off into
+//!   bootloader code.
+//! - `rbp` is 0, so frame pointer walks stop at the entry point.
+//! - Interrupts are disabled, long mode paging uses the bootloader's page
+//!   tables and boot services are gone.
+
+#![no_std]
+
+use core::fmt;
+use core::mem;
+use core::slice;
+
+mod framebuffer;
+mod regions;
+mod timing;
+
+pub use framebuffer::Framebuffer;
+pub use regions::{build_memory_regions, MemoryRegion, MemoryRegionKind};
+pub use timing::{BootPhase, BootTimings, PhaseTimestamps, BOOT_PHASE_COUNT};
+
+/// Magic value at the start of every [`BootInfo`] ("RUSTOSBI").
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"RUSTOSBI");
+
+/// Passed in `rsi` to the kernel entry point ("RUSTOSGO").
+pub const KERNEL_ENTRY_MAGIC: u64 = u64::from_le_bytes(*b"RUSTOSGO");
+
+/// Layout version of [`BootInfo`]. Bump on every ABI change.
+pub const BOOT_INFO_VERSION: u32 = 13;
+
+/// Fixed header at the start of [`BootInfo`].
+///
+/// The header never changes shape, so a kernel can always read it to find out
+/// whether the rest of the structure is something it understands.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfoHeader {
+    pub magic: u64,
+    pub version: u32,
+    /// `size_of::<BootInfo>()` as seen by the bootloader.
+    pub size: u32,
+}
+
+impl BootInfoHeader {
+    pub const fn current() -> Self {
+        Self {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            size: mem::size_of::<BootInfo>() as u32,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), BootInfoError> {
+        if self.magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic(self.magic));
+        }
+        if self.version != BOOT_INFO_VERSION {
+            return Err(BootInfoError::VersionMismatch {
+                expected: BOOT_INFO_VERSION,
+                found: self.version,
+            });
+        }
+        if self.size as usize != mem::size_of::<BootInfo>() {
+            return Err(BootInfoError::SizeMismatch {
+                expected: mem::size_of::<BootInfo>() as u32,
+                found: self.size,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BootInfo {
+    pub header: BootInfoHeader,
+    /// The raw UEFI memory map returned by `ExitBootServices`.
+    pub memory_map: MemoryMapInfo,
+    /// The same map as sorted, merged [`MemoryRegion`]s.
+    pub memory_regions: MemoryRegions,
+    pub framebuffer: FramebufferInfo,
+    /// Physical address of the ACPI RSDP, or 0 if the firmware didn't provide
+    /// a valid one. The ACPI 2.0+ XSDP is preferred over the 1.0 RSDP.
+    pub rsdp_addr: u64,
+    pub kernel: KernelImageInfo,
+    /// Virtual address at which physical address 0 is mapped.
+    pub physical_memory_offset: u64,
+    /// Number of bytes of physical memory mapped at `physical_memory_offset`.
+    pub physical_memory_size: u64,
+    /// Kernel command line from the boot configuration, empty if none was set.
+    pub cmdline: BootStr,
+    pub modules: BootModules,
+    pub stack: KernelStackInfo,
+    pub log: BootLog,
+    pub firmware: FirmwareTables,
+    /// TSC timestamps of the bootloader's phases.
+    pub timings: BootTimings,
+}
+
+impl BootInfo {
+    /// Checks the header of the handoff at `ptr` and returns it if it matches
+    /// the layout this crate was built with.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null or point to readable memory at least as large as a
+    /// [`BootInfoHeader`], and to a full [`BootInfo`] if the header matches.
+    pub unsafe fn from_ptr<'a>(ptr: *const BootInfo) -> Result<&'a BootInfo, BootInfoError> {
+        if ptr.is_null() {
+            return Err(BootInfoError::NullPointer);
+        }
+        let header = &*(ptr as *const BootInfoHeader);
+        header.validate()?;
+        Ok(&*ptr)
+    }
+
+    /// Virtual address of physical address `phys` in the direct map.
+    pub fn phys_to_virt(&self, phys: u64) -> u64 {
+        self.physical_memory_offset + phys
+    }
+
+    /// The framebuffer, or `None` if there is no linear one.
+    pub fn framebuffer(&self) -> Option<&FramebufferInfo> {
+        if self.framebuffer.is_available() {
+            Some(&self.framebuffer)
+        } else {
+            None
+        }
+    }
+
+    pub fn rsdp_addr(&self) -> Option<u64> {
+        non_zero(self.rsdp_addr)
+    }
+}
+
+/// A file the bootloader loaded for the kernel (initrd, symbols, ...).
+///
+/// Modules live in page-aligned memory that the region list marks as
+/// [`MemoryRegionKind::Module`], so the kernel can reclaim it once done.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootModule {
+    pub name: BootStr,
+    pub phys_addr: u64,
+    pub len: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BootModules {
+    pub modules: *const BootModule,
+    pub count: u64,
+}
+
+impl BootModules {
+    pub const fn empty() -> Self {
+        Self {
+            modules: core::ptr::null(),
+            count: 0,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `modules` must point to `count` initialised entries that stay valid
+    /// for the returned lifetime.
+    pub unsafe fn as_slice(&self) -> &[BootModule] {
+        if self.modules.is_null() {
+            return &[];
+        }
+        slice::from_raw_parts(self.modules, self.count as usize)
+    }
+
+    /// Finds a module by name.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`BootModules::as_slice`], and every name must be valid.
+    pub unsafe fn find(&self, name: &str) -> Option<&BootModule> {
+        self.as_slice().iter().find(|module| module.name.as_str() == Some(name))
+    }
+}
+
+/// UTF-8 string in bootloader memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootStr {
+    pub ptr: *const u8,
+    pub len: u64,
+}
+
+impl BootStr {
+    pub const fn empty() -> Self {
+        Self {
+            ptr: core::ptr::null(),
+            len: 0,
+        }
+    }
+
+    /// Returns the string, or `None` if it isn't valid UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` readable bytes that stay valid for `'a`.
+    pub unsafe fn as_str<'a>(&self) -> Option<&'a str> {
+        if self.ptr.is_null() {
+            return Some("");
+        }
+        core::str::from_utf8(slice::from_raw_parts(self.ptr, self.len as usize)).ok()
+    }
+}
+
+/// The bootloader's log messages, including those from after
+/// `ExitBootServices`, kept in a ring buffer in bootloader memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootLog {
+    pub buffer: *const u8,
+    pub capacity: u64,
+    /// Total bytes ever logged; only the last `capacity` of them are kept.
+    pub written: u64,
+}
+
+// The bootloader is done writing to the buffer once the kernel runs
+unsafe impl Send for BootLog {}
+
+impl BootLog {
+    pub const fn empty() -> Self {
+        Self {
+            buffer: core::ptr::null(),
+            capacity: 0,
+            written: 0,
+        }
+    }
+
+    /// Whether older messages were overwritten.
+    pub fn is_truncated(&self) -> bool {
+        self.written > self.capacity
+    }
+
+    /// The kept text, oldest first, as two slices to be read one after the
+    /// other. The first one is empty unless the buffer wrapped around.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must point to `capacity` readable bytes that stay valid for `'a`.
+    pub unsafe fn as_slices<'a>(&self) -> (&'a [u8], &'a [u8]) {
+        if self.buffer.is_null() || self.capacity == 0 {
+            return (&[], &[]);
+        }
+        let buffer = slice::from_raw_parts(self.buffer, self.capacity as usize);
+        if !self.is_truncated() {
+            return (&[], &buffer[..self.written as usize]);
+        }
+        let split = (self.written % self.capacity) as usize;
+        (&buffer[split..], &buffer[..split])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    NullPointer,
+    BadMagic(u64),
+    VersionMismatch { expected: u32, found: u32 },
+    SizeMismatch { expected: u32, found: u32 },
+}
+
+impl fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootInfoError::NullPointer => write!(f, "boot info pointer is null"),
+            BootInfoError::BadMagic(magic) => write!(f, "bad boot info magic 0x{:016x}", magic),
+            BootInfoError::VersionMismatch { expected, found } => {
+                write!(f, "boot info version {} (kernel expects {})", found, expected)
+            }
+            BootInfoError::SizeMismatch { expected, found } => {
+                write!(f, "boot info size {} bytes (kernel expects {})", found, expected)
+            }
+        }
+    }
+}
+
+/// Physical addresses of other firmware structures, 0 for the ones the
+/// firmware doesn't provide.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct FirmwareTables {
+    /// SMBIOS 2.x entry point (`_SM_`).
+    pub smbios_addr: u64,
+    /// SMBIOS 3.x 64-bit entry point (`_SM3_`).
+    pub smbios3_addr: u64,
+    /// The UEFI system table, for runtime services.
+    pub efi_system_table: u64,
+    /// `EFI_MEMORY_ATTRIBUTES_TABLE` with the permissions of runtime
+    /// services code and data.
+    pub memory_attributes_table: u64,
+    /// Every [`EFI_MEMORY_RUNTIME`] range is mapped at its physical address
+    /// plus this offset, ready to be passed to `SetVirtualAddressMap`. 0 if
+    /// the bootloader didn't map them.
+    pub runtime_services_offset: u64,
+}
+
+impl FirmwareTables {
+    pub const fn empty() -> Self {
+        Self {
+            smbios_addr: 0,
+            smbios3_addr: 0,
+            efi_system_table: 0,
+            memory_attributes_table: 0,
+            runtime_services_offset: 0,
+        }
+    }
+
+    pub fn smbios_addr(&self) -> Option<u64> {
+        non_zero(self.smbios_addr)
+    }
+
+    pub fn smbios3_addr(&self) -> Option<u64> {
+        non_zero(self.smbios3_addr)
+    }
+
+    pub fn efi_system_table(&self) -> Option<u64> {
+        non_zero(self.efi_system_table)
+    }
+
+    pub fn memory_attributes_table(&self) -> Option<u64> {
+        non_zero(self.memory_attributes_table)
+    }
+}
+
+fn non_zero(addr: u64) -> Option<u64> {
+    (addr != 0).then_some(addr)
+}
+
+/// Where the kernel image ended up.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct KernelImageInfo {
+    pub phys_base: u64,
+    /// Virtual address the kernel runs at, equal to `phys_base` for kernels
+    /// linked below the higher half.
+    pub virt_base: u64,
+    /// Size of the image in bytes, page aligned.
+    pub size: u64,
+    /// Run-time minus link-time virtual address, wrapping: the KASLR slide
+    /// of a position independent kernel, 0 for one that runs where it was
+    /// linked. Symbolisers subtract it, see [`KernelImageInfo::link_addr`].
+    pub slide: u64,
+}
+
+impl KernelImageInfo {
+    /// Link-time address of run-time virtual address `addr`, for looking up
+    /// symbols.
+    pub fn link_addr(&self, addr: u64) -> u64 {
+        addr.wrapping_sub(self.slide)
+    }
+}
+
+/// The stack the kernel entry point runs on.
+///
+/// `[guard_start, bottom)` is left unmapped, so running off the end of the
+/// stack page faults instead of corrupting whatever lies below it.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct KernelStackInfo {
+    /// Virtual address of the lowest usable byte.
+    pub bottom: u64,
+    /// Virtual address just past the highest usable byte; the initial `rsp`.
+    pub top: u64,
+    pub guard_start: u64,
+}
+
+impl KernelStackInfo {
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    /// Whether `addr`, typically from CR2, lies in the guard page(s).
+    pub fn is_guard_hit(&self, addr: u64) -> bool {
+        (self.guard_start..self.bottom).contains(&addr)
+    }
+}
+
+/// `EFI_MEMORY_RUNTIME` attribute: the range belongs to runtime services,
+/// which need it mapped after `ExitBootServices`.
+pub const EFI_MEMORY_RUNTIME: u64 = 1 << 63;
+
+/// Mirror of the UEFI `EFI_MEMORY_DESCRIPTOR`.
+///
+/// Firmware may use a larger stride than `size_of::<MemoryDescriptor>()`, so
+/// always walk the map with [`MemoryMapInfo::entry_size`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDescriptor {
+    pub ty: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub page_count: u64,
+    pub attribute: u64,
+}
+
+impl MemoryDescriptor {
+    pub fn is_runtime(&self) -> bool {
+        self.attribute & EFI_MEMORY_RUNTIME != 0
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct MemoryMapInfo {
+    pub entries: *const MemoryDescriptor,
+    pub entry_count: u64,
+    /// Distance in bytes between consecutive descriptors.
+    pub entry_size: u64,
+}
+
+impl MemoryMapInfo {
+    pub const fn empty() -> Self {
+        Self {
+            entries: core::ptr::null(),
+            entry_count: 0,
+            entry_size: mem::size_of::<MemoryDescriptor>() as u64,
+        }
+    }
+
+    /// Iterates over the descriptors, honouring `entry_size`.
+    ///
+    /// # Safety
+    ///
+    /// `entries` must point to `entry_count` descriptors spaced `entry_size`
+    /// bytes apart that stay valid for the lifetime of the iterator.
+    pub unsafe fn iter(&self) -> impl Iterator<Item = &MemoryDescriptor> + '_ {
+        let base = self.entries as *const u8;
+        let stride = self.entry_size as usize;
+        (0..self.entry_count as usize).map(move |i| &*(base.add(i * stride) as *const MemoryDescriptor))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct MemoryRegions {
+    pub regions: *const MemoryRegion,
+    pub count: u64,
+}
+
+impl MemoryRegions {
+    pub const fn empty() -> Self {
+        Self {
+            regions: core::ptr::null(),
+            count: 0,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `regions` must point to `count` initialised regions that stay valid
+    /// for the returned lifetime.
+    pub unsafe fn as_slice(&self) -> &[MemoryRegion] {
+        if self.regions.is_null() {
+            return &[];
+        }
+        slice::from_raw_parts(self.regions, self.count as usize)
+    }
+}
+
+/// Linear framebuffer set up by the bootloader.
+///
+/// Pixels are `bpp / 8` bytes wide and each colour channel sits where its
+/// mask says within the pixel's little-endian value.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct FramebufferInfo {
+    /// Physical address of the framebuffer, or 0 if the firmware only
+    /// offers a `BltOnly` mode and there is nothing the kernel can draw to.
+    pub addr: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per scanline.
+    pub pitch: u32,
+    pub bpp: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+}
+
+impl FramebufferInfo {
+    pub fn is_available(&self) -> bool {
+        self.addr != 0
+    }
+}
@@ -0,0 +1,147 @@
+//! Compact physical memory layout derived from the UEFI memory map.
+//!
+//! The raw map has dozens of firmware-specific types and lists neighbouring
+//! ranges separately. The kernel mostly wants to know which memory it may use
+//! and which it has to keep its hands off, so the bootloader hands over a
+//! sorted list of non-overlapping regions with adjacent ones of the same kind
+//! merged.
+
+use crate::MemoryDescriptor;
+
+const UEFI_PAGE_SIZE: u64 = 0x1000;
+
+// EFI_MEMORY_TYPE values the conversion cares about
+const EFI_LOADER_CODE: u32 = 1;
+const EFI_LOADER_DATA: u32 = 2;
+const EFI_BOOT_SERVICES_CODE: u32 = 3;
+const EFI_BOOT_SERVICES_DATA: u32 = 4;
+const EFI_CONVENTIONAL_MEMORY: u32 = 7;
+const EFI_ACPI_RECLAIM_MEMORY: u32 = 9;
+const EFI_ACPI_MEMORY_NVS: u32 = 10;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// Free for the kernel to use.
+    Usable = 1,
+    /// Firmware, MMIO, runtime services or anything else the kernel must not touch.
+    Reserved = 2,
+    /// ACPI tables; usable once the kernel has parsed them.
+    AcpiReclaimable = 3,
+    /// ACPI non-volatile storage, must be preserved across sleep states.
+    AcpiNvs = 4,
+    /// Bootloader code and data (page tables, boot info, stack); reclaimable
+    /// once the kernel no longer needs anything the bootloader handed over.
+    Bootloader = 5,
+    KernelImage = 6,
+    Framebuffer = 7,
+    /// A boot module, see [`BootModule`](crate::BootModule).
+    Module = 8,
+}
+
+impl MemoryRegionKind {
+    /// Classifies an `EFI_MEMORY_TYPE`.
+    pub fn from_uefi(ty: u32) -> Self {
+        match ty {
+            EFI_CONVENTIONAL_MEMORY | EFI_BOOT_SERVICES_CODE | EFI_BOOT_SERVICES_DATA => MemoryRegionKind::Usable,
+            EFI_LOADER_CODE | EFI_LOADER_DATA => MemoryRegionKind::Bootloader,
+            EFI_ACPI_RECLAIM_MEMORY => MemoryRegionKind::AcpiReclaimable,
+            EFI_ACPI_MEMORY_NVS => MemoryRegionKind::AcpiNvs,
+            _ => MemoryRegionKind::Reserved,
+        }
+    }
+}
+
+/// Physical range `[start, end)` of one kind.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Builds the region list for `descriptors` in `out` and returns how many
+/// entries were used, or `None` if `out` is too small.
+///
+/// `overrides` are ranges the bootloader knows more about than the firmware
+/// (the kernel image, the framebuffer, boot modules); they replace whatever
+/// the map says for their range.
+pub fn build_memory_regions<'a>(
+    descriptors: impl Iterator<Item = &'a MemoryDescriptor>,
+    overrides: impl IntoIterator<Item = MemoryRegion>,
+    out: &mut [MemoryRegion],
+) -> Option<usize> {
+    let mut len = 0;
+    for descriptor in descriptors {
+        let region = MemoryRegion {
+            start: descriptor.physical_start,
+            end: descriptor.physical_start.saturating_add(descriptor.page_count.saturating_mul(UEFI_PAGE_SIZE)),
+            kind: MemoryRegionKind::from_uefi(descriptor.ty),
+        };
+        if !region.is_empty() {
+            push(out, &mut len, region)?;
+        }
+    }
+
+    for region in overrides.into_iter().filter(|region| !region.is_empty()) {
+        carve(out, &mut len, region.start, region.end)?;
+        push(out, &mut len, region)?;
+    }
+
+    let regions = &mut out[..len];
+    regions.sort_unstable_by_key(|region| region.start);
+
+    // Merge neighbours of the same kind, dropping the holes carve() left behind
+    let mut merged = 0;
+    for i in 0..len {
+        let region = out[i];
+        if region.is_empty() {
+            continue;
+        }
+        if merged > 0 {
+            let last = &mut out[merged - 1];
+            if last.kind == region.kind && last.end == region.start {
+                last.end = region.end;
+                continue;
+            }
+        }
+        out[merged] = region;
+        merged += 1;
+    }
+    Some(merged)
+}
+
+fn push(out: &mut [MemoryRegion], len: &mut usize, region: MemoryRegion) -> Option<()> {
+    *out.get_mut(*len)? = region;
+    *len += 1;
+    Some(())
+}
+
+/// Removes `[start, end)` from every region, splitting the ones it falls inside.
+fn carve(out: &mut [MemoryRegion], len: &mut usize, start: u64, end: u64) -> Option<()> {
+    for i in 0..*len {
+        let region = out[i];
+        if region.end <= start || region.start >= end {
+            continue;
+        }
+        if region.end > end {
+            push(out, len, MemoryRegion { start: end, ..region })?;
+        }
+        out[i].end = out[i].end.min(start);
+        if out[i].end <= out[i].start {
+            out[i].end = out[i].start;
+        }
+    }
+    Some(())
+}
@@ -0,0 +1,105 @@
+//! How long the bootloader spent on each step of the boot.
+//!
+//! The bootloader reads the TSC at the start and end of every [`BootPhase`]
+//! and hands the readings over in [`BootTimings`], along with the TSC rate it
+//! measured against the firmware's `Stall`. That lets the kernel report a
+//! breakdown without calibrating the TSC itself.
+
+/// Timed steps of the bootloader, in the order they run.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootPhase {
+    /// Reading the kernel file from the boot volume.
+    KernelRead = 0,
+    /// Picking and setting a graphics mode.
+    Graphics = 1,
+    /// Parsing the kernel ELF, loading and relocating it.
+    ElfLoad = 2,
+    /// Building the kernel's page tables.
+    PageTables = 3,
+    /// Exiting boot services and converting the final memory map.
+    MemoryMap = 4,
+}
+
+pub const BOOT_PHASE_COUNT: usize = 5;
+
+impl BootPhase {
+    pub const ALL: [BootPhase; BOOT_PHASE_COUNT] = [
+        BootPhase::KernelRead,
+        BootPhase::Graphics,
+        BootPhase::ElfLoad,
+        BootPhase::PageTables,
+        BootPhase::MemoryMap,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BootPhase::KernelRead => "kernel read",
+            BootPhase::Graphics => "graphics",
+            BootPhase::ElfLoad => "ELF load",
+            BootPhase::PageTables => "page tables",
+            BootPhase::MemoryMap => "memory map",
+        }
+    }
+}
+
+/// TSC readings at the start and end of one phase, both 0 if it didn't run.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PhaseTimestamps {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootTimings {
+    /// TSC ticks per millisecond, 0 if the bootloader couldn't measure it.
+    pub tsc_per_ms: u64,
+    /// TSC when the bootloader started.
+    pub bootloader_start: u64,
+    /// TSC right before the jump to the kernel.
+    pub kernel_entry: u64,
+    /// Indexed by [`BootPhase`].
+    pub phases: [PhaseTimestamps; BOOT_PHASE_COUNT],
+}
+
+impl BootTimings {
+    pub const fn empty() -> Self {
+        Self {
+            tsc_per_ms: 0,
+            bootloader_start: 0,
+            kernel_entry: 0,
+            phases: [PhaseTimestamps { start: 0, end: 0 }; BOOT_PHASE_COUNT],
+        }
+    }
+
+    /// Records that `phase` ran from TSC `start` to `end`.
+    pub fn record(&mut self, phase: BootPhase, start: u64, end: u64) {
+        self.phases[phase as usize] = PhaseTimestamps { start, end };
+    }
+
+    /// TSC ticks `phase` took, or `None` if it didn't run.
+    pub fn ticks(&self, phase: BootPhase) -> Option<u64> {
+        let PhaseTimestamps { start, end } = self.phases[phase as usize];
+        (start != 0 && end >= start).then(|| end - start)
+    }
+
+    /// Converts TSC ticks to microseconds, if the TSC rate is known.
+    pub fn ticks_to_us(&self, ticks: u64) -> Option<u64> {
+        (self.tsc_per_ms != 0).then(|| (ticks as u128 * 1000 / self.tsc_per_ms as u128) as u64)
+    }
+
+    /// Microseconds `phase` took, if it ran and the TSC rate is known.
+    pub fn duration_us(&self, phase: BootPhase) -> Option<u64> {
+        self.ticks_to_us(self.ticks(phase)?)
+    }
+
+    /// Microseconds from the start of the bootloader to the kernel entry.
+    pub fn total_us(&self) -> Option<u64> {
+        if self.bootloader_start == 0 || self.kernel_entry < self.bootloader_start {
+            return None;
+        }
+        self.ticks_to_us(self.kernel_entry - self.bootloader_start)
+    }
+}
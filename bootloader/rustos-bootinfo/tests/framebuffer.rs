@@ -0,0 +1,78 @@
+use rustos_bootinfo::{Framebuffer, FramebufferInfo};
+
+fn info(width: u32, height: u32, pitch: u32, bpp: u32, masks: (u32, u32, u32)) -> FramebufferInfo {
+    FramebufferInfo {
+        addr: 0x1000,
+        width,
+        height,
+        pitch,
+        bpp,
+        red_mask: masks.0,
+        green_mask: masks.1,
+        blue_mask: masks.2,
+        reserved_mask: 0,
+    }
+}
+
+const BGR: (u32, u32, u32) = (0xff_0000, 0x00_ff00, 0x00_00ff);
+const RGB: (u32, u32, u32) = (0x00_00ff, 0x00_ff00, 0xff_0000);
+const RGB565: (u32, u32, u32) = (0xf800, 0x07e0, 0x001f);
+
+fn pixel(memory: &[u8], offset: usize, bytes: usize) -> u32 {
+    let mut value = [0u8; 4];
+    value[..bytes].copy_from_slice(&memory[offset..offset + bytes]);
+    u32::from_le_bytes(value)
+}
+
+#[test]
+fn packs_colours_by_mask() {
+    let mut memory = vec![0u8; 64];
+    let bgr = unsafe { Framebuffer::with_base(&info(4, 4, 16, 32, BGR), memory.as_mut_ptr()) }.unwrap();
+    let rgb = unsafe { Framebuffer::with_base(&info(4, 4, 16, 32, RGB), memory.as_mut_ptr()) }.unwrap();
+    let rgb565 = unsafe { Framebuffer::with_base(&info(4, 4, 8, 16, RGB565), memory.as_mut_ptr()) }.unwrap();
+
+    assert_eq!(bgr.color(0x123456), 0x123456);
+    assert_eq!(rgb.color(0x123456), 0x563412);
+    assert_eq!(rgb565.color(0xffffff), 0xffff);
+    assert_eq!(rgb565.color(0xff0000), 0xf800);
+    assert_eq!(rgb565.color(0x00ff00), 0x07e0);
+}
+
+#[test]
+fn addresses_pixels_by_pitch() {
+    // 3 pixels wide, but each line is 16 bytes
+    let mut memory = vec![0u8; 16 * 2];
+    let mut fb = unsafe { Framebuffer::with_base(&info(3, 2, 16, 32, BGR), memory.as_mut_ptr()) }.unwrap();
+    fb.set_pixel(2, 1, 0xaabbcc);
+    assert_eq!(pixel(&memory, 16 + 8, 4), 0xaabbcc);
+    assert_eq!(memory.iter().filter(|&&byte| byte != 0).count(), 3);
+}
+
+#[test]
+fn clips_to_the_screen() {
+    let mut memory = vec![0u8; 12 * 3];
+    let mut fb = unsafe { Framebuffer::with_base(&info(3, 3, 12, 24, BGR), memory.as_mut_ptr()) }.unwrap();
+    fb.set_pixel(3, 0, 0xffffff);
+    fb.set_pixel(0, 3, 0xffffff);
+    assert!(memory.iter().all(|&byte| byte == 0));
+
+    fb.fill_rect(1, 1, 100, 100, 0x010203);
+    for y in 0..3 {
+        for x in 0..3 {
+            let expected = if x >= 1 && y >= 1 { 0x010203 } else { 0 };
+            assert_eq!(pixel(&memory, y * 12 + x * 3, 3), expected, "pixel ({}, {})", x, y);
+        }
+        // Padding at the end of each line stays untouched
+        assert_eq!(&memory[y * 12 + 9..y * 12 + 12], &[0, 0, 0]);
+    }
+}
+
+#[test]
+fn rejects_unusable_framebuffers() {
+    let mut memory = vec![0u8; 64];
+    let mut blt_only = info(4, 4, 0, 0, (0, 0, 0));
+    blt_only.addr = 0;
+    assert!(unsafe { Framebuffer::new(&blt_only) }.is_none());
+    // Pitch too small for the width
+    assert!(unsafe { Framebuffer::with_base(&info(4, 4, 8, 32, BGR), memory.as_mut_ptr()) }.is_none());
+}
@@ -0,0 +1,32 @@
+use rustos_bootinfo::BootLog;
+
+fn log(buffer: &[u8], written: u64) -> BootLog {
+    BootLog {
+        buffer: buffer.as_ptr(),
+        capacity: buffer.len() as u64,
+        written,
+    }
+}
+
+#[test]
+fn unwrapped_log_is_the_written_prefix() {
+    let buffer = *b"hello\n\0\0";
+    let log = log(&buffer, 6);
+    assert!(!log.is_truncated());
+    assert_eq!(unsafe { log.as_slices() }, (&b""[..], &b"hello\n"[..]));
+}
+
+#[test]
+fn wrapped_log_starts_at_the_oldest_byte() {
+    // "abcdefghij" written into 8 bytes: "ij" overwrote "ab"
+    let buffer = *b"ijcdefgh";
+    let log = log(&buffer, 10);
+    assert!(log.is_truncated());
+    let (older, newer) = unsafe { log.as_slices() };
+    assert_eq!([older, newer].concat(), b"cdefghij");
+}
+
+#[test]
+fn empty_log_has_no_text() {
+    assert_eq!(unsafe { BootLog::empty().as_slices() }, (&b""[..], &b""[..]));
+}
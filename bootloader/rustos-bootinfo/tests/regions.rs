@@ -0,0 +1,87 @@
+use rustos_bootinfo::{build_memory_regions, MemoryDescriptor, MemoryRegion, MemoryRegionKind};
+
+use MemoryRegionKind::*;
+
+const CONVENTIONAL: u32 = 7;
+const BOOT_SERVICES_DATA: u32 = 4;
+const LOADER_DATA: u32 = 2;
+const RUNTIME_SERVICES_DATA: u32 = 6;
+const ACPI_RECLAIM: u32 = 9;
+
+fn descriptor(ty: u32, start: u64, pages: u64) -> MemoryDescriptor {
+    MemoryDescriptor {
+        ty,
+        physical_start: start,
+        virtual_start: 0,
+        page_count: pages,
+        attribute: 0,
+    }
+}
+
+fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+    MemoryRegion { start, end, kind }
+}
+
+fn build(map: &[MemoryDescriptor], overrides: &[MemoryRegion]) -> Vec<MemoryRegion> {
+    let mut out = vec![region(0, 0, Reserved); 32];
+    let len = build_memory_regions(map.iter(), overrides.iter().copied(), &mut out).expect("buffer too small");
+    out.truncate(len);
+    out
+}
+
+#[test]
+fn sorts_and_merges_neighbours() {
+    let map = [
+        descriptor(CONVENTIONAL, 0x10_0000, 0x100),
+        descriptor(ACPI_RECLAIM, 0x1000, 1),
+        descriptor(BOOT_SERVICES_DATA, 0x20_0000, 0x10),
+        descriptor(CONVENTIONAL, 0x0, 1),
+        descriptor(RUNTIME_SERVICES_DATA, 0x21_0000, 2),
+    ];
+    assert_eq!(
+        build(&map, &[]),
+        [
+            region(0x0, 0x1000, Usable),
+            region(0x1000, 0x2000, AcpiReclaimable),
+            region(0x10_0000, 0x21_0000, Usable),
+            region(0x21_0000, 0x21_2000, Reserved),
+        ]
+    );
+}
+
+#[test]
+fn overrides_split_firmware_regions() {
+    let map = [descriptor(LOADER_DATA, 0x10_0000, 0x100), descriptor(CONVENTIONAL, 0x20_0000, 0x100)];
+    let overrides = [
+        region(0x14_0000, 0x15_0000, KernelImage),
+        region(0x1f_0000, 0x21_0000, Framebuffer),
+    ];
+    assert_eq!(
+        build(&map, &overrides),
+        [
+            region(0x10_0000, 0x14_0000, Bootloader),
+            region(0x14_0000, 0x15_0000, KernelImage),
+            region(0x15_0000, 0x1f_0000, Bootloader),
+            region(0x1f_0000, 0x21_0000, Framebuffer),
+            region(0x21_0000, 0x30_0000, Usable),
+        ]
+    );
+}
+
+#[test]
+fn override_outside_the_map_is_added() {
+    let map = [descriptor(CONVENTIONAL, 0, 0x10)];
+    let overrides = [region(0x8000_0000, 0x8040_0000, Framebuffer)];
+    assert_eq!(
+        build(&map, &overrides),
+        [region(0, 0x10000, Usable), region(0x8000_0000, 0x8040_0000, Framebuffer)]
+    );
+}
+
+#[test]
+fn reports_a_buffer_that_is_too_small() {
+    let map = [descriptor(CONVENTIONAL, 0, 0x100)];
+    let overrides = [region(0x1000, 0x2000, KernelImage)];
+    let mut out = [region(0, 0, Reserved); 2];
+    assert_eq!(build_memory_regions(map.iter(), overrides, &mut out), None);
+}
@@ -0,0 +1,33 @@
+use rustos_bootinfo::{BootPhase, BootTimings};
+
+#[test]
+fn durations_use_the_calibrated_rate() {
+    let mut timings = BootTimings::empty();
+    timings.tsc_per_ms = 2_000_000;
+    timings.bootloader_start = 1_000;
+    timings.record(BootPhase::Graphics, 10_000, 4_010_000);
+    timings.record(BootPhase::MemoryMap, 5_000_000, 5_003_000);
+    timings.kernel_entry = 20_001_000;
+
+    assert_eq!(timings.ticks(BootPhase::Graphics), Some(4_000_000));
+    assert_eq!(timings.duration_us(BootPhase::Graphics), Some(2_000));
+    assert_eq!(timings.duration_us(BootPhase::MemoryMap), Some(1));
+    assert_eq!(timings.duration_us(BootPhase::ElfLoad), None);
+    assert_eq!(timings.total_us(), Some(10_000));
+}
+
+#[test]
+fn uncalibrated_timings_only_have_ticks() {
+    let mut timings = BootTimings::empty();
+    timings.record(BootPhase::PageTables, 100, 350);
+    assert_eq!(timings.ticks(BootPhase::PageTables), Some(250));
+    assert_eq!(timings.duration_us(BootPhase::PageTables), None);
+    assert_eq!(timings.total_us(), None);
+}
+
+#[test]
+fn phases_are_in_table_order() {
+    for (index, phase) in BootPhase::ALL.iter().enumerate() {
+        assert_eq!(*phase as usize, index);
+    }
+}
@@ -0,0 +1,100 @@
+//! Starting other EFI applications from the boot menu: the UEFI shell, or a
+//! Linux kernel through its EFI stub.
+//!
+//! The image is handed to the firmware from memory with `LoadImage` and run
+//! with `StartImage`; the entry's command line becomes its load options.
+//! Along with the buffer the firmware gets the file's device path on the
+//! boot volume, so the image knows where it came from and options that name
+//! files next to it (like the Linux stub's `initrd=`) can find them.
+
+use alloc::vec::Vec;
+use rustos_bootconfig::BootEntry;
+use uefi::prelude::*;
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::boot::LoadImageSource;
+use uefi::{CStr16, CString16};
+
+const DOS_MAGIC: &[u8; 2] = b"MZ";
+const PE_MAGIC: &[u8; 4] = b"PE\0\0";
+/// Offset of `e_lfanew`, the file offset of the PE header, in the DOS header.
+const PE_HEADER_OFFSET: usize = 0x3c;
+
+/// Whether `data` looks like a PE/COFF image rather than an ELF kernel.
+pub fn is_efi_image(data: &[u8]) -> bool {
+    if !data.starts_with(DOS_MAGIC) {
+        return false;
+    }
+    let pe_offset = match data.get(PE_HEADER_OFFSET..PE_HEADER_OFFSET + 4) {
+        Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()) as usize,
+        None => return false,
+    };
+    data.get(pe_offset..).is_some_and(|header| header.starts_with(PE_MAGIC))
+}
+
+/// Loads the EFI application in `data` and runs it until it exits.
+pub fn start(boot_services: &BootServices, parent: Handle, data: &[u8], entry: &BootEntry) -> uefi::Result {
+    if !entry.modules.is_empty() {
+        log::warn!("{} is an EFI application, ignoring its modules", entry.kernel_path);
+    }
+    let options = CString16::try_from(entry.cmdline.as_str()).map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+    let path = CString16::try_from(entry.kernel_path.as_str()).map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+
+    let mut device_path_buffer = Vec::new();
+    let file_path = match file_device_path(boot_services, parent, &path, &mut device_path_buffer) {
+        Ok(file_path) => Some(file_path),
+        Err(e) => {
+            log::warn!("No device path for {}: {:?}", entry.kernel_path, e.status());
+            None
+        }
+    };
+
+    let child = boot_services.load_image(
+        parent,
+        LoadImageSource::FromBuffer {
+            buffer: data,
+            file_path,
+        },
+    )?;
+
+    // Load options are a NUL-terminated UCS-2 string, sized in bytes
+    if !entry.cmdline.is_empty() {
+        let mut loaded_image = match boot_services.open_protocol_exclusive::<LoadedImage>(child) {
+            Ok(loaded_image) => loaded_image,
+            Err(e) => {
+                let _ = boot_services.unload_image(child);
+                return Err(e);
+            }
+        };
+        unsafe { loaded_image.set_load_options(options.as_ptr() as *const u8, options.num_bytes() as u32) };
+    }
+
+    // `options` must stay alive until the image is done with it
+    let result = boot_services.start_image(child);
+    drop(options);
+    result
+}
+
+/// Device path of the file at `path` on the volume `image` was loaded from:
+/// the volume's device path followed by a file path node.
+fn file_device_path<'a>(
+    boot_services: &BootServices,
+    image: Handle,
+    path: &CStr16,
+    buffer: &'a mut Vec<u8>,
+) -> uefi::Result<&'a DevicePath> {
+    let loaded_image = boot_services.open_protocol_exclusive::<LoadedImage>(image)?;
+    let device_handle = loaded_image.device().ok_or(uefi::Error::from(Status::NOT_FOUND))?;
+    let device_path = boot_services.open_protocol_exclusive::<DevicePath>(device_handle)?;
+
+    let build_error = |_| uefi::Error::from(Status::BUFFER_TOO_SMALL);
+    let mut builder = DevicePathBuilder::with_vec(buffer);
+    for node in device_path.node_iter() {
+        builder = builder.push(&node).map_err(build_error)?;
+    }
+    builder
+        .push(&build::media::FilePath { path_name: path })
+        .and_then(|builder| builder.finalize())
+        .map_err(build_error)
+}
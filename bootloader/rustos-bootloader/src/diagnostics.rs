@@ -0,0 +1,68 @@
+//! Opt-in boot progress bar (`diagnostics = on` in the boot configuration).
+//!
+//! Each [`Stage`] of the handoff fills a bit more of a bar along the bottom
+//! edge of the framebuffer and is held on screen for a moment, so a boot that
+//! hangs shows how far it got. Boot services are gone for the later stages,
+//! so the pause is timed with the TSC, see [`crate::timing`].
+
+use rustos_bootinfo::{Framebuffer, FramebufferInfo};
+
+use crate::timing;
+
+const BAR_HEIGHT: usize = 8;
+const BAR_COLOR: u32 = 0x00C000;
+const TRACK_COLOR: u32 = 0x303030;
+/// How long each stage stays on screen.
+const STAGE_HOLD_MS: u64 = 250;
+
+/// Checkpoints of the handoff, in the order they are reached.
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    KernelLoaded,
+    PageTablesBuilt,
+    BootServicesExited,
+    EnteringKernel,
+}
+
+const STAGE_COUNT: usize = 4;
+
+pub struct Diagnostics {
+    /// `None` when diagnostics are off or there is nothing to draw on.
+    framebuffer: Option<Framebuffer>,
+    tsc_per_ms: u64,
+}
+
+impl Diagnostics {
+    /// Sets up the progress bar if `enabled`; otherwise every checkpoint is a
+    /// no-op. `tsc_per_ms` comes from [`timing::calibrate`].
+    pub fn new(framebuffer: &FramebufferInfo, tsc_per_ms: u64, enabled: bool) -> Self {
+        if !enabled {
+            return Self { framebuffer: None, tsc_per_ms: 0 };
+        }
+
+        // The bootloader runs identity mapped, before and after the CR3 switch
+        let mut framebuffer = unsafe { Framebuffer::new(framebuffer) };
+        if let Some(framebuffer) = framebuffer.as_mut() {
+            let (width, height) = (framebuffer.width(), framebuffer.height());
+            let track = framebuffer.color(TRACK_COLOR);
+            framebuffer.fill_rect(0, height.saturating_sub(BAR_HEIGHT), width, BAR_HEIGHT, track);
+        }
+        Self { framebuffer, tsc_per_ms }
+    }
+
+    /// Marks `stage` as reached and holds it on screen for a moment.
+    pub fn checkpoint(&mut self, stage: Stage) {
+        let Some(framebuffer) = self.framebuffer.as_mut() else {
+            return;
+        };
+        let (width, height) = (framebuffer.width(), framebuffer.height());
+        let filled = width * (stage as usize + 1) / STAGE_COUNT;
+        let bar = framebuffer.color(BAR_COLOR);
+        framebuffer.fill_rect(0, height.saturating_sub(BAR_HEIGHT), filled, BAR_HEIGHT, bar);
+
+        let end = timing::now() + STAGE_HOLD_MS * self.tsc_per_ms;
+        while timing::now() < end {
+            core::hint::spin_loop();
+        }
+    }
+}
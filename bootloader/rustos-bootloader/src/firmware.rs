@@ -0,0 +1,86 @@
+//! Tables the firmware publishes in the UEFI configuration table.
+
+use core::slice;
+use rustos_bootinfo::FirmwareTables;
+use uefi::prelude::*;
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID, SMBIOS3_GUID, SMBIOS_GUID};
+use uefi::{guid, Guid};
+
+/// `EFI_MEMORY_ATTRIBUTES_TABLE_GUID`
+const MEMORY_ATTRIBUTES_TABLE_GUID: Guid = guid!("dcfa911d-26eb-469f-a220-38b7dc461220");
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the ACPI 1.0 RSDP, covered by the first checksum.
+const RSDP_V1_LEN: usize = 20;
+/// Size of the ACPI 2.0+ XSDP up to and including the extended checksum.
+const RSDP_V2_LEN: usize = 36;
+const RSDP_REVISION_OFFSET: usize = 15;
+const RSDP_LENGTH_OFFSET: usize = 20;
+
+/// Returns the physical address of the ACPI RSDP, preferring a valid 2.0+
+/// XSDP over the 1.0 one, and the other tables the kernel may want.
+///
+/// `efi_system_table` is left 0; it is only final after exit_boot_services.
+pub fn find_tables(system_table: &SystemTable<Boot>) -> (Option<u64>, FirmwareTables) {
+    let mut tables = FirmwareTables::empty();
+    let mut acpi1 = None;
+    let mut acpi2 = None;
+    for entry in system_table.config_table() {
+        let addr = entry.address as u64;
+        match entry.guid {
+            ACPI2_GUID => acpi2 = Some(addr),
+            ACPI_GUID => acpi1 = Some(addr),
+            SMBIOS_GUID => tables.smbios_addr = addr,
+            SMBIOS3_GUID => tables.smbios3_addr = addr,
+            MEMORY_ATTRIBUTES_TABLE_GUID => tables.memory_attributes_table = addr,
+            _ => {}
+        }
+    }
+
+    let rsdp = [acpi2, acpi1].into_iter().flatten().find(|&addr| {
+        // Boot services run identity mapped
+        let valid = unsafe { rsdp_is_valid(addr as *const u8) };
+        if !valid {
+            log::warn!("Ignoring RSDP at 0x{:x} with a bad signature or checksum", addr);
+        }
+        valid
+    });
+    (rsdp, tables)
+}
+
+/// The RSDP at `rsdp`: 20 bytes for revision 0, the whole XSDP otherwise.
+///
+/// # Safety
+///
+/// `rsdp` must be the address of an RSDP [`find_tables`] accepted, and
+/// identity mapped.
+pub unsafe fn rsdp_bytes(rsdp: u64) -> &'static [u8] {
+    let rsdp = rsdp as *const u8;
+    if *rsdp.add(RSDP_REVISION_OFFSET) < 2 {
+        return slice::from_raw_parts(rsdp, RSDP_V1_LEN);
+    }
+    let length = u32::from_le_bytes(*(rsdp.add(RSDP_LENGTH_OFFSET) as *const [u8; 4])) as usize;
+    slice::from_raw_parts(rsdp, length)
+}
+
+/// Checks the signature and checksums of the RSDP at `rsdp`, including the
+/// extended checksum for revision 2 and later.
+///
+/// # Safety
+///
+/// `rsdp` must point to a readable RSDP, or at least 20 readable bytes.
+unsafe fn rsdp_is_valid(rsdp: *const u8) -> bool {
+    let v1 = slice::from_raw_parts(rsdp, RSDP_V1_LEN);
+    if &v1[..RSDP_SIGNATURE.len()] != RSDP_SIGNATURE || checksum(v1) != 0 {
+        return false;
+    }
+    if v1[RSDP_REVISION_OFFSET] < 2 {
+        return true;
+    }
+    let length = u32::from_le_bytes(*(rsdp.add(RSDP_LENGTH_OFFSET) as *const [u8; 4])) as usize;
+    length >= RSDP_V2_LEN && checksum(slice::from_raw_parts(rsdp, length)) == 0
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
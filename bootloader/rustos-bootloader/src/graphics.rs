@@ -0,0 +1,100 @@
+//! GOP mode setup and framebuffer handoff. Which mode to use is up to
+//! [`ResolutionPolicy::choose_mode`].
+
+use alloc::vec::Vec;
+use uefi::prelude::*;
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+use rustos_bootconfig::{ModeFormat, ResolutionPolicy};
+use rustos_bootinfo::FramebufferInfo;
+
+pub fn setup_graphics(
+    boot_services: &BootServices,
+    policy: ResolutionPolicy,
+) -> Result<FramebufferInfo, uefi::Error> {
+    let gop_handle = boot_services
+        .get_handle_for_protocol::<GraphicsOutput>()?;
+    
+    let mut gop = boot_services
+        .open_protocol_exclusive::<GraphicsOutput>(gop_handle)?;
+    
+    // List everything the firmware offers, with the GOP mode numbers
+    // alongside
+    let (current_width, current_height) = gop.current_mode_info().resolution();
+    let mut modes = Vec::new();
+    let mut mode_numbers = Vec::new();
+    for mode in gop.modes(boot_services) {
+        let info = mode.info();
+        let (width, height) = info.resolution();
+        let current = if (width, height) == (current_width, current_height) { " (current)" } else { "" };
+        log::info!("GOP mode {}: {}x{} {:?}{}", mode.index(), width, height, info.pixel_format(), current);
+        
+        let format = match info.pixel_format() {
+            PixelFormat::Rgb => ModeFormat::Rgb,
+            PixelFormat::Bgr => ModeFormat::Bgr,
+            PixelFormat::Bitmask => ModeFormat::Bitmask,
+            PixelFormat::BltOnly => ModeFormat::BltOnly,
+        };
+        modes.push((width, height, format));
+        mode_numbers.push(mode.index());
+    }
+    
+    if let Some(chosen) = policy.choose_mode(&modes) {
+        let mode = gop.query_mode(mode_numbers[chosen], boot_services)?;
+        if mode.info().resolution() != (current_width, current_height) {
+            gop.set_mode(&mode)?;
+        }
+    }
+    
+    let mode_info = gop.current_mode_info();
+    log::info!("Using {}x{} (resolution policy {:?})", mode_info.resolution().0, mode_info.resolution().1, policy);
+    let (width, height) = mode_info.resolution();
+    
+    // Channel masks within a pixel's little-endian value
+    let (red_mask, green_mask, blue_mask, reserved_mask) = match mode_info.pixel_format() {
+        PixelFormat::Rgb => (0x00_00_00_FF, 0x00_00_FF_00, 0x00_FF_00_00, 0xFF_00_00_00),
+        PixelFormat::Bgr => (0x00_FF_00_00, 0x00_00_FF_00, 0x00_00_00_FF, 0xFF_00_00_00),
+        PixelFormat::Bitmask => match mode_info.pixel_bitmask() {
+            Some(bitmask) => (bitmask.red, bitmask.green, bitmask.blue, bitmask.reserved),
+            None => (0, 0, 0, 0),
+        },
+        PixelFormat::BltOnly => (0, 0, 0, 0),
+    };
+    let bpp = bits_per_pixel(red_mask | green_mask | blue_mask | reserved_mask);
+    
+    if bpp == 0 {
+        // BltOnly: the firmware can draw for us but there is no memory the
+        // kernel could write pixels to
+        log::warn!("No linear framebuffer in this mode, the kernel will only have serial output");
+        return Ok(FramebufferInfo {
+            addr: 0,
+            width: width as u32,
+            height: height as u32,
+            pitch: 0,
+            bpp: 0,
+            red_mask: 0,
+            green_mask: 0,
+            blue_mask: 0,
+            reserved_mask: 0,
+        });
+    }
+    
+    let mut framebuffer = gop.frame_buffer();
+    Ok(FramebufferInfo {
+        addr: framebuffer.as_mut_ptr() as u64,
+        width: width as u32,
+        height: height as u32,
+        pitch: (mode_info.stride() * (bpp as usize / 8)) as u32,
+        bpp,
+        red_mask,
+        green_mask,
+        blue_mask,
+        reserved_mask,
+    })
+}
+
+/// Pixel size implied by the union of the channel masks, rounded up to
+/// whole bytes; 0 if there are no masks.
+fn bits_per_pixel(masks: u32) -> u32 {
+    let bits = u32::BITS - masks.leading_zeros();
+    bits.div_ceil(8) * 8
+}
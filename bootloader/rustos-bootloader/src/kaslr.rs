@@ -0,0 +1,129 @@
+//! Kernel address space layout randomisation.
+//!
+//! A position independent higher-half kernel (see
+//! [`ElfFile::can_slide`](rustos_elfloader::ElfFile::can_slide)) is moved up
+//! by a random multiple of 2 MiB, less than 1 GiB, so it stays in the top
+//! 2 GiB the kernel code model needs. Its physical pages go to a random
+//! 2 MiB-aligned spot in free memory. The address arithmetic is in
+//! [`rustos_elfloader::kaslr`].
+//!
+//! Entropy comes from the firmware's `EFI_RNG_PROTOCOL` if it has one, then
+//! `RDSEED` and `RDRAND`. As a last resort it is the jitter of the TSC across
+//! short stalls, which is weak but still better than a fixed address.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+use core::fmt;
+use rustos_elfloader::kaslr::random_slot;
+use uefi::prelude::*;
+use uefi::proto::rng::Rng;
+use uefi::table::boot::{AllocateType, MemoryType};
+
+use crate::paging::PAGE_SIZE;
+
+/// `RDRAND` and `RDSEED` can fail transiently; Intel suggests 10 retries.
+const HW_RETRIES: usize = 10;
+/// Stalls timed for the TSC jitter fallback.
+const JITTER_ROUNDS: usize = 64;
+
+/// Where [`random_u64`] got its bits from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    Firmware,
+    Rdseed,
+    Rdrand,
+    TscJitter,
+}
+
+impl fmt::Display for EntropySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EntropySource::Firmware => "EFI RNG protocol",
+            EntropySource::Rdseed => "RDSEED",
+            EntropySource::Rdrand => "RDRAND",
+            EntropySource::TscJitter => "TSC jitter",
+        };
+        f.write_str(name)
+    }
+}
+
+/// 64 random bits from the best source available.
+pub fn random_u64(boot_services: &BootServices) -> (u64, EntropySource) {
+    if let Some(value) = firmware_random(boot_services) {
+        return (value, EntropySource::Firmware);
+    }
+    if let Some(value) = rdseed_supported().then(rdseed).flatten() {
+        return (value, EntropySource::Rdseed);
+    }
+    if let Some(value) = rdrand_supported().then(rdrand).flatten() {
+        return (value, EntropySource::Rdrand);
+    }
+    (tsc_jitter(boot_services), EntropySource::TscJitter)
+}
+
+fn firmware_random(boot_services: &BootServices) -> Option<u64> {
+    let handle = boot_services.get_handle_for_protocol::<Rng>().ok()?;
+    let mut rng = boot_services.open_protocol_exclusive::<Rng>(handle).ok()?;
+    let mut bytes = [0u8; 8];
+    rng.get_rng(None, &mut bytes).ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// CPUID.07h:EBX.RDSEED
+fn rdseed_supported() -> bool {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0
+}
+
+/// CPUID.01h:ECX.RDRAND
+fn rdrand_supported() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 30) != 0
+}
+
+fn rdseed() -> Option<u64> {
+    (0..HW_RETRIES).find_map(|_| {
+        let (value, ok): (u64, u8);
+        unsafe { asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        (ok != 0).then_some(value)
+    })
+}
+
+fn rdrand() -> Option<u64> {
+    (0..HW_RETRIES).find_map(|_| {
+        let (value, ok): (u64, u8);
+        unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        (ok != 0).then_some(value)
+    })
+}
+
+/// Mixes how many TSC ticks each of a series of 1 µs stalls took.
+fn tsc_jitter(boot_services: &BootServices) -> u64 {
+    let mut value = unsafe { _rdtsc() };
+    for _ in 0..JITTER_ROUNDS {
+        let start = unsafe { _rdtsc() };
+        boot_services.stall(1);
+        let ticks = unsafe { _rdtsc() }.wrapping_sub(start);
+        value = (value ^ ticks).wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(29);
+    }
+    value
+}
+
+/// Allocates `pages` pages at a random 2 MiB-aligned address in
+/// conventional memory. Returns `None` if no such range is free.
+pub fn allocate_random(boot_services: &BootServices, memory_type: MemoryType, pages: u64, random: u64) -> Option<u64> {
+    let sizes = boot_services.memory_map_size();
+    let mut buffer = vec![0u8; sizes.map_size + 8 * sizes.entry_size];
+    let memory_map = boot_services.memory_map(&mut buffer).ok()?;
+
+    let free: Vec<(u64, u64)> = memory_map
+        .entries()
+        .filter(|descriptor| descriptor.ty == MemoryType::CONVENTIONAL)
+        .map(|descriptor| (descriptor.phys_start, descriptor.phys_start + descriptor.page_count * PAGE_SIZE))
+        .collect();
+    let addr = random_slot(free.iter().copied(), pages * PAGE_SIZE, random)?;
+    boot_services
+        .allocate_pages(AllocateType::Address(addr), memory_type, pages as usize)
+        .ok()
+}
@@ -0,0 +1,117 @@
+//! `log` backend for the bootloader.
+//!
+//! Records go to the UEFI console while boot services are up, to COM1, and
+//! into a ring buffer the kernel gets as [`BootLog`]. Serial and the buffer
+//! keep working after `exit_boot_services`, so the kernel can still show
+//! what happened between the last console message and its entry point.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::ptr;
+use log::{LevelFilter, Log, Metadata, Record};
+use rustos_bootconfig::LogFilter;
+use rustos_bootinfo::BootLog;
+use uefi::prelude::*;
+use uefi::proto::console::text::Output;
+
+use crate::serial::{SerialPort, COM1};
+
+const LOG_BUFFER_SIZE: usize = 64 * 1024;
+
+struct Logger {
+    state: UnsafeCell<State>,
+}
+
+struct State {
+    /// Null once boot services are gone.
+    console: *mut Output,
+    serial: Option<SerialPort>,
+    filter: LogFilter,
+    buffer: [u8; LOG_BUFFER_SIZE],
+    written: u64,
+}
+
+// The bootloader runs on one CPU and never logs from event callbacks
+unsafe impl Sync for Logger {}
+unsafe impl Send for Logger {}
+
+static LOGGER: Logger = Logger {
+    state: UnsafeCell::new(State {
+        console: ptr::null_mut(),
+        serial: None,
+        filter: LogFilter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        },
+        buffer: [0; LOG_BUFFER_SIZE],
+        written: 0,
+    }),
+};
+
+/// Installs the logger at `info` level until [`set_filter`] is called.
+pub fn init(system_table: &mut SystemTable<Boot>) {
+    let state = unsafe { &mut *LOGGER.state.get() };
+    state.console = system_table.stdout() as *mut Output;
+    state.serial = Some(unsafe { SerialPort::init(COM1) });
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(state.filter.default);
+    }
+}
+
+pub fn set_filter(filter: LogFilter) {
+    log::set_max_level(filter.max_level());
+    unsafe { (*LOGGER.state.get()).filter = filter };
+}
+
+/// Stops writing to the UEFI console. Must be called before boot services
+/// are exited.
+pub fn exit_boot_services() {
+    unsafe { (*LOGGER.state.get()).console = ptr::null_mut() };
+}
+
+/// Describes the log buffer for the kernel. Anything logged afterwards is
+/// still written to the buffer but may not be covered by `written`.
+pub fn boot_log() -> BootLog {
+    let state = unsafe { &*LOGGER.state.get() };
+    BootLog {
+        buffer: state.buffer.as_ptr(),
+        capacity: LOG_BUFFER_SIZE as u64,
+        written: state.written,
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let state = unsafe { &*self.state.get() };
+        metadata.level() <= state.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let state = unsafe { &mut *self.state.get() };
+        let target = record.target();
+        let module = target.strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::")).unwrap_or(target);
+        let _ = writeln!(state, "{:5} [{}] {}", record.level(), module, record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+impl Write for State {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.console.is_null() {
+            let _ = unsafe { &mut *self.console }.write_str(s);
+        }
+        if let Some(serial) = self.serial.as_mut() {
+            let _ = serial.write_str(s);
+        }
+        for byte in s.bytes() {
+            self.buffer[(self.written % LOG_BUFFER_SIZE as u64) as usize] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
@@ -2,616 +2,709 @@
 #![no_main]
 
 extern crate alloc;
-use uefi_services::println;
 
+mod chainload;
+mod diagnostics;
+mod firmware;
+mod graphics;
+mod kaslr;
+mod logger;
+mod menu;
+mod multiboot2_boot;
+mod paging;
+mod serial;
+mod timing;
+
+use alloc::format;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::mem;
 use core::slice;
-use core::fmt::Write;
 use uefi::prelude::*;
-use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
 use uefi::proto::media::file::{File, FileAttribute, FileMode};
 use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use uefi::table::boot::{AllocateType, MemoryAttribute, MemoryDescriptor, MemoryMap, MemoryType};
+use uefi::CString16;
+use rustos_bootconfig::verify::{Verifier, SIGNATURE_SUFFIX};
+use rustos_bootconfig::{module_name, parse_hex, BootConfig, CONFIG_PATH};
+use rustos_bootinfo::{
+    build_memory_regions, BootInfo, BootInfoHeader, BootLog, BootModule, BootModules, BootPhase, BootStr, BootTimings,
+    FramebufferInfo, KernelImageInfo, KernelStackInfo, MemoryMapInfo, MemoryRegion, MemoryRegionKind, MemoryRegions,
+    KERNEL_ENTRY_MAGIC,
+};
+use rustos_elfloader::{ElfError, ElfFile, LoadedImage, Placement, Segment, SegmentAllocator, PF_W, PF_X};
+use diagnostics::{Diagnostics, Stage};
+use paging::{PageTableBuilder, HUGE_PAGE_SIZE, PAGE_NO_EXECUTE, PAGE_SIZE, PAGE_WRITABLE};
 
 const BOOT_INFO_ADDR: u64 = 0x8000_0000;
 const KERNEL_ADDR: u64 = 0x4000_0000;
 
-#[repr(C, align(4096))]
-struct PageTable {
-    entries: [u64; 512],
-}
-
-impl PageTable {
-    fn new() -> Self {
-        Self {
-            entries: [0; 512],
-        }
-    }
-    
-    fn set_entry(&mut self, index: usize, addr: u64, flags: u64) {
-        self.entries[index] = addr | flags;
-    }
-}
-
-const PAGE_PRESENT: u64 = 1 << 0;
-const PAGE_WRITABLE: u64 = 1 << 1;
-const PAGE_HUGE: u64 = 1 << 7;
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct BootInfo {
-    pub memory_map: MemoryMapInfo,
-    pub framebuffer: FramebufferInfo,
-    pub rsdp_addr: Option<u64>,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct MemoryMapInfo {
-    pub entries: *const MemoryDescriptor,
-    pub entry_count: usize,
-    pub entry_size: usize,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct FramebufferInfo {
-    pub addr: u64,
-    pub width: u32,
-    pub height: u32,
-    pub pitch: u32,
-    pub bpp: u32,
-    pub red_mask: u32,
-    pub green_mask: u32,
-    pub blue_mask: u32,
-}
+/// Virtual address at which all of physical memory is mapped for the kernel.
+const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+/// Lower bound for the identity and direct maps, so the MMIO hole below
+/// 4 GiB (local APIC, I/O APIC, HPET) is covered even though the memory map
+/// usually doesn't list it.
+const MIN_MAPPED_PHYSICAL_MEMORY: u64 = 0x1_0000_0000;
+/// Start of the kernel stack's guard page. The stack lives in its own slot
+/// (PML4 entry 510) right above it, away from the direct and kernel maps.
+const KERNEL_STACK_GUARD_START: u64 = 0xffff_ff00_0000_0000;
+const KERNEL_STACK_GUARD_SIZE: u64 = PAGE_SIZE;
+/// UEFI runtime services regions are mapped at their physical address plus
+/// this (PML4 entry 508), so the kernel can hand them to SetVirtualAddressMap.
+const EFI_RUNTIME_OFFSET: u64 = 0xffff_fe00_0000_0000;
+/// Extra region list entries on top of two per descriptor, for descriptors
+/// the firmware adds between sizing the map and exit_boot_services.
+const MEMORY_REGION_SLACK: usize = 64;
+/// Ed25519 public key from `RUSTOS_ED25519_KEY` at build time, which the
+/// config can't override.
+const BUILTIN_KEY: Option<[u8; 32]> = match option_env!("RUSTOS_ED25519_KEY") {
+    Some(hex) => match parse_hex(hex) {
+        Some(key) => Some(key),
+        None => panic!("RUSTOS_ED25519_KEY must be 64 hex digits"),
+    },
+    None => None,
+};
 
 #[entry]
 fn efi_main(image: Handle, mut system_table: SystemTable<Boot>) -> Status {
+    let mut timings = BootTimings {
+        bootloader_start: timing::now(),
+        ..BootTimings::empty()
+    };
     uefi_services::init(&mut system_table).unwrap();
+    logger::init(&mut system_table);
+    log::info!("RustOS Bootloader Starting...");
+    
+    // Read the boot configuration
+    let config = match load_config(system_table.boot_services(), image) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Invalid boot configuration: {}", e);
+            system_table.boot_services().stall(10_000_000);
+            return Status::LOAD_ERROR;
+        }
+    };
+    logger::set_filter(config.log_filter.clone());
+    let verifier = Verifier::new(&config, BUILTIN_KEY);
+    
+    // Let the user pick what to boot. EFI applications are started right
+    // away; when one exits, or a kernel can't be read or verified, the menu
+    // comes back, without a countdown.
+    let mut autoboot = true;
+    let (entry, kernel_data) = loop {
+        let entry = menu::run(&mut system_table, &config, autoboot);
+        autoboot = false;
+        log::info!("Booting {}: {}, cmdline: \"{}\"", entry.name, entry.kernel_path, entry.cmdline);
+        
+        // Load kernel from filesystem
+        log::info!("Loading kernel...");
+        let read_start = timing::now();
+        let kernel_data = match read_file(system_table.boot_services(), image, &entry.kernel_path) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("Failed to read kernel {}: {:?}", entry.kernel_path, e.status());
+                system_table.boot_services().stall(5_000_000);
+                continue;
+            }
+        };
+        timings.record(BootPhase::KernelRead, read_start, timing::now());
+        if let Err(e) = verify_file(system_table.boot_services(), image, &verifier, &entry.kernel_path, &kernel_data) {
+            log::error!("Refusing to boot {}: {}", entry.name, e);
+            system_table.boot_services().stall(5_000_000);
+            continue;
+        }
+        if !chainload::is_efi_image(&kernel_data) {
+            break (entry, kernel_data);
+        }
+        
+        match chainload::start(system_table.boot_services(), image, &kernel_data, &entry) {
+            Ok(()) => log::info!("{} exited", entry.name),
+            Err(e) => {
+                log::error!("Failed to start {}: {:?}", entry.kernel_path, e.status());
+                system_table.boot_services().stall(5_000_000);
+            }
+        }
+    };
     
-    // Add debug output right at the start
-    system_table.stdout().write_str("RustOS Bootloader Starting...\n").unwrap();
+    // Multiboot2 kernels get their own handoff instead of BootInfo
+    match rustos_multiboot2::Header::find(&kernel_data) {
+        Ok(Some(header)) => {
+            return multiboot2_boot::boot(image, system_table, &config, &verifier, &entry, &kernel_data, &header);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("Unsupported Multiboot2 header in {}: {}", entry.kernel_path, e);
+            system_table.boot_services().stall(10_000_000);
+            return Status::LOAD_ERROR;
+        }
+    }
+    
+    // Lets the kernel turn the phase timestamps into time
+    timings.tsc_per_ms = timing::calibrate(system_table.boot_services());
     
     // Set up graphics mode
-    system_table.stdout().write_str("Setting up graphics...\n").unwrap();
-    let framebuffer_info = setup_graphics(system_table.boot_services()).expect("Failed to setup graphics");
-    
-    // Debug: Print framebuffer info
-    system_table.stdout().write_str("Framebuffer addr: 0x").unwrap();
-    print_hex(&mut system_table, framebuffer_info.addr);
-    system_table.stdout().write_str("\n").unwrap();
-    system_table.stdout().write_str("Framebuffer width: ").unwrap();
-    print_decimal(&mut system_table, framebuffer_info.width as u64);
-    system_table.stdout().write_str("\n").unwrap();
-    system_table.stdout().write_str("Framebuffer height: ").unwrap();
-    print_decimal(&mut system_table, framebuffer_info.height as u64);
-    system_table.stdout().write_str("\n").unwrap();
-    
-    // Load kernel from filesystem
-    system_table.stdout().write_str("Loading kernel...\n").unwrap();
-    let kernel_data = load_kernel(system_table.boot_services(), image).expect("Failed to load kernel");
+    log::info!("Setting up graphics...");
+    let graphics_start = timing::now();
+    let framebuffer_info = graphics::setup_graphics(system_table.boot_services(), config.resolution)
+        .expect("Failed to setup graphics");
+    timings.record(BootPhase::Graphics, graphics_start, timing::now());
+    log::info!(
+        "Framebuffer at 0x{:x}, {}x{}",
+        framebuffer_info.addr, framebuffer_info.width, framebuffer_info.height
+    );
+    
+    let mut diagnostics = Diagnostics::new(&framebuffer_info, timings.tsc_per_ms, config.diagnostics);
     
     // Parse ELF and get entry point
-    system_table.stdout().write_str("Parsing ELF...\n").unwrap();
-    let entry_point = parse_elf_and_load(&kernel_data, system_table.boot_services()).expect("Failed to parse ELF");
+    log::info!("Parsing ELF...");
+    let load_start = timing::now();
+    let kernel = match parse_elf_and_load(&kernel_data, system_table.boot_services(), config.kaslr) {
+        Ok(kernel) => kernel,
+        Err(e) => {
+            log::error!("Failed to load kernel ELF: {}", e);
+            system_table.boot_services().stall(10_000_000);
+            return Status::LOAD_ERROR;
+        }
+    };
+    timings.record(BootPhase::ElfLoad, load_start, timing::now());
+    
+    let entry_point = kernel.entry;
+    
+    // Load boot modules next to the kernel
+    let modules = match load_modules(system_table.boot_services(), image, &verifier, &entry.modules) {
+        Ok(modules) => modules,
+        Err(e) => {
+            log::error!("Failed to load boot modules: {}", e);
+            system_table.boot_services().stall(10_000_000);
+            return Status::LOAD_ERROR;
+        }
+    };
     
-    // Debug: Print the entry point address and where we loaded segments
-    system_table.stdout().write_str("Entry point: 0x").unwrap();
-    print_hex(&mut system_table, entry_point);
-    system_table.stdout().write_str(" (jumping to this address)\n").unwrap();
+    diagnostics.checkpoint(Stage::KernelLoaded);
     
-    // Set up identity mapping for first 1GB
-    system_table.stdout().write_str("Setting up identity mapping...\n").unwrap();
-    setup_identity_mapping(system_table.boot_services()).expect("Failed to setup identity mapping");
+    log::info!("Entry point: 0x{:x}", entry_point);
     
     // Allocate kernel stack before exiting boot services
-    system_table.stdout().write_str("Allocating kernel stack...\n").unwrap();
-    let stack_pages = system_table.boot_services().allocate_pages(
+    log::debug!("Allocating kernel stack...");
+    let stack_phys = system_table.boot_services().allocate_pages(
         uefi::table::boot::AllocateType::AnyPages,
         MemoryType::LOADER_DATA,
-        16, // 16 pages = 64KB
+        (config.stack_size / PAGE_SIZE) as usize,
     ).expect("Failed to allocate kernel stack");
-    let stack_top = stack_pages + (16 * 0x1000); // Stack grows downward
+    let stack = KernelStackInfo {
+        bottom: KERNEL_STACK_GUARD_START + KERNEL_STACK_GUARD_SIZE,
+        top: KERNEL_STACK_GUARD_START + KERNEL_STACK_GUARD_SIZE + config.stack_size, // Stack grows downward
+        guard_start: KERNEL_STACK_GUARD_START,
+    };
     
-    // Get memory map before exiting boot services
-    system_table.stdout().write_str("Getting memory map...\n").unwrap();
-    system_table.stdout().write_str("About to call get_memory_map\n").unwrap();
-    let memory_map_info = get_memory_map(system_table.boot_services()).expect("Failed to get memory map");
-    system_table.stdout().write_str("Memory map obtained successfully\n").unwrap();
-    system_table.stdout().write_str("Memory map has entries and continuing...\n").unwrap();
-    system_table.stdout().write_str("About to proceed to RSDP search...\n").unwrap();
+    // Build the kernel's page tables; they get loaded right before the jump
+    log::info!("Setting up page tables...");
+    let page_tables_start = timing::now();
+    let physical_memory_size = physical_memory_end(system_table.boot_services(), &framebuffer_info)
+        .expect("Failed to size physical memory");
+    log::info!("Mapping 0x{:x} bytes of physical memory", physical_memory_size);
+    let pml4_addr = setup_page_tables(
+        system_table.boot_services(),
+        &kernel,
+        &stack,
+        stack_phys,
+        PHYSICAL_MEMORY_OFFSET,
+        physical_memory_size,
+        EFI_RUNTIME_OFFSET,
+    ).expect("Failed to setup page tables");
+    timings.record(BootPhase::PageTables, page_tables_start, timing::now());
+    let stack_top = stack.top;
+    diagnostics.checkpoint(Stage::PageTablesBuilt);
+    
+    let cmdline = copy_to_loader_data(system_table.boot_services(), entry.cmdline.as_bytes())
+        .expect("Failed to copy kernel command line");
+    
+    // The memory map is only final once boot services are gone; reserve room
+    // for the region list now since nothing can be allocated afterwards
+    let memory_map_sizes = system_table.boot_services().memory_map_size();
+    let region_capacity = 2 * (memory_map_sizes.map_size / memory_map_sizes.entry_size)
+        + 2 * entry.modules.len()
+        + MEMORY_REGION_SLACK;
+    let regions_addr = system_table.boot_services().allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        (region_capacity * mem::size_of::<MemoryRegion>()).div_ceil(PAGE_SIZE as usize),
+    ).expect("Failed to allocate memory region list");
     
-    // Find RSDP
-    system_table.stdout().write_str("Finding RSDP...\n").unwrap();
-    let rsdp_addr = find_rsdp(&mut system_table);
-    system_table.stdout().write_str("RSDP search completed\n").unwrap();
+    // ACPI, SMBIOS and other firmware tables
+    let (rsdp_addr, mut firmware_tables) = firmware::find_tables(&system_table);
+    firmware_tables.runtime_services_offset = EFI_RUNTIME_OFFSET;
+    log::debug!("RSDP: {:x?}, {:x?}", rsdp_addr, firmware_tables);
     
     // Create BootInfo structure
-    system_table.stdout().write_str("Creating BootInfo structure...\n").unwrap();
     let boot_info = BootInfo {
-        memory_map: memory_map_info,
+        header: BootInfoHeader::current(),
+        // Filled in from the final map after exit_boot_services
+        memory_map: MemoryMapInfo::empty(),
+        memory_regions: MemoryRegions::empty(),
         framebuffer: framebuffer_info,
-        rsdp_addr,
+        rsdp_addr: rsdp_addr.unwrap_or(0),
+        kernel: KernelImageInfo {
+            phys_base: kernel.phys_base,
+            virt_base: kernel.virt_base,
+            size: kernel.pages * PAGE_SIZE,
+            slide: kernel.relocation_offset,
+        },
+        physical_memory_offset: PHYSICAL_MEMORY_OFFSET,
+        physical_memory_size,
+        cmdline,
+        modules,
+        stack,
+        // Filled in right before the jump so it covers everything logged
+        log: BootLog::empty(),
+        firmware: firmware_tables,
+        // The memory map phase and kernel entry are filled in later
+        timings,
     };
     
     // Allocate memory for BootInfo through UEFI boot services
-    system_table.stdout().write_str("Allocating memory for BootInfo...\n").unwrap();
     let boot_info_addr = system_table.boot_services().allocate_pages(
         uefi::table::boot::AllocateType::AnyPages,
         MemoryType::LOADER_DATA,
         1, // 1 page should be enough for BootInfo
     ).expect("Failed to allocate BootInfo memory");
     
-    system_table.stdout().write_str("Placing BootInfo at allocated address...\n").unwrap();
     unsafe {
-        let boot_info_ptr = boot_info_addr as *mut BootInfo;
-        system_table.stdout().write_str("About to write BootInfo to memory...\n").unwrap();
-        *boot_info_ptr = boot_info;
-        system_table.stdout().write_str("BootInfo write completed...\n").unwrap();
+        *(boot_info_addr as *mut BootInfo) = boot_info;
     }
-    system_table.stdout().write_str("BootInfo placed successfully\n").unwrap();
+    log::debug!("BootInfo placed at 0x{:x}", boot_info_addr);
     
     // Exit boot services - UEFI 0.26 API takes only MemoryType parameter
-    system_table.stdout().write_str("Exiting boot services...\n").unwrap();
-    let (_runtime_system_table, _memory_map) = system_table
+    log::info!("Exiting boot services...");
+    let memory_map_start = timing::now();
+    logger::exit_boot_services();
+    let (runtime_system_table, memory_map) = system_table
         .exit_boot_services(MemoryType::LOADER_DATA);
     
-    // At this point, we can't use stdout anymore
-    // Now try to write to the framebuffer with expanded identity mapping
+    // Hand over the map exit_boot_services returned; its buffer is
+    // LOADER_DATA and stays put
     unsafe {
-        // Get framebuffer info from our boot_info
-        let boot_info_ref = &*(boot_info_addr as *const BootInfo);
-        let fb_addr = boot_info_ref.framebuffer.addr as *mut u32;
-        let fb_width = boot_info_ref.framebuffer.width;
-        
-        // Draw a red rectangle in top-left corner
-        for y in 0..100 {
-            for x in 0..200 {
-                let pixel_offset = (y * fb_width + x) as isize;
-                *fb_addr.offset(pixel_offset) = 0xFF0000; // Red
-            }
-        }
-        
-        // Small delay to make it visible
-        for _ in 0..50000000 {
-            core::arch::asm!("nop");
-        }
+        let boot_info = &mut *(boot_info_addr as *mut BootInfo);
+        boot_info.memory_map = memory_map_info(&memory_map, memory_map_sizes.entry_size);
+        boot_info.memory_regions = memory_regions(boot_info, regions_addr, region_capacity);
+        boot_info.firmware.efi_system_table = runtime_system_table.as_ptr() as u64;
+        boot_info.timings.record(BootPhase::MemoryMap, memory_map_start, timing::now());
     }
     
+    // At this point, we can't use stdout anymore; logging goes to serial
+    // and the log buffer only
+    diagnostics.checkpoint(Stage::BootServicesExited);
+    
     // Jump to kernel
+    diagnostics.checkpoint(Stage::EnteringKernel);
+    log::info!("Entering kernel at 0x{:x}", entry_point);
     unsafe {
-        // Draw a blue rectangle before jumping to show we're about to call kernel
-        let boot_info_ref = &*(boot_info_addr as *const BootInfo);
-        let fb_addr = boot_info_ref.framebuffer.addr as *mut u32;
-        let fb_width = boot_info_ref.framebuffer.width;
-        
-        for y in 200..250 {
-            for x in 0..200 {
-                let pixel_offset = (y * fb_width + x) as isize;
-                *fb_addr.offset(pixel_offset) = 0x0000FF; // Blue rectangle - "about to jump"
-            }
-        }
-        
-        // Small delay
-        for _ in 0..10000000 {
-            core::arch::asm!("nop");
-        }
-        
-    // Jump to kernel
-    unsafe {
-        // Draw a blue rectangle before jumping to show we're about to call kernel
-        let boot_info_ref = &*(boot_info_addr as *const BootInfo);
-        let fb_addr = boot_info_ref.framebuffer.addr as *mut u32;
-        let fb_width = boot_info_ref.framebuffer.width;
-        
-        for y in 200..250 {
-            for x in 0..200 {
-                let pixel_offset = (y * fb_width + x) as isize;
-                *fb_addr.offset(pixel_offset) = 0x0000FF; // Blue rectangle - "about to jump"
-            }
-        }
-        
-        // Small delay
-        for _ in 0..10000000 {
-            core::arch::asm!("nop");
-        }
-        
-        // Set up stack and jump to kernel
-        core::arch::asm!(
-            "mov rsp, {stack_top}",      // Set up stack pointer
-            "push rbp",                   // Set up frame pointer
-            "mov rbp, rsp",
-            "call {entry_point}",         // Call kernel entry point
-            stack_top = in(reg) stack_top,
-            entry_point = in(reg) entry_point,
-            in("rdi") boot_info_addr,     // Pass boot_info as first parameter
-            options(noreturn)
-        );
-    }
+        (*(boot_info_addr as *mut BootInfo)).log = logger::boot_log();
+        (*(boot_info_addr as *mut BootInfo)).timings.kernel_entry = timing::now();
+        enter_kernel(pml4_addr, entry_point, stack_top, boot_info_addr);
     }
 }
 
-fn setup_graphics(boot_services: &BootServices) -> Result<FramebufferInfo, uefi::Error> {
-    let gop_handle = boot_services
-        .get_handle_for_protocol::<GraphicsOutput>()?;
-    
-    let mut gop = boot_services
-        .open_protocol_exclusive::<GraphicsOutput>(gop_handle)?;
-    
-    // Find best mode (highest resolution with 32-bit color)
-    let mut best_mode = None;
-    let mut best_pixels = 0;
-    
-    for mode_idx in 0..gop.modes(boot_services).count() {
-        if let Ok(mode) = gop.query_mode(mode_idx as u32, boot_services) {
-            let info = mode.info();
-            let pixels = info.resolution().0 * info.resolution().1;
-            
-            if matches!(info.pixel_format(), PixelFormat::Rgb | PixelFormat::Bgr) && pixels > best_pixels {
-                best_mode = Some(mode_idx as u32);
-                best_pixels = pixels;
-            }
-        }
-    }
-    
-    if let Some(mode_idx) = best_mode {
-        if let Ok(mode) = gop.query_mode(mode_idx, boot_services) {
-            gop.set_mode(&mode)?;
-        }
-    }
-    
-    let mode_info = gop.current_mode_info();
-    let mut framebuffer = gop.frame_buffer();
+/// Switches to the kernel's page tables and stack and jumps to its entry
+/// point, following the entry ABI documented in `rustos_bootinfo`.
+///
+/// The kernel starts out as if it had been called with a null return
+/// address: `rsp + 8` is 16-byte aligned, `rbp` is 0 to end backtraces,
+/// `rdi` holds the `BootInfo` address and `rsi` `KERNEL_ENTRY_MAGIC`.
+///
+/// # Safety
+///
+/// Boot services must have been exited. `pml4_addr` must map this code
+/// (identity), `entry_point` and the stack ending at `stack_top`, which has
+/// to be 16-byte aligned.
+unsafe fn enter_kernel(pml4_addr: u64, entry_point: u64, stack_top: u64, boot_info_addr: u64) -> ! {
+    // Nothing may interrupt us halfway between the firmware's world and the kernel's
+    core::arch::asm!("cli", options(nomem, nostack));
+    
+    // Switch to our page tables; the identity map keeps us running
+    paging::enable_protection();
+    paging::load_cr3(pml4_addr);
     
-    let (red_mask, green_mask, blue_mask) = match mode_info.pixel_format() {
-        PixelFormat::Rgb => (0x00_FF_00_00, 0x00_00_FF_00, 0x00_00_00_FF),
-        PixelFormat::Bgr => (0x00_00_00_FF, 0x00_00_FF_00, 0x00_FF_00_00),
-        _ => (0, 0, 0),
-    };
-    
-    Ok(FramebufferInfo {
-        addr: framebuffer.as_mut_ptr() as u64,
-        width: mode_info.resolution().0 as u32,
-        height: mode_info.resolution().1 as u32,
-        pitch: mode_info.stride() as u32 * 4,
-        bpp: 32,
-        red_mask,
-        green_mask,
-        blue_mask,
-    })
+    core::arch::asm!(
+        "mov rsp, {stack_top}",
+        "xor ebp, ebp",
+        "push 0",                         // Null return address
+        "jmp {entry_point}",
+        stack_top = in(reg) stack_top,
+        entry_point = in(reg) entry_point,
+        in("rdi") boot_info_addr,
+        in("rsi") KERNEL_ENTRY_MAGIC,
+        options(noreturn)
+    );
 }
 
-fn load_kernel(boot_services: &BootServices, image: Handle) -> Result<Vec<u8>, uefi::Error> {
+/// Reads the file at `path` (backslash-separated, relative to the root of
+/// the volume the bootloader was loaded from) into memory.
+fn read_file(boot_services: &BootServices, image: Handle, path: &str) -> Result<Vec<u8>, uefi::Error> {
+    let path = CString16::try_from(path).map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+    
     let loaded_image = boot_services.open_protocol_exclusive::<uefi::proto::loaded_image::LoadedImage>(image)?;
     let device_handle = loaded_image.device().unwrap();
     
     let mut fs = boot_services.open_protocol_exclusive::<SimpleFileSystem>(device_handle)?;
     let mut root = fs.open_volume()?;
     
-    let mut kernel_file = root.open(
-        cstr16!("kernel.elf"),
+    let mut file = root.open(
+        &path,
         FileMode::Read,
         FileAttribute::empty(),
-    )?.into_regular_file().unwrap();
+    )?.into_regular_file().ok_or(uefi::Error::from(Status::INVALID_PARAMETER))?;
     
     let mut file_info_buf = [0u8; 512];
-    let file_info = kernel_file.get_info::<uefi::proto::media::file::FileInfo>(&mut file_info_buf)
+    let file_info = file.get_info::<uefi::proto::media::file::FileInfo>(&mut file_info_buf)
         .map_err(|e| e.status())?;
     let file_size = file_info.file_size() as usize;
     
     let mut buffer = vec![0u8; file_size];
-    kernel_file.read(&mut buffer)?;
+    file.read(&mut buffer)?;
     
     Ok(buffer)
 }
 
-fn parse_elf_and_load(elf_data: &[u8], boot_services: &BootServices) -> Result<u64, &'static str> {
-    if elf_data.len() < 64 {
-        return Err("ELF too small");
-    }
-    
-    // Check ELF magic
-    if &elf_data[0..4] != b"\x7fELF" {
-        return Err("Invalid ELF magic");
-    }
-    
-    // Check 64-bit
-    if elf_data[4] != 2 {
-        return Err("Not 64-bit ELF");
-    }
-    
-    // Get entry point (at offset 24 for 64-bit ELF)
-    let original_entry_point = u64::from_le_bytes([
-        elf_data[24], elf_data[25], elf_data[26], elf_data[27],
-        elf_data[28], elf_data[29], elf_data[30], elf_data[31],
-    ]);
-    
-    // Get program header info
-    let ph_offset = u64::from_le_bytes([
-        elf_data[32], elf_data[33], elf_data[34], elf_data[35],
-        elf_data[36], elf_data[37], elf_data[38], elf_data[39],
-    ]) as usize;
-    
-    let ph_entry_size = u16::from_le_bytes([elf_data[54], elf_data[55]]) as usize;
-    let ph_num = u16::from_le_bytes([elf_data[56], elf_data[57]]) as usize;
-    
-    // Calculate the total memory range needed
-    let mut min_addr = u64::MAX;
-    let mut max_addr = 0u64;
-    
-    // First pass: find the memory range
-    for i in 0..ph_num {
-        let ph_start = ph_offset + i * ph_entry_size;
-        if ph_start + 56 > elf_data.len() {
-            continue;
-        }
-        
-        let ph = &elf_data[ph_start..ph_start + 56];
-        let p_type = u32::from_le_bytes([ph[0], ph[1], ph[2], ph[3]]);
-        
-        // PT_LOAD = 1
-        if p_type == 1 {
-            let p_vaddr = u64::from_le_bytes([
-                ph[16], ph[17], ph[18], ph[19], ph[20], ph[21], ph[22], ph[23],
-            ]);
-            
-            let p_memsz = u64::from_le_bytes([
-                ph[40], ph[41], ph[42], ph[43], ph[44], ph[45], ph[46], ph[47],
-            ]);
-            
-            min_addr = min_addr.min(p_vaddr);
-            max_addr = max_addr.max(p_vaddr + p_memsz);
+/// Checks `data`, read from `path`, with `verifier`, loading its signature
+/// from the boot volume if one is needed.
+fn verify_file(
+    boot_services: &BootServices,
+    image: Handle,
+    verifier: &Verifier,
+    path: &str,
+    data: &[u8],
+) -> Result<(), String> {
+    let signature = if verifier.wants_signature() {
+        let signature_path = format!("{}{}", path, SIGNATURE_SUFFIX);
+        match read_file(boot_services, image, &signature_path) {
+            Ok(signature) => Some(signature),
+            Err(e) if e.status() == Status::NOT_FOUND => None,
+            Err(e) => return Err(format!("failed to read {}: {:?}", signature_path, e.status())),
         }
+    } else {
+        None
+    };
+    let verified = verifier.verify(path, data, signature.as_deref()).map_err(|e| format!("{}", e))?;
+    log::info!("{}: {}", path, verified);
+    Ok(())
+}
+
+/// Reads the boot configuration, falling back to the defaults if there is none.
+fn load_config(boot_services: &BootServices, image: Handle) -> Result<BootConfig, String> {
+    match read_file(boot_services, image, CONFIG_PATH) {
+        Ok(data) => BootConfig::parse(&data).map_err(|e| format!("{}", e)),
+        Err(e) if e.status() == Status::NOT_FOUND => Ok(BootConfig::default()),
+        Err(e) => Err(format!("failed to read {}: {:?}", CONFIG_PATH, e.status())),
     }
-    
-    if min_addr == u64::MAX {
-        return Err("No loadable segments found");
+}
+
+/// Copies `bytes` into fresh `LOADER_DATA` pages so they survive
+/// exit_boot_services.
+fn copy_to_loader_data(boot_services: &BootServices, bytes: &[u8]) -> Result<BootStr, uefi::Error> {
+    if bytes.is_empty() {
+        return Ok(BootStr::empty());
     }
-    
-    println!("Total memory range needed: 0x{:x} to 0x{:x}", min_addr, max_addr);
-    
-    // Allocate one large contiguous block for all segments
-    let total_size = max_addr - min_addr;
-    let pages_needed = (total_size + 0xFFF) / 0x1000;
-    
-    let base_addr = match boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::Address(min_addr),
+    let addr = boot_services.allocate_pages(
+        AllocateType::AnyPages,
         MemoryType::LOADER_DATA,
-        pages_needed.try_into().unwrap(),
-    ) {
-        Ok(addr) => {
-            println!("Allocated contiguous block at requested address: 0x{:x}", addr);
-            addr
-        },
-        Err(_) => {
-            // Allocate anywhere and calculate relocation offset
-            let addr = boot_services.allocate_pages(
-                uefi::table::boot::AllocateType::AnyPages,
-                MemoryType::LOADER_DATA,
-                pages_needed.try_into().unwrap(),
-            ).map_err(|_| "Failed to allocate memory for kernel")?;
-            println!("Allocated contiguous block at fallback address: 0x{:x} (requested 0x{:x})", addr, min_addr);
-            addr
-        }
-    };
-    
-    let relocation_offset = base_addr as i64 - min_addr as i64;
-    println!("Relocation offset: 0x{:x}", relocation_offset);
-    
-    // Second pass: load segments into the allocated block
-    for i in 0..ph_num {
-        let ph_start = ph_offset + i * ph_entry_size;
-        if ph_start + 56 > elf_data.len() {
-            continue;
-        }
-        
-        let ph = &elf_data[ph_start..ph_start + 56];
-        let p_type = u32::from_le_bytes([ph[0], ph[1], ph[2], ph[3]]);
-        
-        // PT_LOAD = 1
-        if p_type == 1 {
-            let p_offset = u64::from_le_bytes([
-                ph[8], ph[9], ph[10], ph[11], ph[12], ph[13], ph[14], ph[15],
-            ]) as usize;
-            
-            let p_vaddr = u64::from_le_bytes([
-                ph[16], ph[17], ph[18], ph[19], ph[20], ph[21], ph[22], ph[23],
-            ]);
-            
-            let p_filesz = u64::from_le_bytes([
-                ph[32], ph[33], ph[34], ph[35], ph[36], ph[37], ph[38], ph[39],
-            ]) as usize;
-            
-            let p_memsz = u64::from_le_bytes([
-                ph[40], ph[41], ph[42], ph[43], ph[44], ph[45], ph[46], ph[47],
-            ]) as usize;
-            
-            // Calculate actual load address
-            let load_addr = ((p_vaddr as i64 + relocation_offset) as u64) as *mut u8;
-            
-            println!("Loading segment: vaddr=0x{:x} -> load_addr=0x{:x}, size=0x{:x}", 
-                     p_vaddr, load_addr as u64, p_memsz);
-            
-            // Copy segment data
-            unsafe {
-                if p_filesz > 0 && p_offset + p_filesz <= elf_data.len() {
-                    core::ptr::copy_nonoverlapping(
-                        elf_data.as_ptr().add(p_offset),
-                        load_addr,
-                        p_filesz,
-                    );
-                }
-                // Zero remaining bytes
-                if p_memsz > p_filesz {
-                    core::ptr::write_bytes(load_addr.add(p_filesz), 0, p_memsz - p_filesz);
-                }
-            }
-        }
+        bytes.len().div_ceil(PAGE_SIZE as usize),
+    )?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, bytes.len());
     }
-    
-    // Calculate the relocated entry point
-    let relocated_entry_point = (original_entry_point as i64 + relocation_offset) as u64;
-    println!("Original entry point: 0x{:x}, relocated to: 0x{:x}", 
-             original_entry_point, relocated_entry_point);
-    
-    Ok(relocated_entry_point)
+    Ok(BootStr {
+        ptr: addr as *const u8,
+        len: bytes.len() as u64,
+    })
 }
 
-fn setup_identity_mapping(boot_services: &BootServices) -> Result<(), &'static str> {
-    // Allocate pages for page tables
-    let pml4_addr = boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1,
-    ).map_err(|_| "Failed to allocate PML4")?;
-    
-    let pdpt_addr = boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1,
-    ).map_err(|_| "Failed to allocate PDPT")?;
-    
-    // Allocate more PD pages to cover more memory
-    let pd0_addr = boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1,
-    ).map_err(|_| "Failed to allocate PD0")?;
-    
-    let pd1_addr = boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1,
-    ).map_err(|_| "Failed to allocate PD1")?;
+/// Loads every module in `paths` into its own page-aligned `LOADER_DATA`
+/// allocation and builds the module table for `BootInfo`. Fails if any of
+/// them doesn't pass `verifier`.
+fn load_modules(
+    boot_services: &BootServices,
+    image: Handle,
+    verifier: &Verifier,
+    paths: &[String],
+) -> Result<BootModules, String> {
+    if paths.is_empty() {
+        return Ok(BootModules::empty());
+    }
     
-    let pd2_addr = boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        1,
-    ).map_err(|_| "Failed to allocate PD2")?;
+    let mut modules = Vec::with_capacity(paths.len());
+    for path in paths {
+        let data = read_file(boot_services, image, path)
+            .map_err(|e| format!("failed to read {}: {:?}", path, e.status()))?;
+        verify_file(boot_services, image, verifier, path, &data)?;
+        let contents = copy_to_loader_data(boot_services, &data)
+            .map_err(|e| format!("failed to allocate {} bytes for {}: {:?}", data.len(), path, e.status()))?;
+        let name = copy_to_loader_data(boot_services, module_name(path).as_bytes())
+            .map_err(|e| format!("failed to allocate module name: {:?}", e.status()))?;
+        log::info!("Module {} at 0x{:x} ({} bytes)", path, contents.ptr as u64, contents.len);
+        modules.push(BootModule {
+            name,
+            phys_addr: contents.ptr as u64,
+            len: contents.len,
+        });
+    }
     
-    let pd3_addr = boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
+    let table_addr = boot_services.allocate_pages(
+        AllocateType::AnyPages,
         MemoryType::LOADER_DATA,
-        1,
-    ).map_err(|_| "Failed to allocate PD3")?;
-    
+        (modules.len() * mem::size_of::<BootModule>()).div_ceil(PAGE_SIZE as usize),
+    ).map_err(|e| format!("failed to allocate module table: {:?}", e.status()))?;
     unsafe {
-        // Clear all page tables
-        core::ptr::write_bytes(pml4_addr as *mut u8, 0, 0x1000);
-        core::ptr::write_bytes(pdpt_addr as *mut u8, 0, 0x1000);
-        core::ptr::write_bytes(pd0_addr as *mut u8, 0, 0x1000);
-        core::ptr::write_bytes(pd1_addr as *mut u8, 0, 0x1000);
-        core::ptr::write_bytes(pd2_addr as *mut u8, 0, 0x1000);
-        core::ptr::write_bytes(pd3_addr as *mut u8, 0, 0x1000);
-        
-        let pml4 = &mut *(pml4_addr as *mut PageTable);
-        let pdpt = &mut *(pdpt_addr as *mut PageTable);
-        let pd0 = &mut *(pd0_addr as *mut PageTable);
-        let pd1 = &mut *(pd1_addr as *mut PageTable);
-        let pd2 = &mut *(pd2_addr as *mut PageTable);
-        let pd3 = &mut *(pd3_addr as *mut PageTable);
-        
-        // Set up PML4[0] -> PDPT
-        pml4.set_entry(0, pdpt_addr, PAGE_PRESENT | PAGE_WRITABLE);
-        
-        // Set up PDPT entries to cover first 4GB
-        pdpt.set_entry(0, pd0_addr, PAGE_PRESENT | PAGE_WRITABLE); // 0-1GB
-        pdpt.set_entry(1, pd1_addr, PAGE_PRESENT | PAGE_WRITABLE); // 1-2GB
-        pdpt.set_entry(2, pd2_addr, PAGE_PRESENT | PAGE_WRITABLE); // 2-3GB
-        pdpt.set_entry(3, pd3_addr, PAGE_PRESENT | PAGE_WRITABLE); // 3-4GB
-        
-        // Set up PD entries for first 4GB (4 * 512 * 2MB pages)
-        let mut pds = [pd0, pd1, pd2, pd3];
-        for (pdpt_entry, pd) in pds.iter_mut().enumerate() {
-            for pd_entry in 0..512 {
-                let addr = (pdpt_entry as u64 * 0x4000_0000) + (pd_entry as u64 * 0x20_0000); // 2MB pages
-                pd.set_entry(pd_entry, addr, PAGE_PRESENT | PAGE_WRITABLE | PAGE_HUGE);
-            }
-        }
-        
-        // Load CR3 register
-        load_cr3(pml4_addr);
+        core::ptr::copy_nonoverlapping(modules.as_ptr(), table_addr as *mut BootModule, modules.len());
     }
     
-    Ok(())
+    Ok(BootModules {
+        modules: table_addr as *const BootModule,
+        count: modules.len() as u64,
+    })
 }
 
-#[inline(always)]
-unsafe fn load_cr3(pml4_addr: u64) {
-    core::arch::asm!(
-        "mov cr3, {}",
-        in(reg) pml4_addr,
-        options(nostack, preserves_flags)
-    );
+/// `SegmentAllocator` backed by UEFI boot services page allocation.
+struct BootServicesAllocator<'a> {
+    boot_services: &'a BootServices,
+    /// Picks a random spot for `Placement::Anywhere` when set (KASLR).
+    random: Option<u64>,
 }
 
-fn get_memory_map(boot_services: &BootServices) -> Result<MemoryMapInfo, uefi::Error> {
-    let map_size = boot_services.memory_map_size().map_size + 8 * mem::size_of::<MemoryDescriptor>();
-    
-    let map_addr = boot_services.allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        (map_size + 0xFFF) / 0x1000,
-    )?;
-    
-    let map_buffer = unsafe {
-        slice::from_raw_parts_mut(map_addr as *mut u8, map_size)
+impl SegmentAllocator for BootServicesAllocator<'_> {
+    fn allocate(&mut self, placement: Placement, pages: u64) -> Option<u64> {
+        let allocate_type = match placement {
+            Placement::At(addr) => AllocateType::Address(addr),
+            Placement::Anywhere => {
+                if let Some(random) = self.random {
+                    let addr = kaslr::allocate_random(self.boot_services, MemoryType::LOADER_DATA, pages, random);
+                    if addr.is_some() {
+                        return addr;
+                    }
+                    log::warn!("No free memory at a random address for {} pages, taking any", pages);
+                }
+                AllocateType::AnyPages
+            }
+        };
+        self.boot_services
+            .allocate_pages(allocate_type, MemoryType::LOADER_DATA, pages as usize)
+            .ok()
+    }
+
+    fn memory(&mut self, phys_addr: u64, len: usize) -> &mut [u8] {
+        // Boot services run identity mapped, and the loader only asks for
+        // memory it got from allocate()
+        unsafe { slice::from_raw_parts_mut(phys_addr as *mut u8, len) }
+    }
+}
+
+/// Loads the kernel ELF in `elf_data`, at random addresses if `kaslr` is set.
+fn parse_elf_and_load(elf_data: &[u8], boot_services: &BootServices, kaslr: bool) -> Result<LoadedImage, ElfError> {
+    let elf = ElfFile::parse(elf_data)?;
+    let (min_addr, max_addr) = elf.image_range();
+    log::debug!("Total memory range needed: 0x{:x} to 0x{:x}", min_addr, max_addr);
+    
+    // The low half of the random bits picks the virtual slide and the high
+    // half the physical address
+    let random = if kaslr {
+        let (random, source) = kaslr::random_u64(boot_services);
+        log::debug!("KASLR entropy from {}", source);
+        if !elf.can_slide() {
+            log::info!("Kernel is not a position independent higher-half image, only randomising its physical address");
+        }
+        Some(random)
+    } else {
+        log::info!("KASLR disabled");
+        None
+    };
+    let slide = match random {
+        Some(random) if elf.can_slide() => rustos_elfloader::kaslr::virtual_slide(random & 0xffff_ffff, max_addr),
+        _ => 0,
     };
     
-    let _memory_map = boot_services.memory_map(map_buffer)?;
+    let mut allocator = BootServicesAllocator {
+        boot_services,
+        random: random.map(|random| random >> 32),
+    };
+    let image = rustos_elfloader::load_with_slide(&elf, &mut allocator, slide)?;
+    if random.is_some() {
+        log::info!("KASLR: kernel at 0x{:x} (slide 0x{:x}), physical 0x{:x}", image.virt_base, slide, image.phys_base);
+    }
+    if !image.is_identity_mapped() {
+        log::debug!("Higher-half kernel at 0x{:x} placed at physical 0x{:x}", image.virt_base, image.phys_base);
+    } else if image.relocation_offset == 0 {
+        log::debug!("Allocated contiguous block at requested address: 0x{:x}", image.phys_base);
+    } else {
+        log::debug!("Position-independent kernel relocated to 0x{:x} (linked at 0x{:x})", image.phys_base, min_addr);
+    }
+    log::debug!("Relocation offset: 0x{:x}", image.relocation_offset);
     
-    // Don't count entries - just estimate based on size
-    let entry_size = mem::size_of::<MemoryDescriptor>();
-    let estimated_entry_count = map_size / entry_size;
+    for segment in &image.segments {
+        log::debug!("Loaded segment {}: vaddr=0x{:x} -> load_addr=0x{:x}, size=0x{:x}", 
+                 segment.index, segment.vaddr.wrapping_add(image.relocation_offset), image.phys_addr(segment.vaddr), segment.memsz);
+    }
+    log::debug!("Original entry point: 0x{:x}, relocated to: 0x{:x}", 
+             elf.entry(), image.entry);
     
-    Ok(MemoryMapInfo {
-        entries: map_addr as *const MemoryDescriptor,
-        entry_count: estimated_entry_count,
-        entry_size,
-    })
+    Ok(image)
 }
 
-fn find_rsdp(system_table: &mut SystemTable<Boot>) -> Option<u64> {
-    for entry in system_table.config_table() {
-        if entry.guid == uefi::table::cfg::ACPI2_GUID {
-            return Some(entry.address as u64);
-        }
-        if entry.guid == uefi::table::cfg::ACPI_GUID {
-            return Some(entry.address as u64);
+/// Builds page tables with an identity map and a direct map of the first
+/// `physical_memory_size` bytes, plus the kernel's segments at their
+/// virtual addresses and the stack at `stack.bottom`, backed by
+/// `stack_phys`. UEFI runtime services regions are mapped once more at
+/// `runtime_offset` plus their physical address. Returns the physical
+/// address of the PML4.
+///
+/// Kernel pages get their permissions from the segment flags, and there is
+/// no writable alias of them: the identity map leaves a hole over the
+/// kernel's physical frames, and the direct map has them read-only and NX.
+/// Nothing is mapped at the stack's guard page, so an overflow faults
+/// instead of running into other memory.
+fn setup_page_tables(
+    boot_services: &BootServices,
+    kernel: &LoadedImage,
+    stack: &KernelStackInfo,
+    stack_phys: u64,
+    physical_memory_offset: u64,
+    physical_memory_size: u64,
+    runtime_offset: u64,
+) -> Result<u64, &'static str> {
+    let mut builder = PageTableBuilder::new(boot_services)?;
+    
+    // Identity map so the bootloader keeps running after the CR3 switch
+    let kernel_start = kernel.phys_base.min(physical_memory_size);
+    let kernel_end = (kernel.phys_base + kernel.pages * PAGE_SIZE).min(physical_memory_size);
+    builder.map_range(0, 0, kernel_start, PAGE_WRITABLE)?;
+    builder.map_range(kernel_end, kernel_end, physical_memory_size - kernel_end, PAGE_WRITABLE)?;
+    
+    // Direct map of physical memory for the kernel; data only, and the
+    // kernel's own frames can be read but not written through it
+    builder.map_range(physical_memory_offset, 0, kernel_start, PAGE_WRITABLE | PAGE_NO_EXECUTE)?;
+    builder.map_range(
+        physical_memory_offset + kernel_start,
+        kernel_start,
+        kernel_end - kernel_start,
+        PAGE_NO_EXECUTE,
+    )?;
+    builder.map_range(
+        physical_memory_offset + kernel_end,
+        kernel_end,
+        physical_memory_size - kernel_end,
+        PAGE_WRITABLE | PAGE_NO_EXECUTE,
+    )?;
+    
+    // Kernel segments at their link-time addresses, backed by wherever they
+    // were loaded. 4 KiB pages so neighbouring segments keep their own flags.
+    for segment in &kernel.segments {
+        let flags = segment_page_flags(segment);
+        let start = segment.vaddr & !(PAGE_SIZE - 1);
+        let end = (segment.end() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            builder.map_4k(page.wrapping_add(kernel.relocation_offset), kernel.phys_addr(page), flags)?;
         }
     }
-    None
+    
+    builder.map_range(stack.bottom, stack_phys, stack.size(), PAGE_WRITABLE | PAGE_NO_EXECUTE)?;
+    
+    // Runtime services regions don't change at exit_boot_services, so the
+    // current map is good enough. Their code pages may hold data too.
+    let sizes = boot_services.memory_map_size();
+    let mut buffer = vec![0u8; sizes.map_size + 8 * sizes.entry_size];
+    let memory_map = boot_services.memory_map(&mut buffer).map_err(|_| "Failed to get memory map")?;
+    for descriptor in memory_map.entries().filter(|d| d.att.contains(MemoryAttribute::RUNTIME)) {
+        let flags = if descriptor.ty == MemoryType::RUNTIME_SERVICES_CODE {
+            PAGE_WRITABLE
+        } else {
+            PAGE_WRITABLE | PAGE_NO_EXECUTE
+        };
+        builder.map_range(
+            runtime_offset + descriptor.phys_start,
+            descriptor.phys_start,
+            descriptor.page_count * PAGE_SIZE,
+            flags,
+        )?;
+    }
+    
+    Ok(builder.pml4_addr())
 }
 
-fn print_hex(system_table: &mut SystemTable<Boot>, value: u64) {
-    let hex_chars = b"0123456789ABCDEF";
-    for i in (0..16).rev() {
-        let digit = ((value >> (i * 4)) & 0xF) as usize;
-        let ch = hex_chars[digit] as char;
-        system_table.stdout().write_char(ch).unwrap();
+/// Page flags for a kernel segment: text is read-only and executable, rodata
+/// read-only and NX, data and bss writable and NX.
+fn segment_page_flags(segment: &Segment) -> u64 {
+    let mut flags = 0;
+    if segment.flags & PF_W != 0 {
+        flags |= PAGE_WRITABLE;
+    }
+    if segment.flags & PF_X == 0 {
+        flags |= PAGE_NO_EXECUTE;
     }
+    flags
 }
 
-fn print_decimal(system_table: &mut SystemTable<Boot>, mut value: u64) {
-    if value == 0 {
-        system_table.stdout().write_char('0').unwrap();
-        return;
+/// End of the physical address space the kernel needs mapped: the highest
+/// memory map entry or framebuffer byte, at least 4 GiB, rounded up to 1 GiB.
+fn physical_memory_end(boot_services: &BootServices, framebuffer: &FramebufferInfo) -> Result<u64, uefi::Error> {
+    let sizes = boot_services.memory_map_size();
+    let mut buffer = vec![0u8; sizes.map_size + 8 * sizes.entry_size];
+    let memory_map = boot_services.memory_map(&mut buffer)?;
+    
+    let mut end = MIN_MAPPED_PHYSICAL_MEMORY;
+    for descriptor in memory_map.entries() {
+        end = end.max(descriptor.phys_start + descriptor.page_count * PAGE_SIZE);
     }
+    end = end.max(framebuffer.addr + framebuffer.pitch as u64 * framebuffer.height as u64);
     
-    let mut digits = [0u8; 20];
-    let mut count = 0;
-    
-    while value > 0 {
-        digits[count] = (value % 10) as u8 + b'0';
-        value /= 10;
-        count += 1;
+    Ok((end + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1))
+}
+
+/// Describes the final memory map in place. `entry_size` is the firmware's
+/// descriptor stride, which can be larger than `MemoryDescriptor`.
+fn memory_map_info(memory_map: &MemoryMap<'static>, entry_size: usize) -> MemoryMapInfo {
+    let entries = memory_map
+        .entries()
+        .next()
+        .map_or(core::ptr::null(), |first| first as *const MemoryDescriptor);
+    
+    MemoryMapInfo {
+        entries: entries as *const rustos_bootinfo::MemoryDescriptor,
+        entry_count: memory_map.entries().count() as u64,
+        entry_size: entry_size as u64,
     }
-    
-    for i in (0..count).rev() {
-        system_table.stdout().write_char(digits[i] as char).unwrap();
+}
+
+/// Converts the final memory map into the compact region list at
+/// `regions_addr`, marking the kernel image, framebuffer and modules. Runs after
+/// exit_boot_services, so on overflow the kernel just gets no region list.
+unsafe fn memory_regions(boot_info: &BootInfo, regions_addr: u64, capacity: usize) -> MemoryRegions {
+    let framebuffer = &boot_info.framebuffer;
+    let framebuffer_end = framebuffer.addr + framebuffer.pitch as u64 * framebuffer.height as u64;
+    let overrides = [
+        MemoryRegion {
+            start: boot_info.kernel.phys_base,
+            end: boot_info.kernel.phys_base + boot_info.kernel.size,
+            kind: MemoryRegionKind::KernelImage,
+        },
+        MemoryRegion {
+            start: framebuffer.addr & !(PAGE_SIZE - 1),
+            end: (framebuffer_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+            kind: MemoryRegionKind::Framebuffer,
+        },
+    ];
+    
+    let modules = boot_info.modules.as_slice().iter().map(|module| MemoryRegion {
+        start: module.phys_addr,
+        end: module.phys_addr + ((module.len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)),
+        kind: MemoryRegionKind::Module,
+    });
+    
+    let out = slice::from_raw_parts_mut(regions_addr as *mut MemoryRegion, capacity);
+    match build_memory_regions(boot_info.memory_map.iter(), overrides.into_iter().chain(modules), out) {
+        Some(count) => MemoryRegions {
+            regions: out.as_ptr(),
+            count: count as u64,
+        },
+        None => MemoryRegions::empty(),
     }
 }
@@ -0,0 +1,140 @@
+//! Boot menu on the UEFI text console.
+//!
+//! Lists the entries from the boot configuration with a countdown to the
+//! default one. Up/Down move the selection, Enter boots it and `e` edits its
+//! kernel command line for this boot only. Any key stops the countdown.
+
+use alloc::string::String;
+use core::fmt::Write;
+use rustos_bootconfig::{BootConfig, BootEntry};
+use uefi::prelude::*;
+use uefi::proto::console::text::{Color, Key, ScanCode};
+
+/// How often the keyboard is polled while the countdown runs.
+const POLL_INTERVAL_US: usize = 50_000;
+const TICKS_PER_SECOND: u64 = 1_000_000 / POLL_INTERVAL_US as u64;
+
+const BACKSPACE: char = '\u{8}';
+const ENTER: char = '\r';
+
+/// Lets the user pick an entry and returns it, including any edits. With
+/// `autoboot`, the default entry is booted when the timeout runs out, or
+/// straight away if it is 0; otherwise the menu waits for the user.
+pub fn run(system_table: &mut SystemTable<Boot>, config: &BootConfig, autoboot: bool) -> BootEntry {
+    let mut entries = config.entries.clone();
+    let mut selected = config.default_entry;
+    if autoboot && config.timeout == 0 {
+        return entries.swap_remove(selected);
+    }
+
+    let _ = system_table.stdin().reset(false);
+    let _ = system_table.stdout().enable_cursor(false);
+    let mut remaining_ticks = autoboot.then_some(config.timeout * TICKS_PER_SECOND);
+
+    loop {
+        draw(system_table, &entries, selected, remaining_ticks.map(|ticks| ticks.div_ceil(TICKS_PER_SECOND)));
+
+        let key = match wait_for_key(system_table, &mut remaining_ticks) {
+            Some(key) => key,
+            // Timed out
+            None => break,
+        };
+        match key {
+            Key::Special(ScanCode::UP) => selected = selected.checked_sub(1).unwrap_or(entries.len() - 1),
+            Key::Special(ScanCode::DOWN) => selected = (selected + 1) % entries.len(),
+            Key::Printable(c) if char::from(c) == ENTER => break,
+            Key::Printable(c) if char::from(c) == 'e' => {
+                if let Some(cmdline) = edit_line(system_table, &entries[selected].cmdline) {
+                    entries[selected].cmdline = cmdline;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let _ = system_table.stdout().clear();
+    let _ = system_table.stdout().enable_cursor(true);
+    entries.swap_remove(selected)
+}
+
+/// Waits for a key press while counting `remaining_ticks` down; returns
+/// `None` once it runs out. The countdown stops for good at the first key.
+fn wait_for_key(system_table: &mut SystemTable<Boot>, remaining_ticks: &mut Option<u64>) -> Option<Key> {
+    let start = *remaining_ticks;
+    loop {
+        if let Ok(Some(key)) = system_table.stdin().read_key() {
+            *remaining_ticks = None;
+            return Some(key);
+        }
+        if let Some(ticks) = remaining_ticks {
+            if *ticks == 0 {
+                return None;
+            }
+            *ticks -= 1;
+            // Redraw when the displayed number of seconds changes
+            if start.map(|start| start.div_ceil(TICKS_PER_SECOND)) != Some(ticks.div_ceil(TICKS_PER_SECOND)) {
+                return Some(Key::Special(ScanCode::NULL));
+            }
+        }
+        system_table.boot_services().stall(POLL_INTERVAL_US);
+    }
+}
+
+fn draw(system_table: &mut SystemTable<Boot>, entries: &[BootEntry], selected: usize, seconds_left: Option<u64>) {
+    let stdout = system_table.stdout();
+    let _ = stdout.clear();
+    let _ = writeln!(stdout, "RustOS boot menu\n");
+
+    for (index, entry) in entries.iter().enumerate() {
+        if index == selected {
+            let _ = stdout.set_color(Color::Black, Color::LightGray);
+            let _ = write!(stdout, "  > {}  ", entry.name);
+            let _ = stdout.set_color(Color::LightGray, Color::Black);
+            let _ = writeln!(stdout);
+        } else {
+            let _ = writeln!(stdout, "    {}", entry.name);
+        }
+    }
+
+    let entry = &entries[selected];
+    let _ = writeln!(stdout, "\n  kernel:  {}", entry.kernel_path);
+    let _ = writeln!(stdout, "  cmdline: {}", entry.cmdline);
+    let _ = writeln!(stdout, "\nUp/Down to select, Enter to boot, 'e' to edit the command line");
+    if let Some(seconds) = seconds_left {
+        let _ = writeln!(stdout, "Booting \"{}\" in {}s", entry.name, seconds);
+    }
+}
+
+/// Single-line editor for the command line. Returns `None` if the user
+/// pressed Escape.
+fn edit_line(system_table: &mut SystemTable<Boot>, initial: &str) -> Option<String> {
+    let mut line = String::from(initial);
+    let _ = system_table.stdout().enable_cursor(true);
+    let result = loop {
+        let stdout = system_table.stdout();
+        let _ = stdout.clear();
+        let _ = writeln!(stdout, "Edit the kernel command line, Enter to accept, Escape to cancel\n");
+        let _ = write!(stdout, "> {}", line);
+
+        let key = loop {
+            if let Ok(Some(key)) = system_table.stdin().read_key() {
+                break key;
+            }
+            system_table.boot_services().stall(POLL_INTERVAL_US);
+        };
+        match key {
+            Key::Special(ScanCode::ESCAPE) => break None,
+            Key::Printable(c) => match char::from(c) {
+                ENTER => break Some(line),
+                BACKSPACE => {
+                    line.pop();
+                }
+                c if !c.is_control() => line.push(c),
+                _ => {}
+            },
+            _ => {}
+        }
+    };
+    let _ = system_table.stdout().enable_cursor(false);
+    result
+}
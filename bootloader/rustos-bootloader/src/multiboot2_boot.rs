@@ -0,0 +1,279 @@
+//! Booting Multiboot2 kernels.
+//!
+//! The image and modules are loaded below 4 GiB while boot services are up.
+//! The MBI is written after `exit_boot_services` so it can carry the final
+//! memory map. A trampoline copied below 4 GiB then leaves long mode and
+//! enters the kernel in 32-bit protected mode with paging off, `eax` holding
+//! [`BOOTLOADER_MAGIC`] and `ebx` the MBI address.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use rustos_bootconfig::verify::Verifier;
+use rustos_bootconfig::{BootConfig, BootEntry, ResolutionPolicy};
+use rustos_bootinfo::FramebufferInfo;
+use rustos_multiboot2::{Header, InfoBuilder, LoadPlan, BOOTLOADER_MAGIC};
+use uefi::prelude::*;
+use uefi::table::boot::{AllocateType, MemoryType};
+
+use crate::paging::PAGE_SIZE;
+use crate::{firmware, graphics, logger};
+
+const BOOT_LOADER_NAME: &str = "RustOS Bootloader";
+/// Highest address anything handed to the kernel may end at.
+const MAX_ADDRESS: u64 = 0xffff_ffff;
+/// Room for the MBI's fixed-size tags, on top of the memory maps, command
+/// line and module strings.
+const MBI_SLACK: usize = 4096;
+/// Descriptors the firmware may add between sizing the map and
+/// exit_boot_services.
+const MEMORY_MAP_SLACK: usize = 16;
+
+// Trampoline GDT selectors and the page layout
+const GDT_CODE32: u16 = 0x08;
+const GDT_DATA32: u16 = 0x10;
+const TRAMPOLINE_GDT_OFFSET: usize = 0x800;
+const TRAMPOLINE_GDTR_OFFSET: usize = TRAMPOLINE_GDT_OFFSET + 0x20;
+/// Null, flat 32-bit code, flat 32-bit data.
+const TRAMPOLINE_GDT: [u64; 3] = [0, 0x00cf_9a00_0000_ffff, 0x00cf_9200_0000_ffff];
+
+// Entered through a copy below 4 GiB with the firmware's identity-mapped
+// page tables still active: rdi = entry point, rsi = MBI, rdx = GDTR. Only
+// uses the stack for the far return, while still in long mode.
+core::arch::global_asm!(
+    ".global multiboot2_trampoline_start",
+    ".global multiboot2_trampoline_end",
+    ".code64",
+    "multiboot2_trampoline_start:",
+    "cli",
+    // PCIDE must be off before paging can be
+    "mov rax, cr4",
+    "btr rax, 17",
+    "mov cr4, rax",
+    "lgdt [rdx]",
+    "push {code32}",
+    "lea rax, [rip + 2f]",
+    "push rax",
+    "retfq",
+    ".code32",
+    "2:",
+    "mov ax, {data32}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    // Paging off deactivates long mode; then clear LME and PAE
+    "mov eax, cr0",
+    "btr eax, 31",
+    "mov cr0, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "btr eax, 8",
+    "wrmsr",
+    "mov eax, cr4",
+    "btr eax, 5",
+    "mov cr4, eax",
+    "mov eax, {magic}",
+    "mov ebx, esi",
+    "jmp edi",
+    ".code64",
+    "multiboot2_trampoline_end:",
+    code32 = const GDT_CODE32,
+    data32 = const GDT_DATA32,
+    magic = const BOOTLOADER_MAGIC,
+);
+
+extern "C" {
+    static multiboot2_trampoline_start: u8;
+    static multiboot2_trampoline_end: u8;
+}
+
+/// A module loaded below 4 GiB.
+struct Module {
+    start: u32,
+    end: u32,
+    path: String,
+}
+
+/// Boots the Multiboot2 kernel in `data`. Only returns if something goes
+/// wrong before boot services are exited.
+pub fn boot(
+    image: Handle,
+    system_table: SystemTable<Boot>,
+    config: &BootConfig,
+    verifier: &Verifier,
+    entry: &BootEntry,
+    data: &[u8],
+    header: &Header,
+) -> Status {
+    match prepare(image, &system_table, config, verifier, entry, data, header) {
+        Ok(prepared) => unsafe { enter(system_table, entry, prepared) },
+        Err(e) => {
+            log::error!("Failed to load Multiboot2 kernel {}: {}", entry.kernel_path, e);
+            system_table.boot_services().stall(10_000_000);
+            Status::LOAD_ERROR
+        }
+    }
+}
+
+/// Everything set up before exit_boot_services.
+struct Prepared {
+    plan: LoadPlan,
+    framebuffer: FramebufferInfo,
+    modules: Vec<Module>,
+    rsdp_addr: Option<u64>,
+    mbi_addr: u64,
+    mbi_capacity: usize,
+    trampoline_addr: u64,
+    memory_map_entry_size: usize,
+}
+
+fn prepare(
+    image: Handle,
+    system_table: &SystemTable<Boot>,
+    config: &BootConfig,
+    verifier: &Verifier,
+    entry: &BootEntry,
+    data: &[u8],
+    header: &Header,
+) -> Result<Prepared, String> {
+    let boot_services = system_table.boot_services();
+    let plan = LoadPlan::new(data, header).map_err(|e| format!("{}", e))?;
+    log::info!("Multiboot2 kernel, entry point 0x{:x}", plan.entry);
+
+    // A framebuffer tag asks for a mode; depth is whatever the firmware has
+    let policy = match header.framebuffer {
+        Some(request) if request.width != 0 && request.height != 0 => {
+            ResolutionPolicy::Exact(request.width as usize, request.height as usize)
+        }
+        _ => config.resolution,
+    };
+    let framebuffer = graphics::setup_graphics(boot_services, policy)
+        .map_err(|e| format!("failed to set up graphics: {:?}", e.status()))?;
+
+    for (start, end) in plan.page_ranges(PAGE_SIZE) {
+        boot_services
+            .allocate_pages(AllocateType::Address(start), MemoryType::LOADER_DATA, ((end - start) / PAGE_SIZE) as usize)
+            .map_err(|e| format!("0x{:x}-0x{:x} is not free: {:?}", start, end, e.status()))?;
+        unsafe { ptr::write_bytes(start as *mut u8, 0, (end - start) as usize) };
+    }
+    for segment in &plan.segments {
+        log::debug!(
+            "Segment at 0x{:x}: 0x{:x} bytes from file offset 0x{:x}, 0x{:x} in memory",
+            segment.phys_addr, segment.filesz, segment.file_offset, segment.memsz
+        );
+        let file = &data[segment.file_offset..segment.file_offset + segment.filesz];
+        unsafe { ptr::copy_nonoverlapping(file.as_ptr(), segment.phys_addr as *mut u8, file.len()) };
+    }
+
+    let mut modules = Vec::with_capacity(entry.modules.len());
+    for path in &entry.modules {
+        let contents = crate::read_file(boot_services, image, path)
+            .map_err(|e| format!("failed to read {}: {:?}", path, e.status()))?;
+        crate::verify_file(boot_services, image, verifier, path, &contents)?;
+        let start = allocate_low(boot_services, MemoryType::LOADER_DATA, contents.len().max(1))?;
+        unsafe { ptr::copy_nonoverlapping(contents.as_ptr(), start as *mut u8, contents.len()) };
+        log::info!("Module {} at 0x{:x} ({} bytes)", path, start, contents.len());
+        modules.push(Module {
+            start: start as u32,
+            end: (start + contents.len() as u64) as u32,
+            path: path.clone(),
+        });
+    }
+
+    // Sized for the final map: the EFI copy and the Multiboot2 one, which
+    // has smaller entries
+    let sizes = boot_services.memory_map_size();
+    let map_size = sizes.map_size + MEMORY_MAP_SLACK * sizes.entry_size;
+    let strings: usize = modules.iter().map(|module| module.path.len() + 32).sum();
+    let mbi_capacity = 2 * map_size + entry.cmdline.len() + strings + MBI_SLACK;
+    let mbi_addr = allocate_low(boot_services, MemoryType::LOADER_DATA, mbi_capacity)?;
+
+    // Code pages, so firmware that maps data NX still lets it run
+    let trampoline_addr = allocate_low(boot_services, MemoryType::LOADER_CODE, PAGE_SIZE as usize)?;
+    unsafe { install_trampoline(trampoline_addr) };
+
+    let (rsdp_addr, _) = firmware::find_tables(system_table);
+
+    Ok(Prepared {
+        plan,
+        framebuffer,
+        modules,
+        rsdp_addr,
+        mbi_addr,
+        mbi_capacity,
+        trampoline_addr,
+        memory_map_entry_size: sizes.entry_size,
+    })
+}
+
+/// Allocates at least `len` bytes of page-aligned memory below 4 GiB.
+fn allocate_low(boot_services: &BootServices, memory_type: MemoryType, len: usize) -> Result<u64, String> {
+    boot_services
+        .allocate_pages(AllocateType::MaxAddress(MAX_ADDRESS), memory_type, len.div_ceil(PAGE_SIZE as usize))
+        .map_err(|e| format!("failed to allocate {} bytes below 4 GiB: {:?}", len, e.status()))
+}
+
+/// Copies the trampoline to the page at `addr` and sets up its GDT and GDTR.
+unsafe fn install_trampoline(addr: u64) {
+    let start = ptr::addr_of!(multiboot2_trampoline_start);
+    let len = ptr::addr_of!(multiboot2_trampoline_end) as usize - start as usize;
+    assert!(len <= TRAMPOLINE_GDT_OFFSET, "Multiboot2 trampoline overlaps its GDT");
+    ptr::copy_nonoverlapping(start, addr as *mut u8, len);
+
+    let gdt = addr + TRAMPOLINE_GDT_OFFSET as u64;
+    ptr::copy_nonoverlapping(TRAMPOLINE_GDT.as_ptr(), gdt as *mut u64, TRAMPOLINE_GDT.len());
+    let gdtr = (addr + TRAMPOLINE_GDTR_OFFSET as u64) as *mut u8;
+    let limit = (mem::size_of_val(&TRAMPOLINE_GDT) - 1) as u16;
+    ptr::write_unaligned(gdtr as *mut u16, limit);
+    ptr::write_unaligned(gdtr.add(2) as *mut u64, gdt);
+}
+
+/// Exits boot services, writes the MBI and jumps to the kernel.
+///
+/// # Safety
+///
+/// `prepared` must come from [`prepare`] with the same system table.
+unsafe fn enter(system_table: SystemTable<Boot>, entry: &BootEntry, prepared: Prepared) -> ! {
+    log::info!("Exiting boot services...");
+    logger::exit_boot_services();
+    let (runtime_system_table, memory_map) = system_table.exit_boot_services(MemoryType::LOADER_DATA);
+
+    // Nothing may be allocated or freed from here on
+    let map = crate::memory_map_info(&memory_map, prepared.memory_map_entry_size);
+    let raw_map = slice::from_raw_parts(map.entries as *const u8, (map.entry_count * map.entry_size) as usize);
+    let mbi = slice::from_raw_parts_mut(prepared.mbi_addr as *mut u8, prepared.mbi_capacity);
+
+    let mut info = InfoBuilder::new(mbi);
+    info.command_line(&entry.cmdline);
+    info.boot_loader_name(BOOT_LOADER_NAME);
+    for module in &prepared.modules {
+        info.module(module.start, module.end, &module.path);
+    }
+    info.basic_memory_info(map.iter());
+    info.memory_map(map.iter());
+    info.efi_memory_map(raw_map, map.entry_size as usize);
+    info.framebuffer(&prepared.framebuffer);
+    info.efi64_system_table(runtime_system_table.as_ptr() as u64);
+    if let Some(rsdp_addr) = prepared.rsdp_addr {
+        info.acpi_rsdp(firmware::rsdp_bytes(rsdp_addr));
+    }
+    info.load_base_addr(prepared.plan.load_base() as u32);
+    if info.dropped() > 0 {
+        log::warn!("{} boot information tags didn't fit and were dropped", info.dropped());
+    }
+    let mbi_size = info.finish();
+
+    log::info!(
+        "Entering Multiboot2 kernel at 0x{:x}, MBI at 0x{:x} ({} bytes)",
+        prepared.plan.entry, prepared.mbi_addr, mbi_size
+    );
+    let trampoline: extern "sysv64" fn(u64, u64, u64) -> ! = mem::transmute(prepared.trampoline_addr);
+    trampoline(
+        prepared.plan.entry as u64,
+        prepared.mbi_addr,
+        prepared.trampoline_addr + TRAMPOLINE_GDTR_OFFSET as u64,
+    )
+}
@@ -0,0 +1,199 @@
+//! 4-level page tables built by the bootloader and handed over to the kernel.
+
+use uefi::table::boot::{AllocateType, BootServices, MemoryType};
+
+pub const PAGE_SIZE: u64 = 0x1000;
+pub const LARGE_PAGE_SIZE: u64 = 0x20_0000;
+pub const HUGE_PAGE_SIZE: u64 = 0x4000_0000;
+
+pub const PAGE_PRESENT: u64 = 1 << 0;
+pub const PAGE_WRITABLE: u64 = 1 << 1;
+pub const PAGE_HUGE: u64 = 1 << 7;
+pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+
+const IA32_EFER: u32 = 0xC000_0080;
+const EFER_NXE: u64 = 1 << 11;
+const CR0_WP: u64 = 1 << 16;
+
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[repr(C, align(4096))]
+struct PageTable {
+    entries: [u64; 512],
+}
+
+fn table_index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * level)) & 0x1FF) as usize
+}
+
+/// Allocates a zeroed page for a page table.
+fn allocate_table(boot_services: &BootServices) -> Result<u64, &'static str> {
+    let addr = boot_services
+        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1)
+        .map_err(|_| "Failed to allocate page table")?;
+    unsafe {
+        core::ptr::write_bytes(addr as *mut u8, 0, PAGE_SIZE as usize);
+    }
+    Ok(addr)
+}
+
+/// Builds a fresh set of page tables in `LOADER_DATA` memory. Tables are
+/// written through the firmware's identity mapping, so this has to run
+/// before `exit_boot_services`.
+pub struct PageTableBuilder<'a> {
+    boot_services: &'a BootServices,
+    pml4_addr: u64,
+    /// Whether the CPU understands `PAGE_NO_EXECUTE`; it is a reserved bit
+    /// otherwise and has to be dropped.
+    nx_supported: bool,
+    /// Whether 1 GiB pages can be used (CPUID `pdpe1gb`).
+    gigabyte_pages: bool,
+}
+
+impl<'a> PageTableBuilder<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Result<Self, &'static str> {
+        let pml4_addr = allocate_table(boot_services)?;
+        Ok(Self {
+            boot_services,
+            pml4_addr,
+            nx_supported: nx_supported(),
+            gigabyte_pages: gigabyte_pages_supported(),
+        })
+    }
+
+    pub fn pml4_addr(&self) -> u64 {
+        self.pml4_addr
+    }
+
+    /// Returns the table that entry `index` of `table_addr` points to,
+    /// creating it if needed.
+    fn next_table(&mut self, table_addr: u64, index: usize) -> Result<u64, &'static str> {
+        let table = unsafe { &mut *(table_addr as *mut PageTable) };
+        let entry = table.entries[index];
+        if entry & PAGE_PRESENT != 0 {
+            if entry & PAGE_HUGE != 0 {
+                return Err("Mapping overlaps an existing large page");
+            }
+            return Ok(entry & ADDR_MASK);
+        }
+
+        let next = allocate_table(self.boot_services)?;
+        table.entries[index] = next | PAGE_PRESENT | PAGE_WRITABLE;
+        Ok(next)
+    }
+
+    /// Walks down to the table at `level` (0 = PT, 1 = PD, 2 = PDPT) for `virt`.
+    fn walk(&mut self, virt: u64, level: u32) -> Result<&'static mut PageTable, &'static str> {
+        let mut table = self.pml4_addr;
+        for current in ((level + 1)..4).rev() {
+            table = self.next_table(table, table_index(virt, current))?;
+        }
+        Ok(unsafe { &mut *(table as *mut PageTable) })
+    }
+
+    fn set_leaf(&self, table: &mut PageTable, index: usize, phys: u64, flags: u64) -> Result<(), &'static str> {
+        let flags = if self.nx_supported { flags } else { flags & !PAGE_NO_EXECUTE };
+        let entry = &mut table.entries[index];
+        if *entry & PAGE_PRESENT != 0 {
+            // Segments that share a page get the union of their permissions,
+            // so the page stays executable if either of them is
+            if *entry & ADDR_MASK != phys {
+                return Err("Virtual address is already mapped elsewhere");
+            }
+            let no_execute = *entry & flags & PAGE_NO_EXECUTE;
+            *entry = ((*entry | flags) & !PAGE_NO_EXECUTE) | no_execute;
+            return Ok(());
+        }
+        *entry = phys | flags | PAGE_PRESENT;
+        Ok(())
+    }
+
+    pub fn map_4k(&mut self, virt: u64, phys: u64, flags: u64) -> Result<(), &'static str> {
+        let pt = self.walk(virt, 0)?;
+        self.set_leaf(pt, table_index(virt, 0), phys, flags)
+    }
+
+    pub fn map_2m(&mut self, virt: u64, phys: u64, flags: u64) -> Result<(), &'static str> {
+        let pd = self.walk(virt, 1)?;
+        self.set_leaf(pd, table_index(virt, 1), phys, flags | PAGE_HUGE)
+    }
+
+    pub fn map_1g(&mut self, virt: u64, phys: u64, flags: u64) -> Result<(), &'static str> {
+        let pdpt = self.walk(virt, 2)?;
+        self.set_leaf(pdpt, table_index(virt, 2), phys, flags | PAGE_HUGE)
+    }
+
+    /// Maps `size` bytes at `virt` to `phys`, both page aligned, using the
+    /// largest pages (1 GiB if the CPU has them, else 2 MiB) that both
+    /// addresses allow.
+    pub fn map_range(&mut self, virt: u64, phys: u64, size: u64, flags: u64) -> Result<(), &'static str> {
+        let fits = |v: u64, p: u64, remaining: u64, page: u64| v % page == 0 && p % page == 0 && remaining >= page;
+        let mut offset = 0;
+        while offset < size {
+            let (v, p) = (virt + offset, phys + offset);
+            if self.gigabyte_pages && fits(v, p, size - offset, HUGE_PAGE_SIZE) {
+                self.map_1g(v, p, flags)?;
+                offset += HUGE_PAGE_SIZE;
+            } else if fits(v, p, size - offset, LARGE_PAGE_SIZE) {
+                self.map_2m(v, p, flags)?;
+                offset += LARGE_PAGE_SIZE;
+            } else {
+                self.map_4k(v, p, flags)?;
+                offset += PAGE_SIZE;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// CPUID.80000001h:EDX.NX
+fn nx_supported() -> bool {
+    let max_extended = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0001 {
+        return false;
+    }
+    let edx = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx;
+    edx & (1 << 20) != 0
+}
+
+/// CPUID.80000001h:EDX.Page1GB
+fn gigabyte_pages_supported() -> bool {
+    let max_extended = unsafe { core::arch::x86_64::__cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0001 {
+        return false;
+    }
+    let edx = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx;
+    edx & (1 << 26) != 0
+}
+
+/// Turns on `EFER.NXE` (if the CPU has it) so `PAGE_NO_EXECUTE` is honoured,
+/// and `CR0.WP` so read-only pages are enforced in ring 0 too. Has to run
+/// before loading page tables that use the NX bit.
+pub unsafe fn enable_protection() {
+    if nx_supported() {
+        let (low, high): (u32, u32);
+        core::arch::asm!("rdmsr", in("ecx") IA32_EFER, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        let efer = ((high as u64) << 32 | low as u64) | EFER_NXE;
+        core::arch::asm!(
+            "wrmsr",
+            in("ecx") IA32_EFER,
+            in("eax") efer as u32,
+            in("edx") (efer >> 32) as u32,
+            options(nostack, preserves_flags)
+        );
+    }
+
+    let mut cr0: u64;
+    core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+    cr0 |= CR0_WP;
+    core::arch::asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+}
+
+#[inline(always)]
+pub unsafe fn load_cr3(pml4_addr: u64) {
+    core::arch::asm!(
+        "mov cr3, {}",
+        in(reg) pml4_addr,
+        options(nostack, preserves_flags)
+    );
+}
@@ -0,0 +1,74 @@
+//! Polled 16550 UART on COM1.
+//!
+//! Only needs port I/O, so it keeps working after `exit_boot_services`.
+
+use core::arch::asm;
+use core::fmt;
+
+pub const COM1: u16 = 0x3F8;
+
+// Register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+/// 115200 baud
+const DIVISOR: u16 = 1;
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Programs the UART at `base` for 115200 8N1 with interrupts off.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the I/O port of a 16550 compatible UART, or of nothing.
+    pub unsafe fn init(base: u16) -> Self {
+        outb(base + INTERRUPT_ENABLE, 0x00);
+        outb(base + LINE_CONTROL, 0x80); // DLAB on to set the divisor
+        outb(base + DATA, DIVISOR as u8);
+        outb(base + INTERRUPT_ENABLE, (DIVISOR >> 8) as u8);
+        outb(base + LINE_CONTROL, 0x03); // 8N1, DLAB off
+        outb(base + FIFO_CONTROL, 0xC7); // Enable and clear FIFOs
+        outb(base + MODEM_CONTROL, 0x03); // DTR and RTS
+        Self { base }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            // Without a UART the status reads as 0xFF, so this doesn't hang
+            while inb(self.base + LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
@@ -0,0 +1,20 @@
+//! TSC readings for [`BootTimings`](rustos_bootinfo::BootTimings) and the
+//! diagnostics progress bar.
+//!
+//! Boot services are gone by the end of the boot, so time is kept with the
+//! TSC, and its rate is measured once against `stall`.
+
+use uefi::prelude::*;
+
+const CALIBRATION_US: usize = 10_000;
+
+pub fn now() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// TSC ticks per millisecond.
+pub fn calibrate(boot_services: &BootServices) -> u64 {
+    let start = now();
+    boot_services.stall(CALIBRATION_US);
+    (now() - start) / (CALIBRATION_US as u64 / 1000)
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 
+use rustos_bootinfo::{BootInfo, Framebuffer, KERNEL_ENTRY_MAGIC};
 
 // Simple 8x8 bitmap font data
 const FONT_DATA: [[u8; 8]; 128] = [
@@ -128,44 +129,39 @@ const FONT_DATA: [[u8; 8]; 128] = [
 ];
 
 // Text rendering functions
-unsafe fn draw_char(fb_addr: *mut u32, width: u32, x: u32, y: u32, ch: u8, color: u32) {
+fn draw_char(fb: &mut Framebuffer, x: usize, y: usize, ch: u8, color: u32) {
     if (ch as usize) >= FONT_DATA.len() {
         return;
     }
     
     let font_data = &FONT_DATA[ch as usize];
     
-    for row in 0..8 {
-        let byte = font_data[row];
+    for (row, byte) in font_data.iter().enumerate() {
         for col in 0..8 {
             if (byte >> (7 - col)) & 1 != 0 {
-                let px = x + col;
-                let py = y + row as u32;
-                if px < width && py < 2048 {
-                    let pixel_offset = (py * width + px) as isize;
-                    *fb_addr.offset(pixel_offset) = color;
-                }
+                // Clipped to the real screen size by the framebuffer
+                fb.set_pixel(x + col, y + row, color);
             }
         }
     }
 }
 
-unsafe fn draw_string(fb_addr: *mut u32, width: u32, x: u32, y: u32, s: &str, color: u32) {
+fn draw_string(fb: &mut Framebuffer, x: usize, y: usize, s: &str, color: u32) {
     let mut current_x = x;
     for byte in s.bytes() {
-        draw_char(fb_addr, width, current_x, y, byte, color);
-        current_x += 8; // Move to next character position
-        if current_x >= width - 8 {
+        if current_x + 8 > fb.width() {
             break; // Don't draw beyond screen
         }
+        draw_char(fb, current_x, y, byte, color);
+        current_x += 8; // Move to next character position
     }
 }
 
-unsafe fn draw_u64(fb_addr: *mut u32, width: u32, x: u32, y: u32, number: u64, color: u32) {
+fn draw_u64(fb: &mut Framebuffer, x: usize, y: usize, number: u64, color: u32) {
     let mut current_x = x;
-    draw_char(fb_addr, width, current_x, y, b'0', color);
+    draw_char(fb, current_x, y, b'0', color);
     current_x += 8;
-    draw_char(fb_addr, width, current_x, y, b'x', color);
+    draw_char(fb, current_x, y, b'x', color);
     current_x += 8;
     for i in 0..16 {
         let nibble = (number >> ((15 - i) * 4)) & 0xf;
@@ -175,15 +171,15 @@ unsafe fn draw_u64(fb_addr: *mut u32, width: u32, x: u32, y: u32, number: u64, c
            10..=15 => (b'a' + (nibble - 10)), // For 10-15, convert to 'a'-'f'
         _ => unreachable!(), // This case should never be reached with a valid nibble
         };
-        draw_char(fb_addr, width, current_x, y, ch, color);
+        draw_char(fb, current_x, y, ch, color);
         current_x += 8;
     }
 }
 
 
-unsafe fn draw_number(fb_addr: *mut u32, width: u32, x: u32, y: u32, mut num: u32, color: u32) {
+fn draw_number(fb: &mut Framebuffer, x: usize, y: usize, mut num: u64, color: u32) {
     if num == 0 {
-        draw_char(fb_addr, width, x, y, b'0', color);
+        draw_char(fb, x, y, b'0', color);
         return;
     }
     
@@ -199,93 +195,80 @@ unsafe fn draw_number(fb_addr: *mut u32, width: u32, x: u32, y: u32, mut num: u3
     let mut current_x = x + (digit_count - 1) * 8;
     while num > 0 {
         let digit = (num % 10) as u8 + b'0';
-        draw_char(fb_addr, width, current_x, y, digit, color);
+        draw_char(fb, current_x, y, digit, color);
         num /= 10;
         current_x = current_x.saturating_sub(8);
     }
 }
 
-const PAGE_PRESENT: u64 = 1 << 0;
-const PAGE_WRITABLE: u64 = 1 << 1;
-const PAGE_HUGE: u64 = 1 << 7;
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct BootInfo {
-    pub memory_map: MemoryMapInfo,
-    pub framebuffer: FramebufferInfo,
-    pub rsdp_addr: Option<u64>,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct MemoryMapInfo {
-    pub entries: *const u8, // just opaque for now rather than MemoryDescriptor,
-    pub entry_count: usize,
-    pub entry_size: usize,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct FramebufferInfo {
-    pub addr: u64,
-    pub width: u32,
-    pub height: u32,
-    pub pitch: u32,
-    pub bpp: u32,
-    pub red_mask: u32,
-    pub green_mask: u32,
-    pub blue_mask: u32,
-}
+// Kept for the panic handler
+static mut FRAMEBUFFER: Option<Framebuffer> = None;
 
 #[no_mangle]
-pub extern "C" fn _start(boot_info: *const BootInfo) -> ! {
+pub extern "C" fn _start(boot_info: *const BootInfo, magic: u64) -> ! {
+    if magic != KERNEL_ENTRY_MAGIC {
+        panic!("Bad entry magic");
+    }
+    let boot_info = match unsafe { BootInfo::from_ptr(boot_info) } {
+        Ok(boot_info) => boot_info,
+        Err(_) => panic!("Incompatible boot info"),
+    };
 
-    unsafe {
-        let fb_addr = 0x80000000 as *mut u32;
-        let width = 2048u32;
-        let height = 2048u32;
-        
-        let mut counter = 0u32;
+    // The test kernel runs identity mapped, so the physical address works
+    let fb = match unsafe { Framebuffer::new(&boot_info.framebuffer) } {
+        Some(fb) => unsafe { (*core::ptr::addr_of_mut!(FRAMEBUFFER)).insert(fb) },
+        None => loop {
+            unsafe { core::arch::asm!("hlt") };
+        },
+    };
+    
+    let (width, height) = (fb.width(), fb.height());
+    let black = fb.color(0x000000);
+    let white = fb.color(0xFFFFFF);
+    let green = fb.color(0x00FF00);
+    let cyan = fb.color(0x00FFFF);
+    let magenta = fb.color(0xFF00FF);
+    let yellow = fb.color(0xFFFF00);
+    
+    let mut counter = 0u64;
+    
+    loop {
+        // Clear the text area with black
+        fb.fill_rect(0, 0, width, 100, black);
         
-        loop {
-            // Clear screen with black
-            for i in 0..(width * 100) {
-                *fb_addr.offset(i as isize) = 0xFF000000; // Black background
-            }
-            
-            // Draw some text
-            draw_string(fb_addr, width, 10, 10, "RustOS Kernel Running!", 0xFFFFFFFF); // White text
-            draw_string(fb_addr, width, 10, 30, "Bootloader Success!", 0xFF00FF00); // Green text
-            draw_string(fb_addr, width, 10, 50, "Counter: ", 0xFF00FFFF); // Cyan text
-            draw_number(fb_addr, width, 90, 50, counter, 0xFF00FFFF); // Cyan number
+        // Draw some text
+        draw_string(fb, 10, 10, "RustOS Kernel Running!", white);
+        draw_string(fb, 10, 30, "Bootloader Success!", green);
+        draw_string(fb, 10, 50, "Counter: ", cyan);
+        draw_number(fb, 90, 50, counter, cyan);
 
 
-            // Draw some dynamic info
-            draw_string(fb_addr, width, 10, 70, "Fb addr:", 0xFFFF00FF); // Magenta
-            draw_u64(fb_addr, width, 10 + 128, 70, (*boot_info).framebuffer.addr , 0xFFFF00FF); // Magenta
-            draw_string(fb_addr, width, 10, 90, "Resolution: 2048x2048", 0xFFFFFF00); // Yellow
-            
-            counter += 1;
-            
-            // Delay
-            for _ in 0..10000000 {
-                core::arch::asm!("nop");
-            }
+        // Draw some dynamic info
+        draw_string(fb, 10, 70, "Fb addr:", magenta);
+        draw_u64(fb, 10 + 128, 70, boot_info.framebuffer.addr, magenta);
+        draw_string(fb, 10, 90, "Resolution:", yellow);
+        draw_number(fb, 10 + 96, 90, width as u64, yellow);
+        draw_char(fb, 10 + 96 + 40, 90, b'x', yellow);
+        draw_number(fb, 10 + 96 + 48, 90, height as u64, yellow);
+        
+        counter += 1;
+        
+        // Delay
+        for _ in 0..10000000 {
+            unsafe { core::arch::asm!("nop") };
         }
     }
 }
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe {
-        let fb_addr = 0x80000000 as *mut u32;
+    if let Some(fb) = unsafe { (*core::ptr::addr_of_mut!(FRAMEBUFFER)).as_mut() } {
         // Clear screen with red
-        for i in 0..2048*2048 {
-            *fb_addr.offset(i) = 0xFFFF0000;
-        }
+        let red = fb.color(0xFF0000);
+        let white = fb.color(0xFFFFFF);
+        fb.clear(red);
         // Draw "PANIC!" text
-        draw_string(fb_addr, 2048, 50, 50, "PANIC!", 0xFFFFFFFF);
+        draw_string(fb, 50, 50, "PANIC!", white);
     }
     loop {}
 }
@@ -1,32 +1,7 @@
 #![no_std]
 #![no_main]
 
-// BootInfo structures from bootloader (simplified)
-#[repr(C)]
-pub struct BootInfo {
-    pub memory_map: MemoryMapInfo,
-    pub framebuffer: FramebufferInfo,
-    pub rsdp_addr: Option<u64>,
-}
-
-#[repr(C)]
-pub struct MemoryMapInfo {
-    pub entries: *const u8,
-    pub entry_count: usize,
-    pub entry_size: usize,
-}
-
-#[repr(C)]
-pub struct FramebufferInfo {
-    pub addr: u64,
-    pub width: u32,
-    pub height: u32,
-    pub pitch: u32,
-    pub bpp: u32,
-    pub red_mask: u32,
-    pub green_mask: u32,
-    pub blue_mask: u32,
-}
+use rustos_bootinfo::BootInfo;
 
 #[no_mangle]
 pub extern "C" fn _start(_boot_info: *const BootInfo) -> ! {
@@ -0,0 +1,94 @@
+use core::fmt;
+
+use crate::ELF_PHDR_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooSmall { len: usize },
+    BadMagic,
+    UnsupportedClass(u8),
+    UnsupportedEndianness(u8),
+    UnsupportedVersion(u8),
+    UnsupportedType(u16),
+    UnsupportedMachine(u16),
+    BadProgramHeaderSize(u16),
+    ProgramHeadersOutOfBounds { offset: u64, count: u16 },
+    NoLoadableSegments,
+    FileSizeExceedsMemSize { index: usize, filesz: u64, memsz: u64 },
+    SegmentOutOfBounds { index: usize, offset: u64, filesz: u64 },
+    AddressOverflow { index: usize, vaddr: u64, memsz: u64 },
+    OverlappingSegments { first: usize, second: usize },
+    EntryOutsideSegments(u64),
+    AllocationFailed { pages: u64 },
+    FixedAddressUnavailable { addr: u64, pages: u64 },
+    RelNotSupported,
+    BadRelocationEntrySize(u64),
+    BadSymbolEntrySize(u64),
+    DynamicOutOfBounds(u64),
+    UnsupportedRelocation { ty: u32, offset: u64 },
+    UndefinedSymbol { index: u32, offset: u64 },
+    RelocationOutOfBounds(u64),
+    NotRelocatable,
+    BadSlide(u64),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ElfError::TooSmall { len } => write!(f, "file is {} bytes, too small for an ELF64 header", len),
+            ElfError::BadMagic => write!(f, "missing \\x7fELF magic"),
+            ElfError::UnsupportedClass(class) => write!(f, "EI_CLASS {} is not ELFCLASS64", class),
+            ElfError::UnsupportedEndianness(data) => write!(f, "EI_DATA {} is not little-endian", data),
+            ElfError::UnsupportedVersion(version) => write!(f, "unsupported ELF version {}", version),
+            ElfError::UnsupportedType(ty) => write!(f, "e_type {} is neither ET_EXEC nor ET_DYN", ty),
+            ElfError::UnsupportedMachine(machine) => write!(f, "e_machine {} is not x86_64", machine),
+            ElfError::BadProgramHeaderSize(size) => {
+                write!(f, "e_phentsize is {} bytes, expected {}", size, ELF_PHDR_SIZE)
+            }
+            ElfError::ProgramHeadersOutOfBounds { offset, count } => {
+                write!(f, "{} program headers at offset 0x{:x} run past the end of the file", count, offset)
+            }
+            ElfError::NoLoadableSegments => write!(f, "no PT_LOAD segments"),
+            ElfError::FileSizeExceedsMemSize { index, filesz, memsz } => {
+                write!(f, "segment {}: p_filesz 0x{:x} > p_memsz 0x{:x}", index, filesz, memsz)
+            }
+            ElfError::SegmentOutOfBounds { index, offset, filesz } => {
+                write!(f, "segment {}: file range 0x{:x}+0x{:x} runs past the end of the file", index, offset, filesz)
+            }
+            ElfError::AddressOverflow { index, vaddr, memsz } => {
+                write!(f, "segment {}: p_vaddr 0x{:x} + p_memsz 0x{:x} overflows", index, vaddr, memsz)
+            }
+            ElfError::OverlappingSegments { first, second } => {
+                write!(f, "segments {} and {} overlap in memory", first, second)
+            }
+            ElfError::EntryOutsideSegments(entry) => {
+                write!(f, "entry point 0x{:x} is not inside any PT_LOAD segment", entry)
+            }
+            ElfError::AllocationFailed { pages } => write!(f, "failed to allocate {} pages for the kernel", pages),
+            ElfError::FixedAddressUnavailable { addr, pages } => write!(
+                f,
+                "{} pages at link address 0x{:x} are unavailable and the kernel is not position independent (ET_EXEC)",
+                pages, addr
+            ),
+            ElfError::RelNotSupported => write!(f, "REL relocations are not supported, only RELA"),
+            ElfError::BadRelocationEntrySize(size) => write!(f, "DT_RELAENT is {} bytes, expected 24", size),
+            ElfError::BadSymbolEntrySize(size) => write!(f, "DT_SYMENT is {} bytes, expected 24", size),
+            ElfError::DynamicOutOfBounds(addr) => {
+                write!(f, "dynamic table references 0x{:x}, which is not in the file", addr)
+            }
+            ElfError::UnsupportedRelocation { ty, offset } => {
+                write!(f, "unsupported relocation type {} at 0x{:x}", ty, offset)
+            }
+            ElfError::UndefinedSymbol { index, offset } => {
+                write!(f, "relocation at 0x{:x} refers to undefined symbol {}", offset, index)
+            }
+            ElfError::RelocationOutOfBounds(offset) => {
+                write!(f, "relocation target 0x{:x} is outside the image", offset)
+            }
+            ElfError::NotRelocatable => write!(f, "only higher-half position independent (ET_DYN) kernels can be moved"),
+            ElfError::BadSlide(slide) => {
+                write!(f, "slide 0x{:x} is not page-aligned or moves the kernel past the end of memory", slide)
+            }
+        }
+    }
+}
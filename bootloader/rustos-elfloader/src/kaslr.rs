@@ -0,0 +1,56 @@
+//! Address arithmetic for kernel address space layout randomisation.
+//!
+//! The bootloader gets the random bits and the free memory from the firmware;
+//! turning them into addresses is done here so the host tests can check that
+//! every result is aligned, in range and actually random.
+
+/// Granularity of both the virtual slide and the physical placement, so the
+/// kernel's 2 MiB alignment is kept and large pages still line up.
+pub const KASLR_ALIGN: u64 = 0x20_0000;
+
+/// How far above its link address [`virtual_slide`] may move an image.
+pub const VIRTUAL_WINDOW: u64 = 1 << 30;
+
+/// A slide for an image linked to end at `link_end`: a multiple of
+/// [`KASLR_ALIGN`] below [`VIRTUAL_WINDOW`] that doesn't push the image past
+/// the top of the address space.
+pub fn virtual_slide(random: u64, link_end: u64) -> u64 {
+    let room = VIRTUAL_WINDOW.min(link_end.wrapping_neg());
+    let slots = room.saturating_sub(1) / KASLR_ALIGN + 1;
+    (random % slots) * KASLR_ALIGN
+}
+
+/// First [`KASLR_ALIGN`]ed address in `[start, end)` where `size` bytes fit
+/// and how many such addresses there are. Address 0 is never used, so the
+/// kernel doesn't sit at a null pointer.
+fn slots((start, end): (u64, u64), size: u64) -> (u64, u64) {
+    let Some(first) = start.max(KASLR_ALIGN).checked_next_multiple_of(KASLR_ALIGN) else {
+        return (0, 0);
+    };
+    match first.checked_add(size) {
+        Some(first_end) if first_end <= end => (first, (end - first_end) / KASLR_ALIGN + 1),
+        _ => (first, 0),
+    }
+}
+
+/// Picks one of the [`KASLR_ALIGN`]ed addresses where `size` bytes fit in
+/// one of the free `[start, end)` `ranges`, each equally likely. Returns
+/// `None` if there is no such address.
+pub fn random_slot<I>(ranges: I, size: u64, random: u64) -> Option<u64>
+where
+    I: Iterator<Item = (u64, u64)> + Clone,
+{
+    let total = ranges.clone().map(|range| slots(range, size).1).try_fold(0u64, u64::checked_add)?;
+    if total == 0 {
+        return None;
+    }
+    let mut slot = random % total;
+    for range in ranges {
+        let (first, count) = slots(range, size);
+        if slot < count {
+            return Some(first + slot * KASLR_ALIGN);
+        }
+        slot -= count;
+    }
+    None
+}
@@ -0,0 +1,384 @@
+//! ELF64 kernel loader used by the RustOS UEFI bootloader.
+//!
+//! The loader never touches physical memory directly: memory for the image
+//! comes from a [`SegmentAllocator`]. The bootloader backs it with
+//! `BootServices::allocate_pages`, the host tests back it with plain `Vec`s.
+
+#![no_std]
+
+extern crate alloc;
+
+mod error;
+pub mod kaslr;
+mod reloc;
+
+pub use error::ElfError;
+
+use alloc::vec::Vec;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Start of the canonical higher half. The bootloader maps images linked
+/// above it at their link address, so they can go anywhere in physical memory.
+pub const HIGHER_HALF: u64 = 0xffff_8000_0000_0000;
+
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_TYPE_DYN: u16 = 3;
+const ELF_MACHINE_X86_64: u16 = 62;
+const ELF_HEADER_SIZE: usize = 64;
+pub(crate) const ELF_PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+
+/// Segment permission bits from `p_flags`.
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    /// `ET_EXEC`: must be loaded at its link-time addresses.
+    Executable,
+    /// `ET_DYN`: position independent.
+    SharedObject,
+}
+
+/// A validated `PT_LOAD` program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Index of the program header in the file.
+    pub index: usize,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub flags: u32,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.vaddr + self.memsz
+    }
+}
+
+/// An ELF64 image whose header and `PT_LOAD` segments have been checked
+/// against the file.
+#[derive(Debug, Clone)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    elf_type: ElfType,
+    entry: u64,
+    /// Sorted by virtual address, never overlapping.
+    segments: Vec<Segment>,
+    /// File offset and size of the `PT_DYNAMIC` table.
+    dynamic: Option<(u64, u64)>,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::TooSmall { len: data.len() });
+        }
+        if &data[0..4] != b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELF_CLASS_64 {
+            return Err(ElfError::UnsupportedClass(data[4]));
+        }
+        if data[5] != ELF_DATA_LSB {
+            return Err(ElfError::UnsupportedEndianness(data[5]));
+        }
+        if data[6] != ELF_VERSION_CURRENT {
+            return Err(ElfError::UnsupportedVersion(data[6]));
+        }
+
+        let elf_type = match read_u16(data, 16) {
+            ELF_TYPE_EXEC => ElfType::Executable,
+            ELF_TYPE_DYN => ElfType::SharedObject,
+            other => return Err(ElfError::UnsupportedType(other)),
+        };
+        let machine = read_u16(data, 18);
+        if machine != ELF_MACHINE_X86_64 {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+
+        let entry = read_u64(data, 24);
+        let ph_offset = read_u64(data, 32);
+        let ph_entry_size = read_u16(data, 54);
+        let ph_num = read_u16(data, 56);
+
+        if ph_num > 0 && ph_entry_size as usize != ELF_PHDR_SIZE {
+            return Err(ElfError::BadProgramHeaderSize(ph_entry_size));
+        }
+        let ph_table_end = (ph_num as u64)
+            .checked_mul(ELF_PHDR_SIZE as u64)
+            .and_then(|size| size.checked_add(ph_offset));
+        match ph_table_end {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::ProgramHeadersOutOfBounds { offset: ph_offset, count: ph_num }),
+        }
+
+        let mut segments = Vec::new();
+        let mut dynamic = None;
+        for index in 0..ph_num as usize {
+            let ph_start = ph_offset as usize + index * ELF_PHDR_SIZE;
+            let ph = &data[ph_start..ph_start + ELF_PHDR_SIZE];
+
+            match read_u32(ph, 0) {
+                PT_LOAD => {}
+                PT_DYNAMIC => {
+                    let offset = read_u64(ph, 8);
+                    let filesz = read_u64(ph, 32);
+                    match offset.checked_add(filesz) {
+                        Some(end) if end <= data.len() as u64 => dynamic = Some((offset, filesz)),
+                        _ => return Err(ElfError::SegmentOutOfBounds { index, offset, filesz }),
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let segment = Segment {
+                index,
+                flags: read_u32(ph, 4),
+                offset: read_u64(ph, 8),
+                vaddr: read_u64(ph, 16),
+                paddr: read_u64(ph, 24),
+                filesz: read_u64(ph, 32),
+                memsz: read_u64(ph, 40),
+            };
+
+            if segment.filesz > segment.memsz {
+                return Err(ElfError::FileSizeExceedsMemSize {
+                    index,
+                    filesz: segment.filesz,
+                    memsz: segment.memsz,
+                });
+            }
+            match segment.offset.checked_add(segment.filesz) {
+                Some(end) if end <= data.len() as u64 => {}
+                _ => {
+                    return Err(ElfError::SegmentOutOfBounds {
+                        index,
+                        offset: segment.offset,
+                        filesz: segment.filesz,
+                    })
+                }
+            }
+            // The image end gets rounded up to a page, so leave room for that too
+            match segment.vaddr.checked_add(segment.memsz) {
+                Some(end) if end.checked_add(PAGE_SIZE - 1).is_some() => {}
+                _ => {
+                    return Err(ElfError::AddressOverflow {
+                        index,
+                        vaddr: segment.vaddr,
+                        memsz: segment.memsz,
+                    })
+                }
+            }
+
+            segments.push(segment);
+        }
+
+        if segments.is_empty() {
+            return Err(ElfError::NoLoadableSegments);
+        }
+
+        segments.sort_unstable_by_key(|segment| segment.vaddr);
+        for pair in segments.windows(2) {
+            if pair[0].end() > pair[1].vaddr {
+                return Err(ElfError::OverlappingSegments {
+                    first: pair[0].index,
+                    second: pair[1].index,
+                });
+            }
+        }
+
+        if !segments.iter().any(|s| entry >= s.vaddr && entry < s.end()) {
+            return Err(ElfError::EntryOutsideSegments(entry));
+        }
+
+        Ok(Self {
+            data,
+            elf_type,
+            entry,
+            segments,
+            dynamic,
+        })
+    }
+
+    pub fn elf_type(&self) -> ElfType {
+        self.elf_type
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Page-aligned virtual address range covered by all `PT_LOAD` segments.
+    pub fn image_range(&self) -> (u64, u64) {
+        let start = self.segments[0].vaddr & !(PAGE_SIZE - 1);
+        let end = self.segments.iter().map(Segment::end).max().unwrap();
+        (start, (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+    }
+
+    /// Whether the image can be loaded at a virtual address other than its
+    /// link-time one with [`load_with_slide`]: a higher-half `ET_DYN` image.
+    pub fn can_slide(&self) -> bool {
+        self.elf_type == ElfType::SharedObject && self.image_range().0 >= HIGHER_HALF
+    }
+
+    /// File offset of `len` bytes at link-time address `vaddr`, if they are
+    /// backed by file data of a single `PT_LOAD` segment.
+    fn file_offset(&self, vaddr: u64, len: u64) -> Option<usize> {
+        let segment = self.segments.iter().find(|s| vaddr >= s.vaddr && vaddr < s.vaddr + s.filesz)?;
+        let end = vaddr.checked_add(len)?;
+        if end > segment.vaddr + segment.filesz {
+            return None;
+        }
+        Some((segment.offset + (vaddr - segment.vaddr)) as usize)
+    }
+}
+
+/// Where a [`SegmentAllocator`] should place an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Exactly at this page-aligned physical address.
+    At(u64),
+    Anywhere,
+}
+
+/// Source of physical memory for the loaded image.
+pub trait SegmentAllocator {
+    /// Allocates `pages` contiguous 4 KiB pages and returns their physical
+    /// address, or `None` if the request can't be satisfied.
+    fn allocate(&mut self, placement: Placement, pages: u64) -> Option<u64>;
+
+    /// Returns `len` bytes of memory starting at `phys_addr`, which lies in a
+    /// range previously returned by [`SegmentAllocator::allocate`].
+    fn memory(&mut self, phys_addr: u64, len: usize) -> &mut [u8];
+}
+
+/// Result of [`load`].
+#[derive(Debug, Clone)]
+pub struct LoadedImage {
+    /// Physical address the image was placed at.
+    pub phys_base: u64,
+    /// Virtual address of the first page of the image once running, i.e. the
+    /// link-time base plus `relocation_offset`.
+    pub virt_base: u64,
+    pub pages: u64,
+    /// Entry point, adjusted by `relocation_offset`.
+    pub entry: u64,
+    /// Difference between run-time and link-time virtual addresses, wrapping.
+    /// Always 0 for `ET_EXEC` images; for higher-half ones it is the slide
+    /// passed to [`load_with_slide`].
+    pub relocation_offset: u64,
+    /// Segments at their link-time addresses.
+    pub segments: Vec<Segment>,
+}
+
+impl LoadedImage {
+    /// Link-time virtual address of the first page of the image.
+    pub fn link_base(&self) -> u64 {
+        self.virt_base.wrapping_sub(self.relocation_offset)
+    }
+
+    /// Physical address backing link-time virtual address `vaddr`.
+    pub fn phys_addr(&self, vaddr: u64) -> u64 {
+        self.phys_base + (vaddr - self.link_base())
+    }
+
+    /// Whether the image runs at the same virtual and physical addresses.
+    pub fn is_identity_mapped(&self) -> bool {
+        self.virt_base == self.phys_base
+    }
+}
+
+/// Copies every `PT_LOAD` segment of `elf` into memory obtained from
+/// `allocator` and zeroes the rest of the image.
+///
+/// Images linked into the higher half keep their link-time virtual addresses
+/// and may be placed anywhere physically. Lower-half images run identity
+/// mapped: `ET_EXEC` ones must get their link-time address, `ET_DYN` ones go
+/// there if possible and anywhere otherwise. `ET_DYN` images get their `RELA`
+/// relocations applied for the virtual address they end up at.
+pub fn load<A: SegmentAllocator>(elf: &ElfFile, allocator: &mut A) -> Result<LoadedImage, ElfError> {
+    load_with_slide(elf, allocator, 0)
+}
+
+/// Like [`load`], but moves a higher-half `ET_DYN` image `slide` bytes above
+/// its link-time address. `slide` must be page-aligned and keep the image in
+/// the higher half; it must be 0 for images that can't slide.
+pub fn load_with_slide<A: SegmentAllocator>(elf: &ElfFile, allocator: &mut A, slide: u64) -> Result<LoadedImage, ElfError> {
+    let (link_base, link_end) = elf.image_range();
+    let pages = (link_end - link_base) / PAGE_SIZE;
+
+    if slide != 0 {
+        if !elf.can_slide() {
+            return Err(ElfError::NotRelocatable);
+        }
+        if !slide.is_multiple_of(PAGE_SIZE) || link_end.checked_add(slide).is_none() {
+            return Err(ElfError::BadSlide(slide));
+        }
+    }
+
+    let phys_base = if link_base >= HIGHER_HALF {
+        allocator
+            .allocate(Placement::Anywhere, pages)
+            .ok_or(ElfError::AllocationFailed { pages })?
+    } else {
+        match elf.elf_type() {
+            ElfType::Executable => allocator
+                .allocate(Placement::At(link_base), pages)
+                .ok_or(ElfError::FixedAddressUnavailable { addr: link_base, pages })?,
+            ElfType::SharedObject => allocator
+                .allocate(Placement::At(link_base), pages)
+                .or_else(|| allocator.allocate(Placement::Anywhere, pages))
+                .ok_or(ElfError::AllocationFailed { pages })?,
+        }
+    };
+    let virt_base = if link_base >= HIGHER_HALF { link_base + slide } else { phys_base };
+    let relocation_offset = virt_base.wrapping_sub(link_base);
+
+    let image = allocator.memory(phys_base, (pages * PAGE_SIZE) as usize);
+    image.fill(0);
+    for segment in elf.segments() {
+        let start = (segment.vaddr - link_base) as usize;
+        let file_range = segment.offset as usize..(segment.offset + segment.filesz) as usize;
+        image[start..start + segment.filesz as usize].copy_from_slice(&elf.data[file_range]);
+    }
+
+    if elf.elf_type() == ElfType::SharedObject {
+        reloc::relocate(elf, image, link_base, relocation_offset)?;
+    }
+
+    Ok(LoadedImage {
+        phys_base,
+        virt_base,
+        pages,
+        entry: elf.entry().wrapping_add(relocation_offset),
+        relocation_offset,
+        segments: elf.segments().to_vec(),
+    })
+}
@@ -0,0 +1,158 @@
+//! `RELA` relocation processing for position-independent (`ET_DYN`) images.
+
+use crate::{read_u16, read_u64, ElfError, ElfFile};
+
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_SYMENT: u64 = 11;
+const DT_REL: u64 = 17;
+const DT_PLTREL: u64 = 20;
+const DT_JMPREL: u64 = 23;
+
+const DYN_ENTRY_SIZE: usize = 16;
+const RELA_ENTRY_SIZE: u64 = 24;
+const SYM_ENTRY_SIZE: u64 = 24;
+const SHN_UNDEF: u16 = 0;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// The parts of `PT_DYNAMIC` the loader cares about.
+#[derive(Debug, Default)]
+struct DynamicInfo {
+    rela: Option<u64>,
+    rela_size: u64,
+    rela_entry_size: Option<u64>,
+    jmprel: Option<u64>,
+    pltrel_size: u64,
+    pltrel: Option<u64>,
+    symtab: Option<u64>,
+    sym_entry_size: Option<u64>,
+}
+
+impl DynamicInfo {
+    fn parse(elf: &ElfFile, offset: u64, size: u64) -> Result<Self, ElfError> {
+        let table = &elf.data[offset as usize..(offset + size) as usize];
+        let mut info = DynamicInfo::default();
+        for entry in table.chunks_exact(DYN_ENTRY_SIZE) {
+            let tag = read_u64(entry, 0);
+            let value = read_u64(entry, 8);
+            match tag {
+                DT_NULL => break,
+                DT_RELA => info.rela = Some(value),
+                DT_RELASZ => info.rela_size = value,
+                DT_RELAENT => info.rela_entry_size = Some(value),
+                DT_JMPREL => info.jmprel = Some(value),
+                DT_PLTRELSZ => info.pltrel_size = value,
+                DT_PLTREL => info.pltrel = Some(value),
+                DT_SYMTAB => info.symtab = Some(value),
+                DT_SYMENT => info.sym_entry_size = Some(value),
+                DT_REL => return Err(ElfError::RelNotSupported),
+                _ => {}
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// Applies every relocation of `elf` to `image`, the loaded copy of the file
+/// whose first byte will run at `link_base + offset` instead of `link_base`.
+pub(crate) fn relocate(elf: &ElfFile, image: &mut [u8], link_base: u64, offset: u64) -> Result<(), ElfError> {
+    let Some((dyn_offset, dyn_size)) = elf.dynamic else {
+        return Ok(());
+    };
+    let info = DynamicInfo::parse(elf, dyn_offset, dyn_size)?;
+
+    if let Some(size) = info.rela_entry_size {
+        if size != RELA_ENTRY_SIZE {
+            return Err(ElfError::BadRelocationEntrySize(size));
+        }
+    }
+    if let Some(size) = info.sym_entry_size {
+        if size != SYM_ENTRY_SIZE {
+            return Err(ElfError::BadSymbolEntrySize(size));
+        }
+    }
+
+    if let Some(rela) = info.rela {
+        apply_table(elf, &info, image, link_base, offset, rela, info.rela_size)?;
+    }
+    if let Some(jmprel) = info.jmprel {
+        if info.pltrel != Some(DT_RELA) {
+            return Err(ElfError::RelNotSupported);
+        }
+        apply_table(elf, &info, image, link_base, offset, jmprel, info.pltrel_size)?;
+    }
+    Ok(())
+}
+
+fn apply_table(
+    elf: &ElfFile,
+    info: &DynamicInfo,
+    image: &mut [u8],
+    link_base: u64,
+    offset: u64,
+    table_vaddr: u64,
+    table_size: u64,
+) -> Result<(), ElfError> {
+    if table_size == 0 {
+        return Ok(());
+    }
+    let table_offset = elf
+        .file_offset(table_vaddr, table_size)
+        .ok_or(ElfError::DynamicOutOfBounds(table_vaddr))?;
+    let table = &elf.data[table_offset..table_offset + table_size as usize];
+
+    for rela in table.chunks_exact(RELA_ENTRY_SIZE as usize) {
+        let r_offset = read_u64(rela, 0);
+        let r_info = read_u64(rela, 8);
+        let addend = read_u64(rela, 16);
+        let ty = r_info as u32;
+        let symbol = (r_info >> 32) as u32;
+
+        let value = match ty {
+            R_X86_64_NONE => continue,
+            R_X86_64_RELATIVE => offset.wrapping_add(addend),
+            R_X86_64_64 => symbol_value(elf, info, symbol, r_offset)?
+                .wrapping_add(offset)
+                .wrapping_add(addend),
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => {
+                symbol_value(elf, info, symbol, r_offset)?.wrapping_add(offset)
+            }
+            _ => return Err(ElfError::UnsupportedRelocation { ty, offset: r_offset }),
+        };
+
+        let target = r_offset
+            .checked_sub(link_base)
+            .filter(|&target| target.checked_add(8).is_some_and(|end| end <= image.len() as u64))
+            .ok_or(ElfError::RelocationOutOfBounds(r_offset))? as usize;
+        image[target..target + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Link-time value of dynamic symbol `index`, which must be defined in the image.
+fn symbol_value(elf: &ElfFile, info: &DynamicInfo, index: u32, r_offset: u64) -> Result<u64, ElfError> {
+    let symtab = info.symtab.ok_or(ElfError::UndefinedSymbol { index, offset: r_offset })?;
+    let sym_vaddr = (index as u64)
+        .checked_mul(SYM_ENTRY_SIZE)
+        .and_then(|offset| offset.checked_add(symtab))
+        .ok_or(ElfError::DynamicOutOfBounds(symtab))?;
+    let sym_offset = elf
+        .file_offset(sym_vaddr, SYM_ENTRY_SIZE)
+        .ok_or(ElfError::DynamicOutOfBounds(sym_vaddr))?;
+    let sym = &elf.data[sym_offset..sym_offset + SYM_ENTRY_SIZE as usize];
+
+    // Elf64_Sym: st_name, st_info, st_other, st_shndx, st_value, st_size
+    if read_u16(sym, 6) == SHN_UNDEF {
+        return Err(ElfError::UndefinedSymbol { index, offset: r_offset });
+    }
+    Ok(read_u64(sym, 8))
+}
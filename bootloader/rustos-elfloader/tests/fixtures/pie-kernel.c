@@ -0,0 +1,14 @@
+/* Position-independent test kernel for the loader's relocation tests. */
+int counter = 42;
+/* Preemptible symbol in a shared object: R_X86_64_64 against `counter` */
+int *counter_ptr = &counter;
+
+static const char message[] = "RustOS PIE";
+/* Local symbol: R_X86_64_RELATIVE */
+const char *message_ptr = message;
+
+void _start(void)
+{
+    for (;;)
+        __asm__ volatile("hlt");
+}
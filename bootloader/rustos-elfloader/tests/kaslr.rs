@@ -0,0 +1,84 @@
+use std::collections::BTreeSet;
+
+use rustos_elfloader::kaslr::{random_slot, virtual_slide, KASLR_ALIGN, VIRTUAL_WINDOW};
+
+/// End of a 2 MiB kernel linked at -2 GiB, like the kernel code model wants.
+const KERNEL_END: u64 = 0xffff_ffff_8020_0000;
+
+#[test]
+fn virtual_slide_is_aligned_and_within_the_window() {
+    let slides: BTreeSet<u64> = (0..4096).map(|random| virtual_slide(random, KERNEL_END)).collect();
+    for &slide in &slides {
+        assert_eq!(slide % KASLR_ALIGN, 0, "slide 0x{:x}", slide);
+        assert!(slide < VIRTUAL_WINDOW, "slide 0x{:x}", slide);
+    }
+    // Every 2 MiB step in the 1 GiB window is used
+    assert_eq!(slides.len() as u64, VIRTUAL_WINDOW / KASLR_ALIGN);
+    assert_eq!(slides.last(), Some(&(VIRTUAL_WINDOW - KASLR_ALIGN)));
+}
+
+#[test]
+fn virtual_slide_never_wraps_past_the_top() {
+    for link_end in [
+        0xffff_ffff_ff80_0000,
+        0xffff_ffff_ffe0_0000,
+        0xffff_ffff_ffff_f000,
+        0xffff_ffff_ffc0_1000,
+        // Ends exactly at the top of the address space
+        0,
+    ] {
+        let slides: BTreeSet<u64> = (0..64).map(|random| virtual_slide(random, link_end)).collect();
+        for &slide in &slides {
+            assert_eq!(slide % KASLR_ALIGN, 0);
+            assert!(
+                link_end.wrapping_sub(1).checked_add(slide).is_some(),
+                "image ending at 0x{:x} slid by 0x{:x}",
+                link_end,
+                slide
+            );
+        }
+        let expected = match link_end {
+            // 8 MiB of room
+            0xffff_ffff_ff80_0000 => 4,
+            // Just under 4 MiB
+            0xffff_ffff_ffc0_1000 => 2,
+            _ => 1,
+        };
+        assert_eq!(slides.len(), expected, "image ending at 0x{:x}", link_end);
+    }
+}
+
+#[test]
+fn random_slot_covers_every_fitting_address() {
+    // Low memory, a 126 MiB range and a range too small for the image
+    let ranges = [(0x1000, 0x9_f000), (0x10_0000, 0x800_0000), (0x1000_0000, 0x1030_0000)];
+    let size = 0x40_0000;
+
+    let slots: BTreeSet<u64> = (0..1000).filter_map(|random| random_slot(ranges.iter().copied(), size, random)).collect();
+    // 0x20_0000 up to 0x7c0_0000 in the second range
+    assert_eq!(slots.len(), 62);
+    assert_eq!(slots.first(), Some(&0x20_0000));
+    assert_eq!(slots.last(), Some(&0x7c0_0000));
+    for &slot in &slots {
+        assert_eq!(slot % KASLR_ALIGN, 0);
+        assert!(ranges.iter().any(|&(start, end)| slot >= start && slot + size <= end), "0x{:x}", slot);
+    }
+}
+
+#[test]
+fn random_slot_skips_address_zero() {
+    let ranges = [(0, 0x80_0000)];
+    let slots: BTreeSet<u64> = (0..16).filter_map(|random| random_slot(ranges.iter().copied(), KASLR_ALIGN, random)).collect();
+    assert_eq!(slots, BTreeSet::from([0x20_0000, 0x40_0000, 0x60_0000]));
+}
+
+#[test]
+fn random_slot_without_room() {
+    assert_eq!(random_slot([].into_iter(), KASLR_ALIGN, 7), None);
+    assert_eq!(random_slot([(0x20_0000, 0x3f_f000)].into_iter(), KASLR_ALIGN, 7), None);
+    // Unaligned ranges that hold the size but no aligned start for it
+    assert_eq!(random_slot([(0x20_1000, 0x60_0000)].into_iter(), 0x30_0000, 7), None);
+    // Ranges up against the top of the address space don't overflow
+    assert_eq!(random_slot([(u64::MAX - 0xfff, u64::MAX)].into_iter(), KASLR_ALIGN, 7), None);
+    assert_eq!(random_slot([(0xffff_ffff_ffe0_0000, u64::MAX)].into_iter(), KASLR_ALIGN, 7), None);
+}
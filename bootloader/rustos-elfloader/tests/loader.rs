@@ -0,0 +1,380 @@
+use rustos_elfloader::{load, load_with_slide, ElfError, ElfFile, ElfType, Placement, SegmentAllocator, PAGE_SIZE, PF_R, PF_X};
+
+const TEST_KERNEL: &[u8] = include_bytes!("fixtures/test-kernel");
+const MINIMAL_KERNEL: &[u8] = include_bytes!("fixtures/minimal-kernel");
+const PIE_KERNEL: &[u8] = include_bytes!("fixtures/pie-kernel");
+const HIGHER_HALF_PIE_KERNEL: &[u8] = include_bytes!("fixtures/higher-half-pie-kernel");
+
+/// Refuse anything bigger than this so fuzzed headers can't exhaust host memory.
+const MAX_PAGES: u64 = 4096;
+
+/// `Vec`-backed stand-in for `BootServices::allocate_pages`.
+struct HostMemory {
+    regions: Vec<(u64, Vec<u8>)>,
+    allow_fixed: bool,
+    next_any: u64,
+}
+
+impl HostMemory {
+    fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            allow_fixed: true,
+            next_any: 0x4000_0000,
+        }
+    }
+
+    fn without_fixed() -> Self {
+        Self {
+            allow_fixed: false,
+            ..Self::new()
+        }
+    }
+
+    fn read(&self, phys_addr: u64, len: usize) -> &[u8] {
+        let (base, bytes) = self
+            .regions
+            .iter()
+            .find(|(base, bytes)| phys_addr >= *base && phys_addr + len as u64 <= base + bytes.len() as u64)
+            .expect("read outside allocated memory");
+        let start = (phys_addr - base) as usize;
+        &bytes[start..start + len]
+    }
+}
+
+impl SegmentAllocator for HostMemory {
+    fn allocate(&mut self, placement: Placement, pages: u64) -> Option<u64> {
+        if pages > MAX_PAGES {
+            return None;
+        }
+        let addr = match placement {
+            Placement::At(addr) if self.allow_fixed => addr,
+            Placement::At(_) => return None,
+            Placement::Anywhere => {
+                let addr = self.next_any;
+                self.next_any += pages * PAGE_SIZE;
+                addr
+            }
+        };
+        assert_eq!(addr % PAGE_SIZE, 0, "allocation must be page aligned");
+        self.regions.push((addr, vec![0xAA; (pages * PAGE_SIZE) as usize]));
+        Some(addr)
+    }
+
+    fn memory(&mut self, phys_addr: u64, len: usize) -> &mut [u8] {
+        let (base, bytes) = self
+            .regions
+            .iter_mut()
+            .find(|(base, bytes)| phys_addr >= *base && phys_addr + len as u64 <= *base + bytes.len() as u64)
+            .expect("loader touched memory it didn't allocate");
+        let start = (phys_addr - *base) as usize;
+        &mut bytes[start..start + len]
+    }
+}
+
+/// Builds a minimal ELF64 executable with the given `(vaddr, filesz, memsz)`
+/// PT_LOAD segments. Segment contents are filled with the segment index.
+fn build_elf(entry: u64, segments: &[(u64, u64, u64)]) -> Vec<u8> {
+    let ph_offset = 64u64;
+    let data_offset = ph_offset + 56 * segments.len() as u64;
+    let mut elf = vec![0u8; data_offset as usize];
+
+    elf[0..4].copy_from_slice(b"\x7fELF");
+    elf[4] = 2; // ELFCLASS64
+    elf[5] = 1; // little-endian
+    elf[6] = 1; // EV_CURRENT
+    elf[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    elf[18..20].copy_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+    elf[20..24].copy_from_slice(&1u32.to_le_bytes());
+    elf[24..32].copy_from_slice(&entry.to_le_bytes());
+    elf[32..40].copy_from_slice(&ph_offset.to_le_bytes());
+    elf[52..54].copy_from_slice(&64u16.to_le_bytes());
+    elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+    elf[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+    let mut offset = data_offset;
+    for (i, &(vaddr, filesz, memsz)) in segments.iter().enumerate() {
+        let ph = &mut elf[(ph_offset as usize + i * 56)..(ph_offset as usize + (i + 1) * 56)];
+        ph[0..4].copy_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        ph[4..8].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+        ph[8..16].copy_from_slice(&offset.to_le_bytes());
+        ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        ph[24..32].copy_from_slice(&vaddr.to_le_bytes());
+        ph[32..40].copy_from_slice(&filesz.to_le_bytes());
+        ph[40..48].copy_from_slice(&memsz.to_le_bytes());
+        offset += filesz;
+    }
+    for (i, &(_, filesz, _)) in segments.iter().enumerate() {
+        elf.extend(std::iter::repeat_n(i as u8 + 1, filesz as usize));
+    }
+    elf
+}
+
+#[test]
+fn loads_test_kernel_fixture() {
+    let elf = ElfFile::parse(TEST_KERNEL).unwrap();
+    assert_eq!(elf.elf_type(), ElfType::Executable);
+    assert!(!elf.segments().is_empty());
+
+    let mut memory = HostMemory::new();
+    let image = load(&elf, &mut memory).unwrap();
+    assert_eq!(image.relocation_offset, 0);
+    assert_eq!(image.phys_base, 0x100000);
+    assert_eq!(image.entry, elf.entry());
+
+    for segment in elf.segments() {
+        let loaded = memory.read(segment.vaddr, segment.memsz as usize);
+        let file = &TEST_KERNEL[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+        assert_eq!(&loaded[..file.len()], file);
+        assert!(loaded[file.len()..].iter().all(|&b| b == 0), "bss must be zeroed");
+    }
+}
+
+#[test]
+fn loads_minimal_kernel_fixture() {
+    let elf = ElfFile::parse(MINIMAL_KERNEL).unwrap();
+    assert_eq!(elf.entry(), 0x100000);
+    assert_eq!(elf.image_range(), (0x100000, 0x101000));
+
+    let mut memory = HostMemory::new();
+    let image = load(&elf, &mut memory).unwrap();
+    assert_eq!(image.pages, 1);
+    // mov $0xb8000, %rdi
+    assert_eq!(&memory.read(0x100000, 3), &[0x48, 0xc7, 0xc7]);
+}
+
+#[test]
+fn refuses_to_move_fixed_address_kernel() {
+    let elf = ElfFile::parse(MINIMAL_KERNEL).unwrap();
+    let mut memory = HostMemory::without_fixed();
+    assert_eq!(
+        load(&elf, &mut memory).unwrap_err(),
+        ElfError::FixedAddressUnavailable { addr: 0x100000, pages: 1 }
+    );
+}
+
+#[test]
+fn places_higher_half_kernel_anywhere() {
+    let data = build_elf(0xffff_ffff_8000_0100, &[(0xffff_ffff_8000_0000, 0x200, 0x3000)]);
+    let elf = ElfFile::parse(&data).unwrap();
+    let mut memory = HostMemory::without_fixed();
+    let image = load(&elf, &mut memory).unwrap();
+    assert_eq!(image.phys_base, 0x4000_0000);
+    assert_eq!(image.virt_base, 0xffff_ffff_8000_0000);
+    assert_eq!(image.relocation_offset, 0);
+    assert_eq!(image.entry, 0xffff_ffff_8000_0100);
+    assert!(!image.is_identity_mapped());
+    assert_eq!(image.phys_addr(0xffff_ffff_8000_2000), 0x4000_2000);
+    assert!(memory.read(0x4000_0000, 0x200).iter().all(|&b| b == 1));
+}
+
+/// Returns the image bytes of `PIE_KERNEL` loaded at `base`.
+fn load_pie_at(base: u64) -> (rustos_elfloader::LoadedImage, Vec<u8>) {
+    let elf = ElfFile::parse(PIE_KERNEL).unwrap();
+    let mut memory = HostMemory::without_fixed();
+    memory.next_any = base;
+    let image = load(&elf, &mut memory).unwrap();
+    let bytes = memory.read(image.phys_base, (image.pages * PAGE_SIZE) as usize).to_vec();
+    (image, bytes)
+}
+
+#[test]
+fn relocates_pie_kernel() {
+    let elf = ElfFile::parse(PIE_KERNEL).unwrap();
+    assert_eq!(elf.elf_type(), ElfType::SharedObject);
+
+    let (image, bytes) = load_pie_at(0x4000_0000);
+    assert_eq!(image.phys_base, 0x4000_0000);
+    assert_eq!(image.virt_base, 0x4000_0000);
+    assert_eq!(image.relocation_offset, 0x4000_0000);
+    assert_eq!(image.entry, elf.entry() + 0x4000_0000);
+
+    // Every slot the relocations patched must point back into the image, at
+    // `counter` (R_X86_64_64) and `message` (R_X86_64_RELATIVE)
+    let (_, unrelocated) = load_pie_at(0);
+    let mut targets = Vec::new();
+    for offset in (0..bytes.len()).step_by(8) {
+        let value = u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let original = u64::from_le_bytes(unrelocated[offset..offset + 8].try_into().unwrap());
+        if value != original {
+            assert_eq!(value - original, 0x4000_0000, "slot 0x{:x} moved by the wrong amount", offset);
+            targets.push((value - image.virt_base) as usize);
+        }
+    }
+    assert_eq!(targets.len(), 2);
+    assert!(targets.iter().any(|&t| bytes[t..t + 4] == 42u32.to_le_bytes()));
+    assert!(targets.iter().any(|&t| &bytes[t..t + 10] == b"RustOS PIE"));
+}
+
+#[test]
+fn rejects_pie_relocation_outside_image() {
+    let mut data = PIE_KERNEL.to_vec();
+    // The first .rela.dyn entry (file offset 0x300, see `readelf -r`) is the
+    // R_X86_64_RELATIVE one; point it past the end of the image
+    let rela = 0x300;
+    assert_eq!(data[rela + 8..rela + 16], 8u64.to_le_bytes());
+    data[rela..rela + 8].copy_from_slice(&0x10_0000u64.to_le_bytes());
+    let elf = ElfFile::parse(&data).unwrap();
+    let mut memory = HostMemory::new();
+    assert_eq!(load(&elf, &mut memory).unwrap_err(), ElfError::RelocationOutOfBounds(0x10_0000));
+}
+
+#[test]
+fn slides_higher_half_pie_kernel() {
+    let elf = ElfFile::parse(HIGHER_HALF_PIE_KERNEL).unwrap();
+    assert!(elf.can_slide());
+    assert!(!ElfFile::parse(PIE_KERNEL).unwrap().can_slide());
+
+    let slide = 0x2340_0000;
+    let mut memory = HostMemory::without_fixed();
+    let image = load_with_slide(&elf, &mut memory, slide).unwrap();
+    assert_eq!(image.phys_base, 0x4000_0000);
+    assert_eq!(image.link_base(), 0xffff_ffff_8000_0000);
+    assert_eq!(image.virt_base, 0xffff_ffff_8000_0000 + slide);
+    assert_eq!(image.relocation_offset, slide);
+    assert_eq!(image.entry, elf.entry() + slide);
+    assert_eq!(image.phys_addr(0xffff_ffff_8000_2000), 0x4000_2000);
+
+    // `message_ptr` (R_X86_64_RELATIVE) and `counter_ptr` (R_X86_64_64), see
+    // `nm`, must point at their targets' slid addresses
+    let slot = |vaddr: u64| u64::from_le_bytes(memory.read(image.phys_addr(vaddr), 8).try_into().unwrap());
+    assert_eq!(slot(0xffff_ffff_8000_3138), 0xffff_ffff_8000_2000 + slide);
+    assert_eq!(slot(0xffff_ffff_8000_3140), 0xffff_ffff_8000_3130 + slide);
+
+    let unslid = load(&elf, &mut HostMemory::without_fixed()).unwrap();
+    assert_eq!(unslid.virt_base, 0xffff_ffff_8000_0000);
+    assert_eq!(unslid.relocation_offset, 0);
+}
+
+#[test]
+fn rejects_bad_slides() {
+    let elf = ElfFile::parse(HIGHER_HALF_PIE_KERNEL).unwrap();
+    let mut memory = HostMemory::without_fixed();
+    assert_eq!(load_with_slide(&elf, &mut memory, 0x1234).unwrap_err(), ElfError::BadSlide(0x1234));
+    assert_eq!(
+        load_with_slide(&elf, &mut memory, 0x8000_0000).unwrap_err(),
+        ElfError::BadSlide(0x8000_0000)
+    );
+
+    let data = build_elf(0xffff_ffff_8000_0100, &[(0xffff_ffff_8000_0000, 0x200, 0x3000)]);
+    let exec = ElfFile::parse(&data).unwrap();
+    assert!(!exec.can_slide());
+    assert_eq!(load_with_slide(&exec, &mut memory, 0x20_0000).unwrap_err(), ElfError::NotRelocatable);
+    let pie = ElfFile::parse(PIE_KERNEL).unwrap();
+    assert_eq!(load_with_slide(&pie, &mut memory, 0x20_0000).unwrap_err(), ElfError::NotRelocatable);
+}
+
+#[test]
+fn zeroes_bss_and_gaps() {
+    let data = build_elf(0x200000, &[(0x200000, 0x10, 0x2000), (0x203000, 0x8, 0x8)]);
+    let elf = ElfFile::parse(&data).unwrap();
+    let mut memory = HostMemory::new();
+    let image = load(&elf, &mut memory).unwrap();
+    assert_eq!(image.pages, 4);
+    let bytes = memory.read(0x200000, 0x4000);
+    assert!(bytes[..0x10].iter().all(|&b| b == 1));
+    assert!(bytes[0x10..0x3000].iter().all(|&b| b == 0));
+    assert!(bytes[0x3000..0x3008].iter().all(|&b| b == 2));
+    assert!(bytes[0x3008..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn rejects_bad_headers() {
+    let good = build_elf(0x100000, &[(0x100000, 0x10, 0x10)]);
+
+    let mut bad = good.clone();
+    bad[0] = 0;
+    assert_eq!(ElfFile::parse(&bad).unwrap_err(), ElfError::BadMagic);
+
+    let mut bad = good.clone();
+    bad[4] = 1;
+    assert_eq!(ElfFile::parse(&bad).unwrap_err(), ElfError::UnsupportedClass(1));
+
+    let mut bad = good.clone();
+    bad[5] = 2;
+    assert_eq!(ElfFile::parse(&bad).unwrap_err(), ElfError::UnsupportedEndianness(2));
+
+    let mut bad = good.clone();
+    bad[16] = 1; // ET_REL
+    assert_eq!(ElfFile::parse(&bad).unwrap_err(), ElfError::UnsupportedType(1));
+
+    let mut bad = good.clone();
+    bad[18] = 3; // EM_386
+    assert_eq!(ElfFile::parse(&bad).unwrap_err(), ElfError::UnsupportedMachine(3));
+
+    let mut bad = good.clone();
+    bad[54] = 32;
+    assert_eq!(ElfFile::parse(&bad).unwrap_err(), ElfError::BadProgramHeaderSize(32));
+}
+
+#[test]
+fn rejects_bad_segments() {
+    let data = build_elf(0x100000, &[(0x100000, 0x20, 0x10)]);
+    assert_eq!(
+        ElfFile::parse(&data).unwrap_err(),
+        ElfError::FileSizeExceedsMemSize { index: 0, filesz: 0x20, memsz: 0x10 }
+    );
+
+    let data = build_elf(0x100000, &[(0x100000, 0x10, 0x1000), (0x100800, 0x10, 0x10)]);
+    assert_eq!(ElfFile::parse(&data).unwrap_err(), ElfError::OverlappingSegments { first: 0, second: 1 });
+
+    let data = build_elf(0xffff_ffff_ffff_f000, &[(0xffff_ffff_ffff_f000, 0x10, 0x2000)]);
+    assert!(matches!(ElfFile::parse(&data).unwrap_err(), ElfError::AddressOverflow { index: 0, .. }));
+
+    let data = build_elf(0x300000, &[(0x100000, 0x10, 0x10)]);
+    assert_eq!(ElfFile::parse(&data).unwrap_err(), ElfError::EntryOutsideSegments(0x300000));
+
+    let data = build_elf(0x100000, &[]);
+    assert_eq!(ElfFile::parse(&data).unwrap_err(), ElfError::NoLoadableSegments);
+}
+
+#[test]
+fn rejects_truncated_files() {
+    // Anything past the last segment's file data (section headers etc.) isn't needed
+    let elf = ElfFile::parse(TEST_KERNEL).unwrap();
+    let needed = elf.segments().iter().map(|s| s.offset + s.filesz).max().unwrap() as usize;
+
+    for len in 0..needed {
+        let truncated = &TEST_KERNEL[..len];
+        let err = ElfFile::parse(truncated).expect_err("truncated file must not parse");
+        assert!(
+            matches!(
+                err,
+                ElfError::TooSmall { .. }
+                    | ElfError::ProgramHeadersOutOfBounds { .. }
+                    | ElfError::SegmentOutOfBounds { .. }
+            ),
+            "unexpected error {:?} at length {}",
+            err,
+            len
+        );
+    }
+}
+
+#[test]
+fn survives_fuzzed_inputs() {
+    // xorshift64, so failures are reproducible
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for fixture in [TEST_KERNEL, MINIMAL_KERNEL, PIE_KERNEL] {
+        for _ in 0..2000 {
+            let mut data = fixture.to_vec();
+            // Bias mutations towards the headers, where the interesting fields are
+            for _ in 0..(next() % 8 + 1) {
+                let limit = if next() % 4 == 0 { data.len() } else { 64 + 56 * 4 };
+                let index = (next() as usize) % limit.min(data.len());
+                data[index] = next() as u8;
+            }
+            if let Ok(elf) = ElfFile::parse(&data) {
+                let mut memory = HostMemory::new();
+                let _ = load(&elf, &mut memory);
+            }
+        }
+    }
+}
@@ -0,0 +1,689 @@
+//! Multiboot2 kernels: finding the header, working out where the image goes
+//! and building the boot information structure (MBI) the kernel gets in
+//! `ebx`.
+//!
+//! Only what makes sense after `ExitBootServices` is supported. Header tags
+//! asking for boot services, an EFI entry point or information we don't
+//! provide make the kernel unbootable unless they are marked optional.
+//! `multiboot2_boot.rs` in the bootloader does the loading and the jump;
+//! everything here only works on byte slices, so the host tests can check it.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::fmt;
+use rustos_bootinfo::{FramebufferInfo, MemoryDescriptor, MemoryRegionKind};
+
+pub const HEADER_MAGIC: u32 = 0xe852_50d6;
+/// Value of `eax` when the kernel is entered.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+/// The header, tags and all, must be within the first 32 KiB of the file,
+/// starting at a multiple of 8.
+pub const HEADER_SEARCH_LIMIT: usize = 32 * 1024;
+const HEADER_ALIGN: usize = 8;
+/// Magic, architecture, header length and checksum.
+const HEADER_FIXED_SIZE: usize = 16;
+const ARCHITECTURE_I386: u32 = 0;
+/// Header tag flag: the kernel also boots if the tag is ignored.
+const TAG_OPTIONAL: u16 = 1;
+/// Header and MBI tags are padded to this.
+const TAG_ALIGN: usize = 8;
+const TAG_HEADER_SIZE: usize = 8;
+
+// Header tag types
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFORMATION_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+const HEADER_TAG_FRAMEBUFFER: u16 = 5;
+const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+const HEADER_TAG_RELOCATABLE: u16 = 10;
+
+// MBI tag types
+const INFO_END: u32 = 0;
+const INFO_COMMAND_LINE: u32 = 1;
+const INFO_BOOT_LOADER_NAME: u32 = 2;
+const INFO_MODULE: u32 = 3;
+const INFO_BASIC_MEMORY: u32 = 4;
+const INFO_MEMORY_MAP: u32 = 6;
+const INFO_FRAMEBUFFER: u32 = 8;
+const INFO_EFI64_SYSTEM_TABLE: u32 = 12;
+const INFO_ACPI_OLD: u32 = 14;
+const INFO_ACPI_NEW: u32 = 15;
+const INFO_EFI_MEMORY_MAP: u32 = 17;
+const INFO_LOAD_BASE_ADDR: u32 = 21;
+
+/// MBI tags an information request may ask for without being refused.
+const PROVIDED_INFO: [u32; 11] = [
+    INFO_COMMAND_LINE,
+    INFO_BOOT_LOADER_NAME,
+    INFO_MODULE,
+    INFO_BASIC_MEMORY,
+    INFO_MEMORY_MAP,
+    INFO_FRAMEBUFFER,
+    INFO_EFI64_SYSTEM_TABLE,
+    INFO_ACPI_OLD,
+    INFO_ACPI_NEW,
+    INFO_EFI_MEMORY_MAP,
+    INFO_LOAD_BASE_ADDR,
+];
+
+// Memory map entry types
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_RESERVED: u32 = 2;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_NVS: u32 = 4;
+const MEMORY_MAP_ENTRY_SIZE: usize = 24;
+
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+/// Common framebuffer fields plus the RGB field positions and sizes.
+const FRAMEBUFFER_TAG_SIZE: usize = 38;
+
+const EFI_MEMORY_DESCRIPTOR_VERSION: u32 = 1;
+const EFI_PAGE_SIZE: u64 = 0x1000;
+
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+const RSDP_REVISION_OFFSET: usize = 15;
+
+const LOWER_MEMORY_END: u64 = 640 * 1024;
+const UPPER_MEMORY_START: u64 = 0x10_0000;
+/// Everything the kernel is handed has to be reachable from 32-bit code.
+const ADDRESS_LIMIT: u64 = 1 << 32;
+
+const ELF_CLASS_32: u8 = 1;
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_386: u16 = 3;
+const ELF_MACHINE_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Multiboot2Error {
+    UnsupportedArchitecture(u32),
+    /// The header or one of its tags runs past the header length or the
+    /// 32 KiB search window.
+    TruncatedHeader,
+    BadTagSize { ty: u16, size: u32 },
+    /// A required header tag we can't honour.
+    UnsupportedTag(u16),
+    /// A required information request for an MBI tag we don't provide.
+    UnsupportedInformation(u32),
+    /// The address tag doesn't describe a range of the file.
+    BadAddressTag,
+    /// There is an address tag but no entry address tag.
+    MissingEntryAddress,
+    /// Without an address tag the kernel has to be an ELF executable.
+    NotElf,
+    UnsupportedElf { class: u8, machine: u16, ty: u16 },
+    ElfOutOfBounds,
+    NoLoadableSegments,
+    AboveAddressLimit { addr: u64, size: u64 },
+}
+
+impl fmt::Display for Multiboot2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Multiboot2Error::UnsupportedArchitecture(architecture) => {
+                write!(f, "architecture {} is not i386", architecture)
+            }
+            Multiboot2Error::TruncatedHeader => write!(f, "header runs past its length or the first 32 KiB"),
+            Multiboot2Error::BadTagSize { ty, size } => write!(f, "header tag {} has bad size {}", ty, size),
+            Multiboot2Error::UnsupportedTag(ty) => write!(f, "required header tag {} is not supported", ty),
+            Multiboot2Error::UnsupportedInformation(ty) => {
+                write!(f, "kernel requires boot information tag {}, which is not provided", ty)
+            }
+            Multiboot2Error::BadAddressTag => write!(f, "address tag doesn't match the file"),
+            Multiboot2Error::MissingEntryAddress => write!(f, "address tag without an entry address tag"),
+            Multiboot2Error::NotElf => write!(f, "no address tag and not an ELF file"),
+            Multiboot2Error::UnsupportedElf { class, machine, ty } => write!(
+                f,
+                "ELF class {}, machine {}, type {} is not an i386 or x86_64 executable",
+                class, machine, ty
+            ),
+            Multiboot2Error::ElfOutOfBounds => write!(f, "ELF headers or segments run past the end of the file"),
+            Multiboot2Error::NoLoadableSegments => write!(f, "no PT_LOAD segments"),
+            Multiboot2Error::AboveAddressLimit { addr, size } => {
+                write!(f, "0x{:x} bytes at 0x{:x} don't fit below 4 GiB", size, addr)
+            }
+        }
+    }
+}
+
+/// Where an a.out-style image goes, from the address tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressTag {
+    /// Physical address the Multiboot2 header is loaded at.
+    pub header_addr: u32,
+    /// Physical address of the first byte of the image.
+    pub load_addr: u32,
+    /// End of the file data, or 0 for the rest of the file.
+    pub load_end_addr: u32,
+    /// End of the bss, or 0 if there is none.
+    pub bss_end_addr: u32,
+}
+
+/// Preferred video mode from the framebuffer tag; 0 means no preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferRequest {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+/// The header tags that change how the kernel is booted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// File offset of the header.
+    pub offset: usize,
+    pub address: Option<AddressTag>,
+    pub entry: Option<u32>,
+    pub framebuffer: Option<FramebufferRequest>,
+}
+
+impl Header {
+    /// Looks for a Multiboot2 header in the first 32 KiB of `data`.
+    /// Returns `Ok(None)` if there is none, and an error if the kernel has
+    /// one that can't be honoured.
+    pub fn find(data: &[u8]) -> Result<Option<Header>, Multiboot2Error> {
+        let data = &data[..data.len().min(HEADER_SEARCH_LIMIT)];
+        for offset in (0..data.len()).step_by(HEADER_ALIGN) {
+            if offset + HEADER_FIXED_SIZE > data.len() {
+                break;
+            }
+            if read_u32(data, offset) != HEADER_MAGIC {
+                continue;
+            }
+            let architecture = read_u32(data, offset + 4);
+            let length = read_u32(data, offset + 8);
+            let checksum = read_u32(data, offset + 12);
+            // A magic without a matching checksum is just data
+            if HEADER_MAGIC.wrapping_add(architecture).wrapping_add(length).wrapping_add(checksum) != 0 {
+                continue;
+            }
+            if architecture != ARCHITECTURE_I386 {
+                return Err(Multiboot2Error::UnsupportedArchitecture(architecture));
+            }
+            return Self::parse_tags(data, offset, length as usize).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn parse_tags(data: &[u8], offset: usize, length: usize) -> Result<Header, Multiboot2Error> {
+        let end = match offset.checked_add(length) {
+            Some(end) if length >= HEADER_FIXED_SIZE && end <= data.len() => end,
+            _ => return Err(Multiboot2Error::TruncatedHeader),
+        };
+        let mut header = Header {
+            offset,
+            address: None,
+            entry: None,
+            framebuffer: None,
+        };
+
+        let mut position = offset + HEADER_FIXED_SIZE;
+        loop {
+            if position + TAG_HEADER_SIZE > end {
+                return Err(Multiboot2Error::TruncatedHeader);
+            }
+            let ty = read_u16(data, position);
+            let flags = read_u16(data, position + 2);
+            let size = read_u32(data, position + 4);
+            if (size as usize) < TAG_HEADER_SIZE || size as usize > end - position {
+                return Err(Multiboot2Error::BadTagSize { ty, size });
+            }
+            let body = &data[position + TAG_HEADER_SIZE..position + size as usize];
+            let optional = flags & TAG_OPTIONAL != 0;
+            let min_body = |len: usize| {
+                if body.len() < len {
+                    Err(Multiboot2Error::BadTagSize { ty, size })
+                } else {
+                    Ok(())
+                }
+            };
+
+            match ty {
+                HEADER_TAG_END => break,
+                HEADER_TAG_INFORMATION_REQUEST => {
+                    for request in body.chunks_exact(4).map(|bytes| read_u32(bytes, 0)) {
+                        if !optional && !PROVIDED_INFO.contains(&request) {
+                            return Err(Multiboot2Error::UnsupportedInformation(request));
+                        }
+                    }
+                }
+                HEADER_TAG_ADDRESS => {
+                    min_body(16)?;
+                    header.address = Some(AddressTag {
+                        header_addr: read_u32(body, 0),
+                        load_addr: read_u32(body, 4),
+                        load_end_addr: read_u32(body, 8),
+                        bss_end_addr: read_u32(body, 12),
+                    });
+                }
+                HEADER_TAG_ENTRY_ADDRESS => {
+                    min_body(4)?;
+                    header.entry = Some(read_u32(body, 0));
+                }
+                HEADER_TAG_FRAMEBUFFER => {
+                    min_body(12)?;
+                    header.framebuffer = Some(FramebufferRequest {
+                        width: read_u32(body, 0),
+                        height: read_u32(body, 4),
+                        depth: read_u32(body, 8),
+                    });
+                }
+                // There is always a framebuffer console if anything; modules
+                // are page aligned anyway; the preferred address is used
+                HEADER_TAG_CONSOLE_FLAGS | HEADER_TAG_MODULE_ALIGN | HEADER_TAG_RELOCATABLE => {}
+                // Boot services and EFI entry points among them
+                _ if optional => {}
+                _ => return Err(Multiboot2Error::UnsupportedTag(ty)),
+            }
+            position += align_up(size as usize, TAG_ALIGN);
+        }
+
+        if header.address.is_some() && header.entry.is_none() {
+            return Err(Multiboot2Error::MissingEntryAddress);
+        }
+        Ok(header)
+    }
+}
+
+/// File data that goes to a fixed physical address, followed by zeroes up
+/// to `memsz`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadSegment {
+    pub phys_addr: u64,
+    pub file_offset: usize,
+    pub filesz: usize,
+    pub memsz: u64,
+}
+
+impl LoadSegment {
+    pub fn end(&self) -> u64 {
+        self.phys_addr + self.memsz
+    }
+}
+
+/// Where the kernel's pieces go and where it starts, all below 4 GiB.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadPlan {
+    pub entry: u32,
+    /// Sorted by address.
+    pub segments: Vec<LoadSegment>,
+}
+
+impl LoadPlan {
+    /// Works out the load addresses from the address tag, or from the
+    /// `PT_LOAD` segments' physical addresses if there is none.
+    pub fn new(data: &[u8], header: &Header) -> Result<LoadPlan, Multiboot2Error> {
+        let mut plan = match header.address {
+            Some(address) => Self::from_address_tag(data, header, &address)?,
+            None => Self::from_elf(data, header)?,
+        };
+        plan.segments.sort_unstable_by_key(|segment| segment.phys_addr);
+        for segment in &plan.segments {
+            if segment.end() > ADDRESS_LIMIT {
+                return Err(Multiboot2Error::AboveAddressLimit {
+                    addr: segment.phys_addr,
+                    size: segment.memsz,
+                });
+            }
+        }
+        Ok(plan)
+    }
+
+    fn from_address_tag(data: &[u8], header: &Header, address: &AddressTag) -> Result<LoadPlan, Multiboot2Error> {
+        // The header's file offset and load address pin down everything else
+        let header_distance = address
+            .header_addr
+            .checked_sub(address.load_addr)
+            .ok_or(Multiboot2Error::BadAddressTag)?;
+        let file_offset = header
+            .offset
+            .checked_sub(header_distance as usize)
+            .ok_or(Multiboot2Error::BadAddressTag)?;
+        let filesz = match address.load_end_addr {
+            0 => data.len() - file_offset,
+            end => end.checked_sub(address.load_addr).ok_or(Multiboot2Error::BadAddressTag)? as usize,
+        };
+        if file_offset + filesz > data.len() {
+            return Err(Multiboot2Error::BadAddressTag);
+        }
+        let memsz = match address.bss_end_addr {
+            0 => filesz as u64,
+            end => match end.checked_sub(address.load_addr) {
+                Some(memsz) if memsz as usize >= filesz => memsz as u64,
+                _ => return Err(Multiboot2Error::BadAddressTag),
+            },
+        };
+
+        Ok(LoadPlan {
+            entry: header.entry.ok_or(Multiboot2Error::MissingEntryAddress)?,
+            segments: alloc::vec![LoadSegment {
+                phys_addr: address.load_addr as u64,
+                file_offset,
+                filesz,
+                memsz,
+            }],
+        })
+    }
+
+    fn from_elf(data: &[u8], header: &Header) -> Result<LoadPlan, Multiboot2Error> {
+        if data.len() < 52 || &data[0..4] != b"\x7fELF" {
+            return Err(Multiboot2Error::NotElf);
+        }
+        let class = data[4];
+        let ty = read_u16(data, 16);
+        let machine = read_u16(data, 18);
+        let is_64 = match (class, machine) {
+            (ELF_CLASS_32, ELF_MACHINE_386) => false,
+            (ELF_CLASS_64, ELF_MACHINE_X86_64) if data.len() >= 64 => true,
+            _ => return Err(Multiboot2Error::UnsupportedElf { class, machine, ty }),
+        };
+        if data[5] != ELF_DATA_LSB || ty != ELF_TYPE_EXEC {
+            return Err(Multiboot2Error::UnsupportedElf { class, machine, ty });
+        }
+
+        // (entry, phoff, phentsize, phnum) and the program header field offsets
+        let (elf_entry, ph_offset, ph_entry_size, ph_num) = if is_64 {
+            (read_u64(data, 24), read_u64(data, 32), read_u16(data, 54), read_u16(data, 56))
+        } else {
+            (
+                read_u32(data, 24) as u64,
+                read_u32(data, 28) as u64,
+                read_u16(data, 42),
+                read_u16(data, 44),
+            )
+        };
+        let min_entry_size = if is_64 { 56 } else { 32 };
+        if ph_num > 0 && (ph_entry_size as usize) < min_entry_size {
+            return Err(Multiboot2Error::ElfOutOfBounds);
+        }
+        let table_end = (ph_num as u64)
+            .checked_mul(ph_entry_size as u64)
+            .and_then(|size| size.checked_add(ph_offset));
+        if !matches!(table_end, Some(end) if end <= data.len() as u64) {
+            return Err(Multiboot2Error::ElfOutOfBounds);
+        }
+
+        let mut segments = Vec::new();
+        let mut entry = elf_entry;
+        for index in 0..ph_num as usize {
+            let ph = &data[ph_offset as usize + index * ph_entry_size as usize..];
+            if read_u32(ph, 0) != PT_LOAD {
+                continue;
+            }
+            let (offset, vaddr, paddr, filesz, memsz) = if is_64 {
+                (read_u64(ph, 8), read_u64(ph, 16), read_u64(ph, 24), read_u64(ph, 32), read_u64(ph, 40))
+            } else {
+                (
+                    read_u32(ph, 4) as u64,
+                    read_u32(ph, 8) as u64,
+                    read_u32(ph, 12) as u64,
+                    read_u32(ph, 16) as u64,
+                    read_u32(ph, 20) as u64,
+                )
+            };
+            if filesz > memsz || !matches!(offset.checked_add(filesz), Some(end) if end <= data.len() as u64) {
+                return Err(Multiboot2Error::ElfOutOfBounds);
+            }
+            if paddr.checked_add(memsz).is_none() {
+                return Err(Multiboot2Error::AboveAddressLimit { addr: paddr, size: memsz });
+            }
+            if memsz == 0 {
+                continue;
+            }
+            // Like GRUB: an entry point given as a virtual address is moved
+            // along with its segment
+            if elf_entry >= vaddr && elf_entry - vaddr < memsz {
+                entry = paddr + (elf_entry - vaddr);
+            }
+            segments.push(LoadSegment {
+                phys_addr: paddr,
+                file_offset: offset as usize,
+                filesz: filesz as usize,
+                memsz,
+            });
+        }
+        if segments.is_empty() {
+            return Err(Multiboot2Error::NoLoadableSegments);
+        }
+
+        let entry = match header.entry {
+            Some(entry) => entry,
+            None => u32::try_from(entry).map_err(|_| Multiboot2Error::AboveAddressLimit { addr: entry, size: 0 })?,
+        };
+        Ok(LoadPlan { entry, segments })
+    }
+
+    /// Lowest address the image occupies.
+    pub fn load_base(&self) -> u64 {
+        self.segments[0].phys_addr
+    }
+
+    /// Page ranges `[start, end)` covering the segments, with ranges that
+    /// share a page merged, so each can be allocated in one go.
+    pub fn page_ranges(&self, page_size: u64) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for segment in &self.segments {
+            let start = segment.phys_addr & !(page_size - 1);
+            let end = segment.end().div_ceil(page_size) * page_size;
+            match ranges.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => ranges.push((start, end)),
+            }
+        }
+        ranges
+    }
+}
+
+/// Multiboot2 memory map type for an `EFI_MEMORY_TYPE`. Like GRUB, the
+/// bootloader's own memory counts as available; the kernel is told where
+/// the MBI and modules are.
+fn memory_type(efi_type: u32) -> u32 {
+    match MemoryRegionKind::from_uefi(efi_type) {
+        MemoryRegionKind::Usable | MemoryRegionKind::Bootloader => MEMORY_AVAILABLE,
+        MemoryRegionKind::AcpiReclaimable => MEMORY_ACPI_RECLAIMABLE,
+        MemoryRegionKind::AcpiNvs => MEMORY_NVS,
+        _ => MEMORY_RESERVED,
+    }
+}
+
+/// Bytes of available memory from `start` up to the first gap.
+fn contiguous_available<'a>(descriptors: impl Iterator<Item = &'a MemoryDescriptor> + Clone, start: u64) -> u64 {
+    let mut end = start;
+    // The map isn't sorted, so keep looking for the descriptor that continues the run
+    while let Some(next) = descriptors.clone().find_map(|descriptor| {
+        let descriptor_end = descriptor.physical_start + descriptor.page_count * EFI_PAGE_SIZE;
+        let continues = memory_type(descriptor.ty) == MEMORY_AVAILABLE
+            && descriptor.physical_start <= end
+            && end < descriptor_end;
+        continues.then_some(descriptor_end)
+    }) {
+        end = next;
+    }
+    end - start
+}
+
+/// Writes the MBI into `buffer`, which must be 8-byte aligned, one tag at a
+/// time. Tags that don't fit are dropped; there is always room for the end
+/// tag [`InfoBuilder::finish`] adds.
+pub struct InfoBuilder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    dropped: usize,
+}
+
+impl<'a> InfoBuilder<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        assert!(buffer.len() >= 2 * TAG_HEADER_SIZE, "MBI buffer too small for the fixed part and end tag");
+        // Total size and a reserved field, filled in by finish()
+        buffer[..TAG_HEADER_SIZE].fill(0);
+        Self {
+            buffer,
+            len: TAG_HEADER_SIZE,
+            dropped: 0,
+        }
+    }
+
+    /// Number of tags that didn't fit.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Appends a tag of `size` bytes, header included, and returns its
+    /// zeroed body.
+    fn tag(&mut self, ty: u32, size: usize) -> Option<&mut [u8]> {
+        let padded = align_up(size, TAG_ALIGN);
+        if self.len + padded + TAG_HEADER_SIZE > self.buffer.len() {
+            self.dropped += 1;
+            return None;
+        }
+        let tag = &mut self.buffer[self.len..self.len + padded];
+        tag.fill(0);
+        tag[0..4].copy_from_slice(&ty.to_le_bytes());
+        tag[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+        self.len += padded;
+        Some(&mut tag[TAG_HEADER_SIZE..size])
+    }
+
+    /// A tag holding just a NUL-terminated string.
+    fn string_tag(&mut self, ty: u32, string: &str) {
+        if let Some(body) = self.tag(ty, TAG_HEADER_SIZE + string.len() + 1) {
+            body[..string.len()].copy_from_slice(string.as_bytes());
+        }
+    }
+
+    pub fn command_line(&mut self, cmdline: &str) {
+        self.string_tag(INFO_COMMAND_LINE, cmdline);
+    }
+
+    pub fn boot_loader_name(&mut self, name: &str) {
+        self.string_tag(INFO_BOOT_LOADER_NAME, name);
+    }
+
+    /// A module occupying `[start, end)`, with `string` as its command line.
+    pub fn module(&mut self, start: u32, end: u32, string: &str) {
+        if let Some(body) = self.tag(INFO_MODULE, TAG_HEADER_SIZE + 8 + string.len() + 1) {
+            body[0..4].copy_from_slice(&start.to_le_bytes());
+            body[4..8].copy_from_slice(&end.to_le_bytes());
+            body[8..8 + string.len()].copy_from_slice(string.as_bytes());
+        }
+    }
+
+    /// KiB of available memory from 0 (at most 640) and from 1 MiB up to
+    /// the first gap.
+    pub fn basic_memory_info<'d>(&mut self, descriptors: impl Iterator<Item = &'d MemoryDescriptor> + Clone) {
+        let lower = contiguous_available(descriptors.clone(), 0).min(LOWER_MEMORY_END) / 1024;
+        let upper = (contiguous_available(descriptors, UPPER_MEMORY_START) / 1024).min(u32::MAX as u64);
+        if let Some(body) = self.tag(INFO_BASIC_MEMORY, TAG_HEADER_SIZE + 8) {
+            body[0..4].copy_from_slice(&(lower as u32).to_le_bytes());
+            body[4..8].copy_from_slice(&(upper as u32).to_le_bytes());
+        }
+    }
+
+    pub fn memory_map<'d>(&mut self, descriptors: impl Iterator<Item = &'d MemoryDescriptor> + Clone) {
+        let size = TAG_HEADER_SIZE + 8 + descriptors.clone().count() * MEMORY_MAP_ENTRY_SIZE;
+        if let Some(body) = self.tag(INFO_MEMORY_MAP, size) {
+            body[0..4].copy_from_slice(&(MEMORY_MAP_ENTRY_SIZE as u32).to_le_bytes());
+            // body[4..8]: entry version 0
+            for (entry, descriptor) in body[8..].chunks_exact_mut(MEMORY_MAP_ENTRY_SIZE).zip(descriptors) {
+                entry[0..8].copy_from_slice(&descriptor.physical_start.to_le_bytes());
+                entry[8..16].copy_from_slice(&(descriptor.page_count * EFI_PAGE_SIZE).to_le_bytes());
+                entry[16..20].copy_from_slice(&memory_type(descriptor.ty).to_le_bytes());
+            }
+        }
+    }
+
+    /// A verbatim copy of the UEFI memory map, `stride` bytes per descriptor.
+    pub fn efi_memory_map(&mut self, descriptors: &[u8], stride: usize) {
+        if let Some(body) = self.tag(INFO_EFI_MEMORY_MAP, TAG_HEADER_SIZE + 8 + descriptors.len()) {
+            body[0..4].copy_from_slice(&(stride as u32).to_le_bytes());
+            body[4..8].copy_from_slice(&EFI_MEMORY_DESCRIPTOR_VERSION.to_le_bytes());
+            body[8..].copy_from_slice(descriptors);
+        }
+    }
+
+    /// Skipped if there is no linear framebuffer.
+    pub fn framebuffer(&mut self, framebuffer: &FramebufferInfo) {
+        if !framebuffer.is_available() {
+            return;
+        }
+        if let Some(body) = self.tag(INFO_FRAMEBUFFER, FRAMEBUFFER_TAG_SIZE) {
+            body[0..8].copy_from_slice(&framebuffer.addr.to_le_bytes());
+            body[8..12].copy_from_slice(&framebuffer.pitch.to_le_bytes());
+            body[12..16].copy_from_slice(&framebuffer.width.to_le_bytes());
+            body[16..20].copy_from_slice(&framebuffer.height.to_le_bytes());
+            body[20] = framebuffer.bpp as u8;
+            body[21] = FRAMEBUFFER_TYPE_RGB;
+            // Field position and size of each channel
+            for (i, mask) in [framebuffer.red_mask, framebuffer.green_mask, framebuffer.blue_mask]
+                .into_iter()
+                .enumerate()
+            {
+                body[24 + 2 * i] = if mask == 0 { 0 } else { mask.trailing_zeros() as u8 };
+                body[25 + 2 * i] = mask.count_ones() as u8;
+            }
+        }
+    }
+
+    pub fn efi64_system_table(&mut self, addr: u64) {
+        if let Some(body) = self.tag(INFO_EFI64_SYSTEM_TABLE, TAG_HEADER_SIZE + 8) {
+            body.copy_from_slice(&addr.to_le_bytes());
+        }
+    }
+
+    /// Copies of the RSDP: the 1.0 part, and the whole XSDP if `rsdp` is
+    /// revision 2 or later.
+    pub fn acpi_rsdp(&mut self, rsdp: &[u8]) {
+        if rsdp.len() < RSDP_V1_LEN {
+            return;
+        }
+        if let Some(body) = self.tag(INFO_ACPI_OLD, TAG_HEADER_SIZE + RSDP_V1_LEN) {
+            body.copy_from_slice(&rsdp[..RSDP_V1_LEN]);
+        }
+        if rsdp[RSDP_REVISION_OFFSET] >= 2 && rsdp.len() >= RSDP_V2_LEN {
+            if let Some(body) = self.tag(INFO_ACPI_NEW, TAG_HEADER_SIZE + rsdp.len()) {
+                body.copy_from_slice(rsdp);
+            }
+        }
+    }
+
+    pub fn load_base_addr(&mut self, addr: u32) {
+        if let Some(body) = self.tag(INFO_LOAD_BASE_ADDR, TAG_HEADER_SIZE + 4) {
+            body.copy_from_slice(&addr.to_le_bytes());
+        }
+    }
+
+    /// Adds the end tag and fills in the total size, which it returns.
+    pub fn finish(self) -> usize {
+        let end = &mut self.buffer[self.len..self.len + TAG_HEADER_SIZE];
+        end[0..4].copy_from_slice(&INFO_END.to_le_bytes());
+        end[4..8].copy_from_slice(&(TAG_HEADER_SIZE as u32).to_le_bytes());
+        let total = self.len + TAG_HEADER_SIZE;
+        self.buffer[0..4].copy_from_slice(&(total as u32).to_le_bytes());
+        total
+    }
+}
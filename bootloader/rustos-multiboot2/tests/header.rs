@@ -0,0 +1,300 @@
+use rustos_multiboot2::{AddressTag, FramebufferRequest, Header, LoadPlan, LoadSegment, Multiboot2Error, HEADER_MAGIC, HEADER_SEARCH_LIMIT};
+
+// Header tag types
+const INFORMATION_REQUEST: u16 = 1;
+const ADDRESS: u16 = 2;
+const ENTRY_ADDRESS: u16 = 3;
+const CONSOLE_FLAGS: u16 = 4;
+const FRAMEBUFFER: u16 = 5;
+const EFI_BS: u16 = 7;
+const ENTRY_ADDRESS_EFI64: u16 = 9;
+
+const REQUIRED: u16 = 0;
+const OPTIONAL: u16 = 1;
+
+// MBI tag types for information requests
+const INFO_MEMORY_MAP: u32 = 6;
+const INFO_ACPI_OLD: u32 = 14;
+const INFO_NETWORK: u32 = 16;
+
+/// `(type, flags, body)` of header tags.
+type Tags = [(u16, u16, Vec<u8>)];
+
+fn words(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+/// A header with `tags`, each padded to 8 bytes, then the end tag, and a
+/// correct checksum.
+fn header(tags: &Tags) -> Vec<u8> {
+    let mut tag_bytes = Vec::new();
+    for (ty, flags, body) in tags {
+        tag_bytes.extend(ty.to_le_bytes());
+        tag_bytes.extend(flags.to_le_bytes());
+        tag_bytes.extend((8 + body.len() as u32).to_le_bytes());
+        tag_bytes.extend(body);
+        tag_bytes.resize(tag_bytes.len().next_multiple_of(8), 0);
+    }
+    tag_bytes.extend(words(&[0, 8]));
+
+    let length = 16 + tag_bytes.len() as u32;
+    let mut header = words(&[HEADER_MAGIC, 0, length, 0u32.wrapping_sub(HEADER_MAGIC).wrapping_sub(length)]);
+    header.extend(tag_bytes);
+    header
+}
+
+/// `header` at `offset` in a file of `len` bytes.
+fn file_with(header: &[u8], offset: usize, len: usize) -> Vec<u8> {
+    let mut file = vec![0; len.max(offset + header.len())];
+    file[offset..offset + header.len()].copy_from_slice(header);
+    file
+}
+
+fn find(tags: &Tags) -> Result<Option<Header>, Multiboot2Error> {
+    Header::find(&file_with(&header(tags), 0x40, 0x1000))
+}
+
+#[test]
+fn searches_the_first_32k_at_8_byte_steps() {
+    let minimal = header(&[]);
+    assert_eq!(Header::find(&file_with(&minimal, 0, 64)).unwrap().unwrap().offset, 0);
+    assert_eq!(Header::find(&file_with(&minimal, 0x1238, 0x2000)).unwrap().unwrap().offset, 0x1238);
+
+    // The last place the whole header fits
+    let last = HEADER_SEARCH_LIMIT - minimal.len();
+    assert_eq!(Header::find(&file_with(&minimal, last, 0x10000)).unwrap().unwrap().offset, last);
+
+    assert_eq!(Header::find(&file_with(&minimal, 0x1234, 0x2000)), Ok(None), "not 8-byte aligned");
+    assert_eq!(Header::find(&file_with(&minimal, HEADER_SEARCH_LIMIT, 0x10000)), Ok(None), "past 32 KiB");
+    assert_eq!(
+        Header::find(&file_with(&minimal, last + 8, 0x10000)),
+        Err(Multiboot2Error::TruncatedHeader),
+        "end tag past 32 KiB"
+    );
+    assert_eq!(Header::find(&[]), Ok(None));
+    assert_eq!(Header::find(&[0; 100]), Ok(None));
+}
+
+#[test]
+fn checksum_must_match() {
+    let mut bad = header(&[]);
+    bad[12] ^= 1;
+    assert_eq!(Header::find(&file_with(&bad, 0x40, 0x1000)), Ok(None));
+
+    // A magic with a bad checksum is skipped, not taken as the header
+    let mut file = file_with(&bad, 0x40, 0x1000);
+    let good = header(&[(ENTRY_ADDRESS, REQUIRED, words(&[0x10_0000]))]);
+    file[0x100..0x100 + good.len()].copy_from_slice(&good);
+    let found = Header::find(&file).unwrap().unwrap();
+    assert_eq!(found.offset, 0x100);
+    assert_eq!(found.entry, Some(0x10_0000));
+
+    let mut mips = header(&[]);
+    mips[4] = 4;
+    mips[12..16].copy_from_slice(&(0u32.wrapping_sub(HEADER_MAGIC).wrapping_sub(4).wrapping_sub(24)).to_le_bytes());
+    assert_eq!(
+        Header::find(&file_with(&mips, 0, 64)),
+        Err(Multiboot2Error::UnsupportedArchitecture(4))
+    );
+}
+
+#[test]
+fn reads_the_tags_that_matter() {
+    let found = find(&[
+        (INFORMATION_REQUEST, REQUIRED, words(&[INFO_MEMORY_MAP, INFO_ACPI_OLD])),
+        (ADDRESS, REQUIRED, words(&[0x10_0040, 0x10_0000, 0x10_2000, 0x10_3000])),
+        (ENTRY_ADDRESS, REQUIRED, words(&[0x10_0080])),
+        (CONSOLE_FLAGS, REQUIRED, words(&[0])),
+        // 20 bytes: the next tag starts after 4 bytes of padding
+        (FRAMEBUFFER, OPTIONAL, words(&[1024, 768, 32])),
+    ])
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        found,
+        Header {
+            offset: 0x40,
+            address: Some(AddressTag {
+                header_addr: 0x10_0040,
+                load_addr: 0x10_0000,
+                load_end_addr: 0x10_2000,
+                bss_end_addr: 0x10_3000,
+            }),
+            entry: Some(0x10_0080),
+            framebuffer: Some(FramebufferRequest {
+                width: 1024,
+                height: 768,
+                depth: 32,
+            }),
+        }
+    );
+}
+
+#[test]
+fn refuses_required_tags_it_cant_honour() {
+    let cases: &[(&Tags, Result<(), Multiboot2Error>)] = &[
+        (&[(EFI_BS, REQUIRED, vec![])], Err(Multiboot2Error::UnsupportedTag(EFI_BS))),
+        (&[(EFI_BS, OPTIONAL, vec![])], Ok(())),
+        (
+            &[(ENTRY_ADDRESS_EFI64, REQUIRED, words(&[0x10_0000]))],
+            Err(Multiboot2Error::UnsupportedTag(ENTRY_ADDRESS_EFI64)),
+        ),
+        (&[(ENTRY_ADDRESS_EFI64, OPTIONAL, words(&[0x10_0000]))], Ok(())),
+        (
+            &[(INFORMATION_REQUEST, REQUIRED, words(&[INFO_MEMORY_MAP, INFO_NETWORK]))],
+            Err(Multiboot2Error::UnsupportedInformation(INFO_NETWORK)),
+        ),
+        (&[(INFORMATION_REQUEST, OPTIONAL, words(&[INFO_MEMORY_MAP, INFO_NETWORK]))], Ok(())),
+        (
+            &[(ADDRESS, REQUIRED, words(&[0x10_0040, 0x10_0000, 0, 0]))],
+            Err(Multiboot2Error::MissingEntryAddress),
+        ),
+    ];
+    for (tags, expected) in cases {
+        assert_eq!(find(tags).map(|header| assert!(header.is_some())), *expected, "{:?}", tags);
+    }
+}
+
+#[test]
+fn refuses_truncated_tags() {
+    // Too short for its type
+    assert_eq!(
+        find(&[(ENTRY_ADDRESS, REQUIRED, vec![0; 2])]),
+        Err(Multiboot2Error::BadTagSize { ty: ENTRY_ADDRESS, size: 10 })
+    );
+    assert_eq!(
+        find(&[(FRAMEBUFFER, OPTIONAL, words(&[1024, 768]))]),
+        Err(Multiboot2Error::BadTagSize { ty: FRAMEBUFFER, size: 16 })
+    );
+
+    // A tag size of 0 would never advance
+    let mut zero = header(&[(CONSOLE_FLAGS, REQUIRED, words(&[0]))]);
+    zero[20..24].copy_from_slice(&0u32.to_le_bytes());
+    assert_eq!(
+        Header::find(&file_with(&zero, 0, 64)),
+        Err(Multiboot2Error::BadTagSize { ty: CONSOLE_FLAGS, size: 0 })
+    );
+
+    // A tag running past the header length
+    let mut long = header(&[(CONSOLE_FLAGS, REQUIRED, words(&[0]))]);
+    long[20..24].copy_from_slice(&64u32.to_le_bytes());
+    assert_eq!(
+        Header::find(&file_with(&long, 0, 256)),
+        Err(Multiboot2Error::BadTagSize { ty: CONSOLE_FLAGS, size: 64 })
+    );
+
+    // No end tag before the header length runs out
+    let mut unterminated = header(&[]);
+    let length = 16u32;
+    unterminated[8..12].copy_from_slice(&length.to_le_bytes());
+    unterminated[12..16].copy_from_slice(&(0u32.wrapping_sub(HEADER_MAGIC).wrapping_sub(length)).to_le_bytes());
+    assert_eq!(Header::find(&file_with(&unterminated, 0, 64)), Err(Multiboot2Error::TruncatedHeader));
+
+    // The file ends in the middle of the header
+    let mut file = file_with(&header(&[]), 8, 0);
+    file.truncate(30);
+    assert_eq!(Header::find(&file), Err(Multiboot2Error::TruncatedHeader));
+}
+
+#[test]
+fn plans_address_tag_images() {
+    let mut file = vec![0xcc; 0x40];
+    file.extend(header(&[
+        (ADDRESS, REQUIRED, words(&[0x10_0040, 0x10_0000, 0, 0x10_3000])),
+        (ENTRY_ADDRESS, REQUIRED, words(&[0x10_0080])),
+    ]));
+    file.resize(0x200, 0xdd);
+
+    let plan = LoadPlan::new(&file, &Header::find(&file).unwrap().unwrap()).unwrap();
+    assert_eq!(plan.entry, 0x10_0080);
+    assert_eq!(
+        plan.segments,
+        [LoadSegment {
+            phys_addr: 0x10_0000,
+            file_offset: 0,
+            filesz: 0x200,
+            memsz: 0x3000,
+        }]
+    );
+    assert_eq!(plan.load_base(), 0x10_0000);
+    assert_eq!(plan.page_ranges(0x1000), [(0x10_0000, 0x10_3000)]);
+
+    // The header can't sit before the load address
+    let mut file = vec![0xcc; 0x40];
+    file.extend(header(&[
+        (ADDRESS, REQUIRED, words(&[0x10_0000, 0x10_0040, 0, 0])),
+        (ENTRY_ADDRESS, REQUIRED, words(&[0x10_0080])),
+    ]));
+    assert_eq!(
+        LoadPlan::new(&file, &Header::find(&file).unwrap().unwrap()),
+        Err(Multiboot2Error::BadAddressTag)
+    );
+}
+
+/// A little-endian i386 `ET_EXEC` with `(offset, vaddr, paddr, filesz, memsz)`
+/// `PT_LOAD` segments and the header at 0x400.
+fn elf32(entry: u32, segments: &[(u32, u32, u32, u32, u32)]) -> Vec<u8> {
+    let mut file = vec![0; 0x1000];
+    file[0..4].copy_from_slice(b"\x7fELF");
+    file[4] = 1;
+    file[5] = 1;
+    file[16..18].copy_from_slice(&2u16.to_le_bytes());
+    file[18..20].copy_from_slice(&3u16.to_le_bytes());
+    file[24..28].copy_from_slice(&entry.to_le_bytes());
+    file[28..32].copy_from_slice(&52u32.to_le_bytes());
+    file[42..44].copy_from_slice(&32u16.to_le_bytes());
+    file[44..46].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (index, &(offset, vaddr, paddr, filesz, memsz)) in segments.iter().enumerate() {
+        let ph = 52 + 32 * index;
+        file[ph..ph + 24].copy_from_slice(&words(&[1, offset, vaddr, paddr, filesz, memsz]));
+    }
+    let header = header(&[]);
+    file[0x400..0x400 + header.len()].copy_from_slice(&header);
+    file
+}
+
+#[test]
+fn plans_elf_images() {
+    let file = elf32(
+        0xc010_0010,
+        &[
+            (0x200, 0xc010_2000, 0x10_2000, 0x10, 0x2000),
+            (0x100, 0xc010_0000, 0x10_0000, 0x100, 0x100),
+            // Empty segments take no memory
+            (0, 0xc020_0000, 0x20_0000, 0, 0),
+        ],
+    );
+    let plan = LoadPlan::new(&file, &Header::find(&file).unwrap().unwrap()).unwrap();
+    // The virtual entry point moves with its segment
+    assert_eq!(plan.entry, 0x10_0010);
+    assert_eq!(plan.load_base(), 0x10_0000);
+    assert_eq!(plan.segments.len(), 2);
+    assert_eq!(plan.page_ranges(0x1000), [(0x10_0000, 0x10_1000), (0x10_2000, 0x10_4000)]);
+
+    let file = elf32(0x10_0000, &[(0x100, 0xffff_f000, 0xffff_f000, 0x100, 0x2000)]);
+    assert_eq!(
+        LoadPlan::new(&file, &Header::find(&file).unwrap().unwrap()),
+        Err(Multiboot2Error::AboveAddressLimit {
+            addr: 0xffff_f000,
+            size: 0x2000,
+        })
+    );
+
+    let file = elf32(0x10_0000, &[(0xf00, 0x10_0000, 0x10_0000, 0x200, 0x200)]);
+    assert_eq!(
+        LoadPlan::new(&file, &Header::find(&file).unwrap().unwrap()),
+        Err(Multiboot2Error::ElfOutOfBounds)
+    );
+
+    let file = elf32(0x10_0000, &[]);
+    assert_eq!(
+        LoadPlan::new(&file, &Header::find(&file).unwrap().unwrap()),
+        Err(Multiboot2Error::NoLoadableSegments)
+    );
+
+    let not_elf = file_with(&header(&[]), 0, 0x100);
+    assert_eq!(
+        LoadPlan::new(&not_elf, &Header::find(&not_elf).unwrap().unwrap()),
+        Err(Multiboot2Error::NotElf)
+    );
+}
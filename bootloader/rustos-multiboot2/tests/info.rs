@@ -0,0 +1,219 @@
+use rustos_bootinfo::{FramebufferInfo, MemoryDescriptor};
+use rustos_multiboot2::InfoBuilder;
+
+// MBI tag types
+const END: u32 = 0;
+const COMMAND_LINE: u32 = 1;
+const BOOT_LOADER_NAME: u32 = 2;
+const MODULE: u32 = 3;
+const BASIC_MEMORY: u32 = 4;
+const MEMORY_MAP: u32 = 6;
+const FRAMEBUFFER: u32 = 8;
+const EFI64_SYSTEM_TABLE: u32 = 12;
+const ACPI_OLD: u32 = 14;
+const ACPI_NEW: u32 = 15;
+const EFI_MEMORY_MAP: u32 = 17;
+const LOAD_BASE_ADDR: u32 = 21;
+
+// EFI memory types
+const LOADER_DATA: u32 = 2;
+const BOOT_SERVICES_DATA: u32 = 4;
+const CONVENTIONAL: u32 = 7;
+const ACPI_RECLAIM: u32 = 9;
+const RESERVED: u32 = 0;
+
+fn descriptor(ty: u32, start: u64, pages: u64) -> MemoryDescriptor {
+    MemoryDescriptor {
+        ty,
+        physical_start: start,
+        virtual_start: 0,
+        page_count: pages,
+        attribute: 0,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// `(type, size, body)` of each tag, checking the layout a kernel relies on
+/// on the way: 8-byte aligned tags, an end tag, and a total size that adds up.
+fn tags(mbi: &[u8], total: usize) -> Vec<(u32, usize, &[u8])> {
+    assert_eq!(read_u32(mbi, 0) as usize, total);
+    assert_eq!(read_u32(mbi, 4), 0, "reserved");
+
+    let mut tags = Vec::new();
+    let mut position = 8;
+    loop {
+        assert_eq!(position % 8, 0, "tag at {} isn't 8-byte aligned", position);
+        let ty = read_u32(mbi, position);
+        let size = read_u32(mbi, position + 4) as usize;
+        assert!(size >= 8);
+        tags.push((ty, size, &mbi[position + 8..position + size]));
+        // Padding is zeroed
+        assert!(mbi[position + size..(position + size).next_multiple_of(8)].iter().all(|&byte| byte == 0));
+        position += size.next_multiple_of(8);
+        if ty == END {
+            assert_eq!(size, 8);
+            assert_eq!(position, total);
+            return tags;
+        }
+    }
+}
+
+#[test]
+fn round_trips_every_tag() {
+    let map = [
+        descriptor(CONVENTIONAL, 0x10_0000, 0x100),
+        descriptor(CONVENTIONAL, 0, 0x9f),
+        descriptor(LOADER_DATA, 0x20_0000, 0x10),
+        descriptor(RESERVED, 0x21_0000, 1),
+        descriptor(BOOT_SERVICES_DATA, 0x21_1000, 0xf),
+        descriptor(ACPI_RECLAIM, 0x30_0000, 1),
+    ];
+    let framebuffer = FramebufferInfo {
+        addr: 0x8000_0000,
+        width: 800,
+        height: 600,
+        pitch: 3200,
+        bpp: 32,
+        red_mask: 0xff_0000,
+        green_mask: 0xff00,
+        blue_mask: 0xff,
+        reserved_mask: 0xff00_0000,
+    };
+    let mut rsdp = [0x5a; 36];
+    rsdp[15] = 2;
+    let efi_map = [0x11; 96];
+
+    let mut buffer = vec![0xee; 4096];
+    let mut builder = InfoBuilder::new(&mut buffer);
+    builder.command_line("console=ttyS0");
+    builder.boot_loader_name("RustOS Bootloader");
+    builder.module(0x40_0000, 0x40_1234, "initrd");
+    builder.basic_memory_info(map.iter());
+    builder.memory_map(map.iter());
+    builder.framebuffer(&framebuffer);
+    builder.efi64_system_table(0x7f00_1000);
+    builder.acpi_rsdp(&rsdp);
+    builder.efi_memory_map(&efi_map, 48);
+    builder.load_base_addr(0x10_0000);
+    assert_eq!(builder.dropped(), 0);
+    let total = builder.finish();
+
+    let tags = tags(&buffer, total);
+    let types: Vec<u32> = tags.iter().map(|&(ty, _, _)| ty).collect();
+    assert_eq!(
+        types,
+        [
+            COMMAND_LINE,
+            BOOT_LOADER_NAME,
+            MODULE,
+            BASIC_MEMORY,
+            MEMORY_MAP,
+            FRAMEBUFFER,
+            EFI64_SYSTEM_TABLE,
+            ACPI_OLD,
+            ACPI_NEW,
+            EFI_MEMORY_MAP,
+            LOAD_BASE_ADDR,
+            END
+        ]
+    );
+
+    for &(ty, size, body) in &tags {
+        match ty {
+            COMMAND_LINE => assert_eq!(body, b"console=ttyS0\0"),
+            BOOT_LOADER_NAME => assert_eq!(body, b"RustOS Bootloader\0"),
+            MODULE => {
+                assert_eq!((read_u32(body, 0), read_u32(body, 4)), (0x40_0000, 0x40_1234));
+                assert_eq!(&body[8..], b"initrd\0");
+            }
+            // 636 KiB below 640K, and 1 MiB up to the reserved page; boot
+            // services and loader memory count as available
+            BASIC_MEMORY => assert_eq!((read_u32(body, 0), read_u32(body, 4)), (636, 1088)),
+            MEMORY_MAP => {
+                assert_eq!(read_u32(body, 0), 24, "entry size");
+                assert_eq!(read_u32(body, 4), 0, "entry version");
+                let entries: Vec<(u64, u64, u32)> = body[8..]
+                    .chunks_exact(24)
+                    .map(|entry| (read_u64(entry, 0), read_u64(entry, 8), read_u32(entry, 16)))
+                    .collect();
+                assert_eq!(
+                    entries,
+                    [
+                        (0x10_0000, 0x10_0000, 1),
+                        (0, 0x9_f000, 1),
+                        (0x20_0000, 0x1_0000, 1),
+                        (0x21_0000, 0x1000, 2),
+                        (0x21_1000, 0xf000, 1),
+                        (0x30_0000, 0x1000, 3),
+                    ]
+                );
+            }
+            FRAMEBUFFER => {
+                // The common part with a 16-bit reserved field, as GRUB
+                // writes it, then 6 bytes of RGB field positions and sizes
+                assert_eq!(size, 38);
+                assert_eq!(read_u64(body, 0), 0x8000_0000);
+                assert_eq!((read_u32(body, 8), read_u32(body, 12), read_u32(body, 16)), (3200, 800, 600));
+                assert_eq!(&body[20..24], [32, 1, 0, 0]);
+                assert_eq!(&body[24..30], [16, 8, 8, 8, 0, 8]);
+            }
+            EFI64_SYSTEM_TABLE => assert_eq!(read_u64(body, 0), 0x7f00_1000),
+            ACPI_OLD => assert_eq!(body, &rsdp[..20]),
+            ACPI_NEW => assert_eq!(body, rsdp),
+            EFI_MEMORY_MAP => {
+                assert_eq!((read_u32(body, 0), read_u32(body, 4)), (48, 1));
+                assert_eq!(&body[8..], efi_map);
+            }
+            LOAD_BASE_ADDR => assert_eq!(read_u32(body, 0), 0x10_0000),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn skips_what_isnt_there() {
+    let mut buffer = vec![0; 256];
+    let mut builder = InfoBuilder::new(&mut buffer);
+    builder.framebuffer(&FramebufferInfo {
+        addr: 0,
+        width: 800,
+        height: 600,
+        pitch: 0,
+        bpp: 0,
+        red_mask: 0,
+        green_mask: 0,
+        blue_mask: 0,
+        reserved_mask: 0,
+    });
+    // Too short for an RSDP; a revision 0 one has no XSDP
+    builder.acpi_rsdp(&[0; 19]);
+    builder.acpi_rsdp(&[0; 20]);
+    assert_eq!(builder.dropped(), 0);
+    let total = builder.finish();
+
+    let types: Vec<u32> = tags(&buffer, total).iter().map(|&(ty, _, _)| ty).collect();
+    assert_eq!(types, [ACPI_OLD, END]);
+}
+
+#[test]
+fn drops_tags_that_dont_fit_but_keeps_the_end_tag() {
+    let mut buffer = vec![0; 40];
+    let mut builder = InfoBuilder::new(&mut buffer);
+    // 8 + 14 bytes, padded to 24, leaves exactly room for the end tag
+    builder.command_line("console=ttyS0");
+    builder.command_line("x");
+    builder.load_base_addr(0x10_0000);
+    assert_eq!(builder.dropped(), 2);
+    let total = builder.finish();
+    assert_eq!(total, 40);
+
+    let types: Vec<u32> = tags(&buffer, total).iter().map(|&(ty, _, _)| ty).collect();
+    assert_eq!(types, [COMMAND_LINE, END]);
+}
@@ -0,0 +1,260 @@
+//! UEFI runtime services: wall-clock time, reset and variables.
+//!
+//! The bootloader maps every runtime services region at its physical address
+//! plus `runtime_services_offset`. [`init`] tells the firmware about that
+//! layout with `SetVirtualAddressMap`; from then on the services are called
+//! through those virtual addresses and no longer need the identity map.
+//!
+//! The firmware doesn't allow runtime services to be entered twice at once,
+//! so every call goes through the `RUNTIME` lock.
+
+use core::ffi::c_void;
+use core::fmt;
+use rustos_bootinfo::{BootInfo, MemoryDescriptor};
+use spin::Mutex;
+
+/// `EFI_STATUS`; errors have the top bit set.
+pub type Status = usize;
+
+const ERROR_BIT: usize = 1 << (usize::BITS - 1);
+pub const SUCCESS: Status = 0;
+pub const INVALID_PARAMETER: Status = ERROR_BIT | 2;
+pub const UNSUPPORTED: Status = ERROR_BIT | 3;
+#[allow(dead_code)] // For get_variable callers that size their buffer by retrying
+pub const BUFFER_TOO_SMALL: Status = ERROR_BIT | 5;
+
+const MEMORY_DESCRIPTOR_VERSION: u32 = 1;
+
+// Variable attributes, for set_variable, which has no caller yet
+#[allow(dead_code)]
+pub const VARIABLE_NON_VOLATILE: u32 = 0x1;
+#[allow(dead_code)]
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+#[allow(dead_code)]
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+/// `EFI_GLOBAL_VARIABLE`, the vendor of the architectural variables
+/// (`BootOrder`, `BootCurrent`, ...).
+pub const GLOBAL_VARIABLE: Guid = Guid {
+    data1: 0x8be4_df61,
+    data2: 0x93ca,
+    data3: 0x11d2,
+    data4: [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+};
+
+/// `EFI_TIME`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pad1: u8,
+    pub nanosecond: u32,
+    /// Minutes from UTC, or 0x7ff if unspecified.
+    pub time_zone: i16,
+    pub daylight: u8,
+    pad2: u8,
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+pub enum ResetType {
+    Cold = 0,
+    #[allow(dead_code)] // Part of EFI_RESET_TYPE; `panic=reboot` does a cold reset
+    Warm = 1,
+    Shutdown = 2,
+}
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+// Only the fields up to the runtime services pointer
+#[repr(C)]
+struct SystemTable {
+    header: TableHeader,
+    firmware_vendor: *const u16,
+    firmware_revision: u32,
+    console_in_handle: *const c_void,
+    console_in: *const c_void,
+    console_out_handle: *const c_void,
+    console_out: *const c_void,
+    standard_error_handle: *const c_void,
+    standard_error: *const c_void,
+    runtime_services: *const RuntimeServicesTable,
+}
+
+// Services we don't call are kept as plain addresses
+#[repr(C)]
+struct RuntimeServicesTable {
+    header: TableHeader,
+    get_time: unsafe extern "efiapi" fn(time: *mut Time, capabilities: *mut c_void) -> Status,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: unsafe extern "efiapi" fn(
+        map_size: usize,
+        descriptor_size: usize,
+        descriptor_version: u32,
+        map: *mut MemoryDescriptor,
+    ) -> Status,
+    convert_pointer: usize,
+    get_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut u8,
+    ) -> Status,
+    get_next_variable_name: usize,
+    set_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: u32,
+        data_size: usize,
+        data: *const u8,
+    ) -> Status,
+    get_next_high_monotonic_count: usize,
+    reset_system:
+        unsafe extern "efiapi" fn(reset_type: ResetType, status: Status, data_size: usize, data: *const c_void) -> !,
+}
+
+struct Runtime(&'static RuntimeServicesTable);
+
+// The table is only used with the lock held
+unsafe impl Send for Runtime {}
+
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+/// Switches runtime services to the bootloader's virtual mapping. Must be
+/// called at most once, while the identity map is still in place.
+pub fn init(boot_info: &BootInfo) -> Result<(), Status> {
+    let system_table = boot_info.firmware.efi_system_table().ok_or(UNSUPPORTED)?;
+    let offset = boot_info.firmware.runtime_services_offset;
+    if offset == 0 {
+        return Err(UNSUPPORTED);
+    }
+
+    let map = &boot_info.memory_map;
+    let stride = map.entry_size as usize;
+    let entries = map.entries as *mut MemoryDescriptor;
+    unsafe {
+        // The firmware only looks at the runtime descriptors' virtual addresses
+        for i in 0..map.entry_count as usize {
+            let descriptor = &mut *(entries as *mut u8).add(i * stride).cast::<MemoryDescriptor>();
+            if descriptor.is_runtime() {
+                descriptor.virtual_start = descriptor.physical_start + offset;
+            }
+        }
+
+        // Still called through the physical addresses
+        let runtime = &*(*(system_table as *const SystemTable)).runtime_services;
+        let status = (runtime.set_virtual_address_map)(
+            map.entry_count as usize * stride,
+            stride,
+            MEMORY_DESCRIPTOR_VERSION,
+            entries,
+        );
+        if status != SUCCESS {
+            return Err(status);
+        }
+
+        // The firmware converted its own pointers, including this one
+        let system_table = &*((system_table + offset) as *const SystemTable);
+        *RUNTIME.lock() = Some(Runtime(&*system_table.runtime_services));
+    }
+    Ok(())
+}
+
+pub fn get_time() -> Result<Time, Status> {
+    let runtime = RUNTIME.lock();
+    let runtime = runtime.as_ref().ok_or(UNSUPPORTED)?;
+    let mut time = Time::default();
+    match unsafe { (runtime.0.get_time)(&mut time, core::ptr::null_mut()) } {
+        SUCCESS => Ok(time),
+        status => Err(status),
+    }
+}
+
+/// Resets or powers off the machine. Returns only if runtime services
+/// aren't available, or are in use, as they may be when this is called from
+/// the panic handler.
+pub fn reset(reset_type: ResetType) -> Status {
+    let runtime = match RUNTIME.try_lock() {
+        Some(runtime) => runtime,
+        None => return UNSUPPORTED,
+    };
+    match runtime.as_ref() {
+        Some(runtime) => unsafe { (runtime.0.reset_system)(reset_type, SUCCESS, 0, core::ptr::null()) },
+        None => UNSUPPORTED,
+    }
+}
+
+/// Reads the variable `name` (NUL-terminated UCS-2) into `data` and returns
+/// its size and attributes. Fails with `BUFFER_TOO_SMALL` if it doesn't fit.
+pub fn get_variable(name: &[u16], vendor: &Guid, data: &mut [u8]) -> Result<(usize, u32), Status> {
+    if name.last() != Some(&0) {
+        return Err(INVALID_PARAMETER);
+    }
+    let runtime = RUNTIME.lock();
+    let runtime = runtime.as_ref().ok_or(UNSUPPORTED)?;
+    let mut attributes = 0;
+    let mut size = data.len();
+    match unsafe { (runtime.0.get_variable)(name.as_ptr(), vendor, &mut attributes, &mut size, data.as_mut_ptr()) } {
+        SUCCESS => Ok((size, attributes)),
+        status => Err(status),
+    }
+}
+
+/// Creates, replaces or (with empty `data`) deletes the variable `name`.
+#[allow(dead_code)] // Nothing in the kernel writes variables yet
+pub fn set_variable(name: &[u16], vendor: &Guid, attributes: u32, data: &[u8]) -> Result<(), Status> {
+    if name.last() != Some(&0) {
+        return Err(INVALID_PARAMETER);
+    }
+    let runtime = RUNTIME.lock();
+    let runtime = runtime.as_ref().ok_or(UNSUPPORTED)?;
+    match unsafe { (runtime.0.set_variable)(name.as_ptr(), vendor, attributes, data.len(), data.as_ptr()) } {
+        SUCCESS => Ok(()),
+        status => Err(status),
+    }
+}
+
+/// UCS-2 copy of an ASCII string, for variable names: `ucs2(b"BootOrder\0")`.
+pub const fn ucs2<const N: usize>(ascii: &[u8; N]) -> [u16; N] {
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = ascii[i] as u16;
+        i += 1;
+    }
+    out
+}
@@ -0,0 +1,110 @@
+//! The kernel's own GDT and TSS.
+//!
+//! The firmware's GDT is gone after the handoff, and the TSS is what gives
+//! faults that must not use the interrupted stack (double faults, page
+//! faults from a kernel stack overflow) a known good stack to run on.
+
+use core::mem::size_of;
+use core::ptr::addr_of;
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+const TSS_SELECTOR: u16 = 0x18;
+
+/// IST slots, as used in IDT entries (1-based; 0 means no stack switch)
+pub const DOUBLE_FAULT_IST_INDEX: u8 = 1;
+pub const PAGE_FAULT_IST_INDEX: u8 = 2;
+
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+static mut PAGE_FAULT_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+
+#[repr(C, packed)]
+struct TaskStateSegment {
+    reserved0: u32,
+    privilege_stacks: [u64; 3],
+    reserved1: u64,
+    interrupt_stacks: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+static mut TSS: TaskStateSegment = TaskStateSegment {
+    reserved0: 0,
+    privilege_stacks: [0; 3],
+    reserved1: 0,
+    interrupt_stacks: [0; 7],
+    reserved2: 0,
+    reserved3: 0,
+    // No I/O permission bitmap
+    iomap_base: size_of::<TaskStateSegment>() as u16,
+};
+
+// Null, kernel code, kernel data and the two halves of the TSS descriptor
+static mut GDT: [u64; 5] = [
+    0,
+    0x00af_9a00_0000_ffff, // 64-bit code, present, ring 0
+    0x00cf_9200_0000_ffff, // Data, present, writable, ring 0
+    0,
+    0,
+];
+
+#[repr(C, packed)]
+struct GdtDescriptor {
+    limit: u16,
+    base: u64,
+}
+
+/// Loads the GDT and TSS and reloads every segment register.
+pub fn init() {
+    unsafe {
+        TSS.interrupt_stacks[DOUBLE_FAULT_IST_INDEX as usize - 1] = stack_top(addr_of!(DOUBLE_FAULT_STACK));
+        TSS.interrupt_stacks[PAGE_FAULT_IST_INDEX as usize - 1] = stack_top(addr_of!(PAGE_FAULT_STACK));
+
+        // Available 64-bit TSS
+        let base = addr_of!(TSS) as u64;
+        let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+        GDT[3] = (limit & 0xffff)
+            | ((base & 0xff_ffff) << 16)
+            | (0x89 << 40)
+            | (((limit >> 16) & 0xf) << 48)
+            | (((base >> 24) & 0xff) << 56);
+        GDT[4] = base >> 32;
+
+        let gdt_descriptor = GdtDescriptor {
+            limit: (size_of::<[u64; 5]>() - 1) as u16,
+            base: addr_of!(GDT) as u64,
+        };
+        core::arch::asm!("lgdt [{}]", in(reg) &gdt_descriptor, options(readonly, nostack, preserves_flags));
+
+        // Reload CS with a far return, then the data segments
+        core::arch::asm!(
+            "push {code}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov ss, {data:x}",
+            "mov fs, {null:x}",
+            "mov gs, {null:x}",
+            code = in(reg) KERNEL_CODE_SELECTOR as u64,
+            data = in(reg) KERNEL_DATA_SELECTOR as u64,
+            null = in(reg) 0u64,
+            tmp = out(reg) _,
+            options(preserves_flags)
+        );
+
+        core::arch::asm!("ltr {:x}", in(reg) TSS_SELECTOR, options(nomem, nostack, preserves_flags));
+    }
+}
+
+fn stack_top(stack: *const IstStack) -> u64 {
+    stack as u64 + IST_STACK_SIZE as u64
+}
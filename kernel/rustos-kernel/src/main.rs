@@ -1,50 +1,15 @@
 #![no_std]
 #![no_main]
 
+mod efi_runtime;
+mod gdt;
+
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use rustos_bootinfo::{BootInfo, BootLog, BootPhase, BootTimings, Framebuffer, KernelImageInfo, KernelStackInfo, MemoryRegionKind, KERNEL_ENTRY_MAGIC};
 use spin::Mutex;
 use uart_16550::SerialPort;
 
-// Boot info structures as specified
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct BootInfo {
-    pub memory_map: MemoryMapInfo,
-    pub framebuffer: FramebufferInfo,
-    pub rsdp_addr: Option<u64>,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct MemoryMapInfo {
-    pub entries: *const MemoryDescriptor,
-    pub entry_count: usize,
-    pub entry_size: usize,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct MemoryDescriptor {
-    pub ty: u32,
-    pub physical_start: u64,
-    pub virtual_start: u64,
-    pub number_of_pages: u64,
-    pub attribute: u64,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct FramebufferInfo {
-    pub addr: u64,
-    pub width: u32,
-    pub height: u32,
-    pub pitch: u32,
-    pub bpp: u32,
-    pub red_mask: u32,
-    pub green_mask: u32,
-    pub blue_mask: u32,
-}
-
 // System call numbers
 #[repr(u64)]
 #[derive(Debug, Clone, Copy)]
@@ -127,8 +92,25 @@ impl From<u64> for SyscallNumber {
 // Global serial port for logging
 static SERIAL1: Mutex<Option<SerialPort>> = Mutex::new(None);
 
-// Global framebuffer info for panic handler
-static FRAMEBUFFER: Mutex<Option<FramebufferInfo>> = Mutex::new(None);
+// Global framebuffer for panic handler
+static FRAMEBUFFER: Mutex<Option<Framebuffer>> = Mutex::new(None);
+// Bounds of the boot stack, so faults in its guard page can be recognised
+static KERNEL_STACK: Mutex<Option<KernelStackInfo>> = Mutex::new(None);
+// Where the kernel runs and its KASLR slide, reported on panic so addresses
+// can be symbolised
+static KERNEL_IMAGE: Mutex<Option<KernelImageInfo>> = Mutex::new(None);
+// The bootloader's log, dumped on panic
+static BOOT_LOG: Mutex<Option<BootLog>> = Mutex::new(None);
+// What to do after reporting a panic, from `panic=reboot` or `panic=poweroff`
+// on the command line; halt if unset
+static PANIC_RESET: Mutex<Option<efi_runtime::ResetType>> = Mutex::new(None);
+
+// Formatted logging to serial, e.g. `serial_println!("{} entries", count)`
+macro_rules! serial_println {
+    ($($arg:tt)*) => {
+        log_fmt(format_args!($($arg)*))
+    };
+}
 
 // IDT structures
 #[repr(C)]
@@ -165,6 +147,11 @@ impl IdtEntry {
         self.ist = 0;
         self.reserved = 0;
     }
+
+    // Run the handler on the given TSS interrupt stack (1-7)
+    fn set_stack_index(&mut self, ist: u8) {
+        self.ist = ist;
+    }
 }
 
 #[repr(C, packed)]
@@ -293,7 +280,11 @@ device_not_available_handler_asm:
 
 double_fault_handler_asm:
     SAVE_REGS
+    // Error code plus 15 registers leave rsp 8 bytes off the 16-byte
+    // alignment the SysV ABI wants at the call
+    sub rsp, 8
     call double_fault_handler
+    add rsp, 8
 1:
     hlt
     jmp 1b
@@ -328,10 +319,14 @@ general_protection_fault_handler_asm:
 
 page_fault_handler_asm:
     SAVE_REGS
-    pop rdi
+    mov rdi, [rsp + 15 * 8]
+    // Realign rsp, as for the double fault
+    sub rsp, 8
     call page_fault_handler
-    RESTORE_REGS
-    iretq
+    add rsp, 8
+1:
+    hlt
+    jmp 1b
 
 x87_floating_point_handler_asm:
     SAVE_REGS
@@ -499,6 +494,11 @@ extern "C" fn device_not_available_handler() {
 
 #[no_mangle]
 extern "C" fn double_fault_handler() -> ! {
+    // A fault while pushing the page fault frame ends up here
+    let fault_addr = read_cr2();
+    if let Some(stack) = KERNEL_STACK.lock().as_ref().filter(|stack| stack.is_guard_hit(fault_addr)) {
+        report_stack_overflow(stack, fault_addr);
+    }
     log_error("EXCEPTION: Double Fault");
     loop {
         unsafe { core::arch::asm!("hlt"); }
@@ -538,8 +538,13 @@ extern "C" fn general_protection_fault_handler(_error_code: u64) {
 }
 
 #[no_mangle]
-extern "C" fn page_fault_handler(_error_code: u64) {
-    log_error("EXCEPTION: Page Fault");
+extern "C" fn page_fault_handler(error_code: u64) -> ! {
+    let fault_addr = read_cr2();
+    if let Some(stack) = KERNEL_STACK.lock().as_ref().filter(|stack| stack.is_guard_hit(fault_addr)) {
+        report_stack_overflow(stack, fault_addr);
+    } else {
+        serial_println!("ERROR: EXCEPTION: Page Fault at 0x{:x}, error code 0x{:x}", fault_addr, error_code);
+    }
     loop {
         unsafe { core::arch::asm!("hlt"); }
     }
@@ -636,6 +641,21 @@ struct InterruptStackFrame {
     stack_segment: u64,
 }
 
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+    cr2
+}
+
+fn report_stack_overflow(stack: &KernelStackInfo, fault_addr: u64) {
+    serial_println!(
+        "ERROR: EXCEPTION: Kernel stack overflow (fault at 0x{:x}, stack 0x{:x}-0x{:x})",
+        fault_addr, stack.bottom, stack.top
+    );
+}
+
 // Initialize IDT
 fn init_idt() {
     unsafe {
@@ -673,6 +693,10 @@ fn init_idt() {
         IDT[30].set_handler(security_handler_asm as u64, 0x08);
         IDT[31].set_handler(generic_interrupt_handler_asm as u64, 0x08); // Reserved
 
+        // These must not run on the stack that may have just overflowed
+        IDT[8].set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        IDT[14].set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+
         // Set interrupt handlers (32-255)
         for i in 32..256 {
             if i == 0x80 {
@@ -715,47 +739,33 @@ fn log_error(msg: &str) {
     }
 }
 
+fn log_fmt(args: core::fmt::Arguments) {
+    if let Some(serial) = SERIAL1.lock().as_mut() {
+        let _ = serial.write_fmt(args);
+        unsafe { serial.send(b'\n'); }
+    }
+}
+
 // Framebuffer utilities for panic handler
-fn write_to_framebuffer(msg: &str, color: u32) {
-    if let Some(fb) = FRAMEBUFFER.lock().as_ref() {
-        let fb_ptr = fb.addr as *mut u32;
-        let bytes_per_pixel = fb.bpp / 8;
-        
-        if bytes_per_pixel == 4 {
-            unsafe {
-                // Simple text rendering - just fill some pixels with color to indicate panic
-                for i in 0..1000 {
-                    if (i as u64) < (fb.width as u64 * fb.height as u64) {
-                        *fb_ptr.add(i) = color;
-                    }
-                }
-            }
-        }
+fn write_to_framebuffer(_msg: &str, color: u32) {
+    if let Some(fb) = FRAMEBUFFER.lock().as_mut() {
+        // Simple text rendering - just fill a band with color to indicate panic
+        let color = fb.color(color);
+        let width = fb.width();
+        fb.fill_rect(0, 0, width, 4, color);
     }
 }
 
 // Validate boot info structure
 fn validate_boot_info(boot_info: &BootInfo) -> bool {
-    // Check if memory map has valid entries
-    if boot_info.memory_map.entry_count == 0 || boot_info.memory_map.entries.is_null() {
-        return false;
-    }
-    
-    // Check if framebuffer info is reasonable
-    if boot_info.framebuffer.width == 0 || boot_info.framebuffer.height == 0 {
-        return false;
-    }
-    
-    if boot_info.framebuffer.bpp != 32 && boot_info.framebuffer.bpp != 24 && boot_info.framebuffer.bpp != 16 {
-        return false;
-    }
-    
-    true
+    // Check if memory map has valid entries. A framebuffer the kernel can't
+    // draw to isn't fatal, output just stays on serial.
+    boot_info.memory_map.entry_count != 0 && !boot_info.memory_map.entries.is_null()
 }
 
-// Kernel entry point
+// Kernel entry point, see the entry ABI in rustos_bootinfo
 #[no_mangle]
-pub extern "C" fn kernel_main(boot_info: &'static BootInfo) -> ! {
+pub extern "C" fn kernel_main(boot_info_ptr: *const BootInfo, magic: u64) -> ! {
     // Initialize serial port for logging
     let mut serial_port = unsafe { SerialPort::new(0x3F8) };
     serial_port.init();
@@ -763,6 +773,20 @@ pub extern "C" fn kernel_main(boot_info: &'static BootInfo) -> ! {
     
     log_info("RustOS Kernel Starting...");
     
+    if magic != KERNEL_ENTRY_MAGIC {
+        serial_println!("ERROR: Not started by the RustOS bootloader (magic 0x{:016x})", magic);
+        panic!("Bad entry magic");
+    }
+    
+    // Refuse a handoff from a bootloader built against a different BootInfo layout
+    let boot_info: &'static BootInfo = match unsafe { BootInfo::from_ptr(boot_info_ptr) } {
+        Ok(boot_info) => boot_info,
+        Err(e) => {
+            serial_println!("ERROR: Incompatible boot info at {:p}: {}", boot_info_ptr, e);
+            panic!("Boot info version check failed");
+        }
+    };
+    
     // Validate boot info
     if !validate_boot_info(boot_info) {
         log_error("Invalid boot info structure");
@@ -772,37 +796,127 @@ pub extern "C" fn kernel_main(boot_info: &'static BootInfo) -> ! {
     log_info("Boot info validated successfully");
     
     // Store framebuffer info for panic handler
-    *FRAMEBUFFER.lock() = Some(boot_info.framebuffer.clone());
-    
-    // Log boot info details
-    log_info("Memory map entries:");
-    unsafe {
-        let entries = core::slice::from_raw_parts(
-            boot_info.memory_map.entries,
-            boot_info.memory_map.entry_count
-        );
-        
-        for (i, entry) in entries.iter().enumerate() {
-            if i < 10 { // Limit output for readability
-                log_info("  Entry found in memory map");
+    match boot_info.framebuffer() {
+        // Drawn through the direct map so it keeps working once the identity map is gone
+        Some(framebuffer) => {
+            let base = boot_info.phys_to_virt(framebuffer.addr) as *mut u8;
+            let usable = match unsafe { Framebuffer::with_base(framebuffer, base) } {
+                Some(fb) if fb.width() != 0 && fb.height() != 0 => Some(fb),
+                _ => None,
+            };
+            if usable.is_none() {
+                serial_println!(
+                    "Unusable framebuffer ({}x{}, {} bpp, pitch {}), using serial output only",
+                    framebuffer.width, framebuffer.height, framebuffer.bpp, framebuffer.pitch
+                );
             }
+            *FRAMEBUFFER.lock() = usable;
         }
+        None => log_info("No linear framebuffer, using serial output only"),
     }
     
-    log_info("Framebuffer info:");
-    log_info("  Resolution and format validated");
+    // Log boot info details
+    serial_println!("Memory map: {} UEFI descriptors", boot_info.memory_map.entry_count);
+    let mut usable = 0;
+    for region in unsafe { boot_info.memory_regions.as_slice() } {
+        serial_println!("  0x{:016x}-0x{:016x} {:?}", region.start, region.end, region.kind);
+        if region.kind == MemoryRegionKind::Usable {
+            usable += region.len();
+        }
+    }
+    serial_println!("Usable memory: {} MiB", usable >> 20);
     
-    if let Some(rsdp_addr) = boot_info.rsdp_addr {
-        log_info("ACPI RSDP found");
+    if let Some(rsdp_addr) = boot_info.rsdp_addr() {
+        serial_println!("ACPI RSDP at 0x{:x}", rsdp_addr);
     } else {
         log_info("No ACPI RSDP provided");
     }
-    
+    let firmware = &boot_info.firmware;
+    for (name, addr) in [
+        ("SMBIOS entry point", firmware.smbios_addr()),
+        ("SMBIOS3 entry point", firmware.smbios3_addr()),
+        ("EFI system table", firmware.efi_system_table()),
+        ("EFI memory attributes table", firmware.memory_attributes_table()),
+    ] {
+        match addr {
+            Some(addr) => serial_println!("{} at 0x{:x}", name, addr),
+            None => serial_println!("No {}", name),
+        }
+    }
+
+    match unsafe { boot_info.cmdline.as_str() } {
+        Some(cmdline) => {
+            serial_println!("Command line: \"{}\"", cmdline);
+            let panic_reset = match cmdline.split_whitespace().find_map(|option| option.strip_prefix("panic=")) {
+                Some("reboot") => Some(efi_runtime::ResetType::Cold),
+                Some("poweroff") => Some(efi_runtime::ResetType::Shutdown),
+                Some(other) => {
+                    serial_println!("Unknown panic={}, halting on panic", other);
+                    None
+                }
+                None => None,
+            };
+            *PANIC_RESET.lock() = panic_reset;
+        }
+        None => log_error("Command line is not valid UTF-8"),
+    }
+
+    for module in unsafe { boot_info.modules.as_slice() } {
+        serial_println!(
+            "Module {}: 0x{:x}, {} bytes",
+            unsafe { module.name.as_str() }.unwrap_or("<invalid>"),
+            module.phys_addr,
+            module.len
+        );
+    }
+
+    serial_println!(
+        "Kernel image: virt 0x{:x} -> phys 0x{:x}, 0x{:x} bytes, slide 0x{:x}",
+        boot_info.kernel.virt_base, boot_info.kernel.phys_base, boot_info.kernel.size, boot_info.kernel.slide
+    );
+    *KERNEL_IMAGE.lock() = Some(boot_info.kernel.clone());
+    serial_println!(
+        "Physical memory mapped at 0x{:x} (0x{:x} bytes)",
+        boot_info.physical_memory_offset, boot_info.physical_memory_size
+    );
+
+    serial_println!(
+        "Kernel stack: 0x{:x}-0x{:x} ({} KiB), guard page at 0x{:x}",
+        boot_info.stack.bottom, boot_info.stack.top, boot_info.stack.size() / 1024, boot_info.stack.guard_start
+    );
+    *KERNEL_STACK.lock() = Some(boot_info.stack.clone());
+    *BOOT_LOG.lock() = Some(boot_info.log);
+    serial_println!(
+        "Bootloader log: {} bytes{}",
+        boot_info.log.written.min(boot_info.log.capacity),
+        if boot_info.log.is_truncated() { " (truncated)" } else { "" }
+    );
+    print_boot_times(&boot_info.timings);
+
+    // Replace the firmware's GDT; the IDT below refers to its selectors
+    gdt::init();
+
     // Initialize IDT
     log_info("Initializing IDT...");
     init_idt();
     log_info("IDT initialized successfully");
     
+    // Needs the identity map, which the kernel still runs with
+    match efi_runtime::init(boot_info) {
+        Ok(()) => {
+            match efi_runtime::get_time() {
+                Ok(time) => serial_println!("EFI time: {}", time),
+                Err(status) => serial_println!("EFI GetTime failed: 0x{:x}", status),
+            }
+            let name = efi_runtime::ucs2(b"BootCurrent\0");
+            let mut boot_current = [0u8; 2];
+            if efi_runtime::get_variable(&name, &efi_runtime::GLOBAL_VARIABLE, &mut boot_current).is_ok() {
+                serial_println!("Booted from Boot{:04X}", u16::from_le_bytes(boot_current));
+            }
+        }
+        Err(status) => serial_println!("EFI runtime services unavailable: 0x{:x}", status),
+    }
+    
     // Enable interrupts
     unsafe {
         core::arch::asm!("sti", options(nomem, nostack));
@@ -820,6 +934,41 @@ pub extern "C" fn kernel_main(boot_info: &'static BootInfo) -> ! {
     }
 }
 
+// Where the bootloader spent its time, one line per phase, so CI logs show
+// boot time regressions
+fn print_boot_times(timings: &BootTimings) {
+    if timings.tsc_per_ms == 0 {
+        serial_println!("Boot time: TSC rate unknown, durations in ticks");
+    }
+    for phase in BootPhase::ALL {
+        match (timings.duration_us(phase), timings.ticks(phase)) {
+            (Some(us), _) => serial_println!("Boot time: {:<12} {:>5}.{:03} ms", phase.name(), us / 1000, us % 1000),
+            (None, Some(ticks)) => serial_println!("Boot time: {:<12} {} ticks", phase.name(), ticks),
+            (None, None) => serial_println!("Boot time: {:<12} not run", phase.name()),
+        }
+    }
+    if let Some(us) = timings.total_us() {
+        serial_println!("Boot time: {:<12} {:>5}.{:03} ms", "bootloader", us / 1000, us % 1000);
+    }
+}
+
+// Replay the bootloader's log on serial, for failures that happened
+// before the kernel had any output of its own
+fn dump_boot_log() {
+    let log = match *BOOT_LOG.lock() {
+        Some(log) => log,
+        None => return,
+    };
+    if let Some(serial) = SERIAL1.lock().as_mut() {
+        let _ = serial.write_str("--- Bootloader log ---\n");
+        let (older, newer) = unsafe { log.as_slices() };
+        for &byte in older.iter().chain(newer) {
+            unsafe { serial.send(byte); }
+        }
+        let _ = serial.write_str("--- End of bootloader log ---\n");
+    }
+}
+
 // Panic handler
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -880,11 +1029,25 @@ fn panic(info: &PanicInfo) -> ! {
             }
             unsafe { serial.send(b'\n'); }
         }
+        
+        // Addresses in a backtrace minus the slide are link-time addresses
+        if let Some(kernel) = KERNEL_IMAGE.lock().as_ref() {
+            let _ = writeln!(serial, "Kernel slide: 0x{:x}", kernel.slide);
+        }
     }
     
+    dump_boot_log();
+    
     // Write to framebuffer (red color to indicate panic)
     write_to_framebuffer("KERNEL PANIC", 0xFF0000);
     
+    // Reboot or power off if asked to; reset only returns if runtime
+    // services can't be used
+    if let Some(reset_type) = PANIC_RESET.try_lock().and_then(|reset_type| *reset_type) {
+        let status = efi_runtime::reset(reset_type);
+        serial_println!("EFI ResetSystem unavailable: 0x{:x}", status);
+    }
+    
     // Halt forever
     loop {
         unsafe {